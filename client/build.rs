@@ -1,4 +1,4 @@
 fn main () -> Result<(), Box<dyn std::error::Error>> {
-    tonic_build::compile_protos("../protos/zkp.proto")?;
+    tonic_build::compile_protos("../protos/zkp_v2.proto")?;
     Ok(())
 }
\ No newline at end of file
@@ -1,52 +1,117 @@
+mod nonce;
 mod parameters;
+mod puzzle;
+mod secret;
+mod sigma;
+mod tls;
 mod zkp_utils;
 
 use std::io::stdin;
 use tonic::Response;
-use num_bigint::{BigInt, ToBigInt};
-use num::Num;
 use colored::Colorize;
+use sha2::{Digest, Sha256};
+use zeroize::Zeroizing;
 
-use parameters::{public_params};
+use parameters::{LOGIN_STREAM, REGISTRATION_GROUP_ID, CHANGE_PASSWORD_DOMAIN, UNREGISTER_DOMAIN, RECOVERY_DOMAIN, RECOVERY_CODES};
 use zkp_utils::{
-    random_big_int,
+    negotiate_capabilities,
+    fetch_public_parameters,
+    load_server_key,
     get_user_credentials,
+    get_password,
+    get_credential_id,
+    get_recovery_code,
+    get_totp_code,
+    generate_recovery_codes,
+    get_new_password,
+    public_key,
+    decode_bytes,
+    bound_challenge,
+    confirm,
+    zkp_request_challenge,
+    zkp_authenticate,
+    zkp_ring,
+    zkp_authenticate_anonymously,
+    derive_session_key,
+    Commitment,
+    zkp_change_password,
+    zkp_unregister,
+    zkp_add_credential,
+    zkp_recover_account,
     zkp_register, 
-    zkp_authentication_challenge, 
     zkp_verify_authentication};
     
-use zkp_auth::{
+use zkp_auth::v2::{
     auth_client::AuthClient,
     RegisterResponse,
-    AuthenticationChallengeResponse,
-    AuthenticationAnswerResponse
+    AuthenticationAnswerResponse,
+    ChangePasswordResponse,
+    UnregisterResponse,
+    RecoverAccountResponse,
+    ListCredentialsRequest,
+    RevokeCredentialRequest,
+    VerifySecondFactorRequest,
+    EnrollTotpRequest,
+    ConfirmTotpRequest
 };
 
 pub mod zkp_auth {
-  tonic::include_proto!("zkp_auth");
+  pub mod v2 {
+    tonic::include_proto!("zkp_auth.v2");
+  }
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-  let mut client = AuthClient::connect("http://[::1]:8080").await?;
+  let (channel, channel_binding) = tls::connect().await?;
+  let mut client = AuthClient::new(channel);
 
-  let (p, q, g, h) = public_params();
+  // Check that the server speaks the same version of the protocol before anything else
+  let capabilities = negotiate_capabilities(&mut client).await?;
+
+  println!("{}", "Server capabilities".cyan());
+  for version in &capabilities.versions {
+    println!("version = {} ({})", version.service, version.encoding);
+  }
+  println!("groups = {}", capabilities.groups.join(", "));
+  println!("default group = {}", capabilities.default_group_id);
+  println!("kdfs = {}", capabilities.kdfs.join(", "));
+  println!("proof modes = {}", capabilities.proof_modes.join(", "));
+  println!();
+
+  // Public key of the server, to check that the challenges come from it
+  let server_key = load_server_key(&mut client).await?;
+
+  println!("{}", "Server key".cyan());
+  println!("group = {}", server_key.params.group_id);
+  println!("y = {}", server_key.y);
+  println!();
+
+  // Parameters used to register new users
+  let params = fetch_public_parameters(&mut client, REGISTRATION_GROUP_ID, "", "", false).await?;
 
   println!("{}", "Public parameters".cyan());
-  println!("p = {}", &p);
-  println!("q = {}", &q);
-  println!("g = {}", &g);
-  println!("h = {}", &h);
-  println!("");
+  println!("group = {}", &params.group_id);
+  println!("p = {}", &params.p);
+  println!("q = {}", &params.q);
+  println!("g = {}", &params.g);
+  println!("h = {}", &params.h);
+  println!("kdf = {}", &params.kdf);
+  println!("challenge bits = {}", if params.challenge_bits == 0 { format!("{} (c in [0, q))", params.q.bits()) } else { params.challenge_bits.to_string() });
+  println!("fingerprint = {}", params.fingerprint());
+  println!();
+
+  // Session of the last successful login, needed to manage the credentials
+  let mut session_id: Option<String> = None;
 
   let mut finish = false;
   let mut option = String::new();
 
   while !finish {
 
-    println!("");
-    println!("{}", "Please choose a number: 1. Register | 2. Login | 3. Exit".yellow());
-    println!("");
+    println!();
+    println!("{}", "Please choose a number: 1. Register | 2. Login | 3. Change password | 4. Delete account | 5. Add credential | 6. List credentials | 7. Revoke credential | 8. Recover account | 9. Enable second factor | 10. Anonymous login | 11. Exit".yellow());
+    println!();
 
         option.clear();
         stdin()
@@ -58,10 +123,16 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
         match option {
             1 => {
-                let (username, x) = get_user_credentials();
+                let (username, credential_id, x) = get_user_credentials();
+
+                // The public keys of the recovery codes are registered with the user
+                let recovery_codes = match confirm("Generate recovery codes? Type yes to generate them:") {
+                    true => generate_recovery_codes(RECOVERY_CODES),
+                    false => Zeroizing::default(),
+                };
 
                 // Send the parameters: user, y1 and y2 to the server
-                let register_request = zkp_register(&username, &x);
+                let register_request = zkp_register(&params, &username, &credential_id, &x, &recovery_codes);
                 println!("Request={:?}", register_request);
 
                 // Response is an empty struct as per the protobuf. Therefore there is no way to know from the server 
@@ -69,62 +140,379 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 // RegisterResponse to include the registration outcome. 
                 let register_response:Response<RegisterResponse> = client.register(register_request).await?;
                 println!("Response={:?}", register_response);
+
+                if !recovery_codes.is_empty() {
+                    println!();
+                    println!("{}", "Recovery codes. Keep them safe, each can be used once to set a new password:".cyan());
+                    for code in recovery_codes.iter() {
+                        println!("{}", code);
+                    }
+                    println!();
+                }
             }
             2 => {
-                let (username, x) = get_user_credentials();
+                let (username, credential_id, x) = get_user_credentials();
 
-                // Generate random k in the range {2, ..., q - 2}
-                let k = random_big_int(2.to_bigint().unwrap(), &q - 2);
+                // The login happens in the group the user registered with
+                let params = fetch_public_parameters(&mut client, "", &username, &credential_id, false).await?;
 
-                // Send the parameters: user, r1 and r2 to the server
-                let authentication_challenge_request = zkp_authentication_challenge(&username, &k);
-                let authentication_challenge_response:Response<AuthenticationChallengeResponse> = client
-                    .create_authentication_challenge(authentication_challenge_request)
-                    .await?;
-                let auth_id = &authentication_challenge_response.get_ref().auth_id;
+                // Generate random k in the range {2, ..., q - 2}, and the secret of the key exchange of the session key
+                let commitment = Commitment::new(&params, &x, &["login", &username, &credential_id], true);
 
-                // If user was not registered, notify the user and continue the loop
-                if auth_id == "UserNotRegistered" {
-                    println!("");
-                    println!("{}", "You are not registered yet".red());
-                    println!("");
-                    continue;
-                }
+                // Receive challenge c, on an Authenticate stream or with CreateAuthenticationChallenge. If user was not
+                // registered, notify the user and continue the loop
+                let (challenge, stream) = match LOGIN_STREAM {
+                    true => zkp_authenticate(&mut client, &channel_binding, &server_key, &params, &username, &credential_id, &commitment).await?
+                        .map_or((None, None), |(challenge, stream)| (Some(challenge), Some(stream))),
+                    false => (zkp_request_challenge(&mut client, &channel_binding, &server_key, &params, &username, &credential_id, 0, &commitment).await?, None),
+                };
+                let challenge = match challenge {
+                    Some(challenge) => challenge,
+                    None => {
+                        println!();
+                        println!("{}", "You are not registered yet".red());
+                        println!();
+                        continue;
+                    }
+                };
 
-                // Receive challenge c
-                let c: BigInt = Num::from_str_radix(
-                    &authentication_challenge_response.get_ref().c, 
-                    16)
-                    .unwrap();
-    
                 println!("{}", "Challenge".cyan());
-                println!("c = {}", &c);
-                println!("");
+                println!("c = {}", &challenge.c);
+                println!();
 
                 // Compute s = k - c * x (mod q)
-                let s = (((&k - &c * &x) % (&q)) + (&q)) % (&q);
+                let s = commitment.answer(&params, &x, &challenge.c);
 
                 println!("{}", "Answer".cyan());
                 println!("s = {}", &s);
-                println!("");
+                println!();
 
-                let authentication_answer_request = zkp_verify_authentication(&s, auth_id);
-                let verify_authentication_response:Response<AuthenticationAnswerResponse> = client
-                    .verify_authentication(authentication_answer_request)
-                    .await?;
+                let verify_authentication_response = match stream {
+                    Some(stream) => stream.answer(&params, &s).await?,
+                    None => {
+                        let authentication_answer_request = zkp_verify_authentication(&params, &s, &challenge.auth_id);
+                        let verify_authentication_response:Response<AuthenticationAnswerResponse> = client
+                            .verify_authentication(authentication_answer_request)
+                            .await?;
+                        verify_authentication_response.into_inner()
+                    }
+                };
+
+                // The server proves it derived the same session key before the client uses it
+                let login_session_key = match (&challenge.key_exchange, verify_authentication_response.session_id.as_str()) {
+                    (_, "WrongCredentials") | (None, _) => None,
+                    (Some(key_exchange), _) => Some(derive_session_key(&params, key_exchange, &s, &verify_authentication_response.key_confirmation)?),
+                };
+
+                // The proof is accepted but the user enabled the second factor: the login is finished with a TOTP code
+                let (login_session_id, login_credential_id) = match verify_authentication_response.session_id.as_str() {
+                    "WrongCredentials" => {
+                        println!("{}", "Wrong credentials. Please retry again".red());
+                        continue;
+                    }
+                    "SecondFactorRequired" => {
+                        let request = VerifySecondFactorRequest {
+                            second_factor_token: verify_authentication_response.second_factor_token,
+                            code: get_totp_code(),
+                        };
+                        let response = client.verify_second_factor(request).await?.into_inner();
+                        if response.session_id == "WrongCredentials" {
+                            println!("{}", "Wrong code. Please retry again".red());
+                            continue;
+                        }
+                        (response.session_id, response.credential_id)
+                    }
+                    _ => (verify_authentication_response.session_id, verify_authentication_response.credential_id),
+                };
 
-                let session_id = &verify_authentication_response.get_ref().session_id;
+                println!("{}", format!("Login succeeded with the credential {}!", login_credential_id).green());
+                session_id = Some(login_session_id);
+
+                // The key shared with the server to protect the traffic of the application
+                if let Some(key) = &login_session_key {
+                    let fingerprint: String = Sha256::digest(key).iter().map(|byte| format!("{:02x}", byte)).collect();
+                    println!("{}", format!("Session key established (fingerprint {})", fingerprint).green());
+                }
+
+            }
+            3 => {
+                let (username, credential_id, x) = get_user_credentials();
+                let new_x = match get_new_password() {
+                    Ok(new_x) => new_x,
+                    Err(error) => {
+                        println!("{}", error.red());
+                        continue;
+                    }
+                };
 
-                if session_id == "WrongCredentials" {
+                // The password is changed in the group the user registered with
+                let params = fetch_public_parameters(&mut client, "", &username, &credential_id, false).await?;
+
+                // Commit with the old x like for a login
+                let commitment = Commitment::new(&params, &x, &["change-password", &username, &credential_id], false);
+                let (auth_id, c) = match zkp_request_challenge(&mut client, &channel_binding, &server_key, &params, &username, &credential_id, 0, &commitment).await? {
+                    Some(challenge) => (challenge.auth_id, challenge.c),
+                    None => {
+                        println!();
+                        println!("{}", "You are not registered yet".red());
+                        println!();
+                        continue;
+                    }
+                };
+
+                // Answer the challenge bound to the new public key: s = k - c' * x (mod q)
+                let (new_y1, new_y2) = public_key(&params, new_x.expose());
+                let c = bound_challenge(&params, CHANGE_PASSWORD_DOMAIN, &c, &[&new_y1, &new_y2]);
+                let s = commitment.answer(&params, &x, &c);
+
+                let change_password_request = zkp_change_password(&params, &s, &auth_id, &new_y1, &new_y2);
+                let change_password_response:Response<ChangePasswordResponse> = client
+                    .change_password(change_password_request)
+                    .await?;
+
+                if change_password_response.get_ref().changed {
+                    println!("{}", "Password changed! Please login again".green());
+                }
+                else {
                     println!("{}", "Wrong credentials. Please retry again".red());
+                }
+            }
+            4 => {
+                let (username, credential_id, x) = get_user_credentials();
+
+                if !confirm(&format!("Delete the account {}? This cannot be undone. Type yes to confirm:", username.trim())) {
                     continue;
                 }
+
+                // The account is deleted with a proof in the group the user registered with
+                let params = fetch_public_parameters(&mut client, "", &username, &credential_id, false).await?;
+
+                let commitment = Commitment::new(&params, &x, &["unregister", &username, &credential_id], false);
+                let (auth_id, c) = match zkp_request_challenge(&mut client, &channel_binding, &server_key, &params, &username, &credential_id, 0, &commitment).await? {
+                    Some(challenge) => (challenge.auth_id, challenge.c),
+                    None => {
+                        println!();
+                        println!("{}", "You are not registered yet".red());
+                        println!();
+                        continue;
+                    }
+                };
+
+                // Answer the challenge bound to the deletion: s = k - c' * x (mod q)
+                let c = bound_challenge(&params, UNREGISTER_DOMAIN, &c, &[]);
+                let s = commitment.answer(&params, &x, &c);
+
+                let unregister_response:Response<UnregisterResponse> = client
+                    .unregister(zkp_unregister(&params, &s, &auth_id))
+                    .await?;
+
+                if unregister_response.get_ref().unregistered {
+                    println!("{}", "Account deleted".green());
+                }
                 else {
-                    println!("{}", "Login succeeded!".green());
+                    println!("{}", "Wrong credentials. Please retry again".red());
                 }
+            }
+            5 => {
+                let session_id = match &session_id {
+                    Some(session_id) => session_id,
+                    None => {
+                        println!("{}", "Please login first".red());
+                        continue;
+                    }
+                };
+
+                // The new credential has its own password, and is registered in the registration group
+                let credential_id = get_credential_id();
+                let x = match get_new_password() {
+                    Ok(x) => x,
+                    Err(error) => {
+                        println!("{}", error.red());
+                        continue;
+                    }
+                };
+
+                match client.add_credential(zkp_add_credential(&params, session_id, &credential_id, &x)).await {
+                    Ok(_) => println!("{}", "Credential added".green()),
+                    Err(status) => println!("{}", status.message().red()),
+                }
+            }
+            6 => {
+                let session_id = match &session_id {
+                    Some(session_id) => session_id,
+                    None => {
+                        println!("{}", "Please login first".red());
+                        continue;
+                    }
+                };
+
+                match client.list_credentials(ListCredentialsRequest { session_id: session_id.clone() }).await {
+                    Ok(response) => {
+                        println!("{}", "Credentials".cyan());
+                        for credential in &response.get_ref().credentials {
+                            println!("{} ({})", credential.credential_id, credential.group_id);
+                        }
+                    }
+                    Err(status) => println!("{}", status.message().red()),
+                }
+            }
+            7 => {
+                let session_id = match &session_id {
+                    Some(session_id) => session_id,
+                    None => {
+                        println!("{}", "Please login first".red());
+                        continue;
+                    }
+                };
+
+                let credential_id = get_credential_id();
+                let request = RevokeCredentialRequest {
+                    session_id: session_id.clone(),
+                    credential_id,
+                };
+
+                match client.revoke_credential(request).await {
+                    Ok(_) => println!("{}", "Credential revoked".green()),
+                    Err(status) => println!("{}", status.message().red()),
+                }
+            }
+            8 => {
+                let mut username = String::new();
+                println!("Please input user:");
+                stdin()
+                    .read_line(&mut username)
+                    .expect("Failed to read username");
+
+                let (recovery_code_id, x) = match get_recovery_code() {
+                    Ok(code) => code,
+                    Err(error) => {
+                        println!("{}", error.red());
+                        continue;
+                    }
+                };
+
+                // The new password is set on this credential, which is created if it does not exist
+                let credential_id = get_credential_id();
+                let new_x = match get_new_password() {
+                    Ok(new_x) => new_x,
+                    Err(error) => {
+                        println!("{}", error.red());
+                        continue;
+                    }
+                };
+
+                // The recovery codes were registered in the group of the registration
+                let params = fetch_public_parameters(&mut client, "", &username, "", true).await?;
+
+                // Commit with the x of the recovery code
+                let commitment = Commitment::new(&params, &x, &["recovery", &username, &recovery_code_id.to_string()], false);
+                let (auth_id, c) = match zkp_request_challenge(&mut client, &channel_binding, &server_key, &params, &username, "", recovery_code_id, &commitment).await? {
+                    Some(challenge) => (challenge.auth_id, challenge.c),
+                    None => {
+                        println!("{}", "Unknown or already used recovery code".red());
+                        continue;
+                    }
+                };
+
+                // Answer the challenge bound to the new public key: s = k - c' * x (mod q)
+                let (new_y1, new_y2) = public_key(&params, new_x.expose());
+                let c = bound_challenge(&params, RECOVERY_DOMAIN, &c, &[&new_y1, &new_y2]);
+                let s = commitment.answer(&params, &x, &c);
+
+                let recover_account_response:Response<RecoverAccountResponse> = client
+                    .recover_account(zkp_recover_account(&params, &s, &auth_id, &credential_id, &new_y1, &new_y2))
+                    .await?;
+
+                if recover_account_response.get_ref().recovered {
+                    println!("{}", format!(
+                        "Account recovered! Please login with the new password. {} recovery codes left",
+                        recover_account_response.get_ref().remaining_recovery_codes
+                    ).green());
+                }
+                else {
+                    println!("{}", "Wrong recovery code. Please retry again".red());
+                }
+            }
+            9 => {
+                let session_id = match &session_id {
+                    Some(session_id) => session_id,
+                    None => {
+                        println!("{}", "Please login first".red());
+                        continue;
+                    }
+                };
+
+                let enrollment = match client.enroll_totp(EnrollTotpRequest { session_id: session_id.clone() }).await {
+                    Ok(response) => response.into_inner(),
+                    Err(status) => {
+                        println!("{}", status.message().red());
+                        continue;
+                    }
+                };
+
+                println!("{}", "Add this secret to your authenticator app:".cyan());
+                println!("{}", enrollment.secret);
+                println!("otpauth://totp/zkp_auth?secret={}&digits={}&period={}", enrollment.secret, enrollment.digits, enrollment.period);
+                println!();
+
+                // The second factor is only required at login once a first code proves the app is set up
+                let request = ConfirmTotpRequest {
+                    session_id: session_id.clone(),
+                    code: get_totp_code(),
+                };
+                match client.confirm_totp(request).await {
+                    Ok(response) if response.get_ref().confirmed => println!("{}", "Second factor enabled".green()),
+                    Ok(_) => println!("{}", "Wrong code. Please retry again".red()),
+                    Err(status) => println!("{}", status.message().red()),
+                }
+            }
+            10 => {
+                if !capabilities.proof_modes.iter().any(|mode| mode == "ring") {
+                    println!("{}", "The server does not support anonymous logins".red());
+                    continue;
+                }
+
+                let session_id = match &session_id {
+                    Some(session_id) => session_id,
+                    None => {
+                        println!("{}", "Please login first".red());
+                        continue;
+                    }
+                };
 
+                // The ring of the credential of the last login, in its group. The password of that credential proves
+                // the knowledge of one of the keys of the ring.
+                let (group_id, ring) = match zkp_ring(&mut client, session_id).await {
+                    Ok(ring) => ring,
+                    Err(error) => {
+                        println!("{}", error.to_string().red());
+                        continue;
+                    }
+                };
+                let params = fetch_public_parameters(&mut client, &group_id, "", "", false).await?;
+                let ring = ring
+                    .iter()
+                    .map(|y1| decode_bytes(y1, &params, "ring key"))
+                    .collect::<Result<Vec<_>, String>>()?;
+
+                println!("{}", "Ring".cyan());
+                println!("{} keys of {}", ring.len(), &params.group_id);
+                println!();
+
+                let x = get_password();
+
+                // The proof goes over a new connection, which carries no session and no TLS exporter of the identified
+                // calls. The server still sees the address and the timing of the connection (see the README).
+                // The session proves that a registered user is calling, not which one, so it cannot manage credentials
+                let (channel, channel_binding) = tls::connect().await?;
+                let mut anonymous_client = AuthClient::new(channel);
+                match zkp_authenticate_anonymously(&mut anonymous_client, &channel_binding, &server_key, &params, &ring, &x).await {
+                    Ok(Some(_)) => println!("{}", format!("Anonymous login succeeded with a ring of {} keys!", ring.len()).green()),
+                    Ok(None) => println!("{}", "Wrong credentials. Please retry again".red()),
+                    Err(error) => println!("{}", error.to_string().red()),
+                }
             }
-            3 => { finish = true;}
+            11 => { finish = true;}
             _ => {println!("Invalid input!")}
         }
     }
@@ -0,0 +1,228 @@
+use std::fs;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use hmac::{Hmac, Mac};
+use num_bigint::{BigInt, Sign};
+use num_traits::One;
+use sha2::{Digest, Sha256};
+
+use zeroize::Zeroizing;
+
+use crate::parameters::{PublicParameters, NONCE_COUNTER_PATH, NONCE_DOMAIN, NONCE_EXTRA_LENGTH};
+use crate::secret::SecretNumber;
+
+// Derivation of the nonce k of a commitment from x, in the style of RFC 6979 section 3.2 with HMAC-SHA256, so that a
+// device with a bad random number generator does not reuse k and give away x.
+// The derivation is hedged: fresh random bytes are mixed in as the additional data of section 3.6. With a good
+// generator k is as random as before. With a broken one, k is still a different value for every commitment, as long
+// as the counter of NONCE_COUNTER_PATH goes up or the clock moves: the message also holds both of them. A device whose
+// generator, counter file and clock all repeat does reuse k.
+
+type HmacSha256 = Hmac<Sha256>;
+
+// The state K, V of the derivation depends on x, so it is wiped like x
+fn hmac(key: &[u8], parts: &[&[u8]]) -> Zeroizing<Vec<u8>> {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC accepts keys of any length");
+    for part in parts {
+        mac.update(part);
+    }
+    Zeroizing::new(mac.finalize().into_bytes().to_vec())
+}
+
+// The qlen leftmost bits of bytes as an integer
+fn bits2int(bytes: &[u8], q: &BigInt) -> BigInt {
+    let value = BigInt::from_bytes_be(Sign::Plus, bytes);
+    let length = bytes.len() as u64 * 8;
+    if length > q.bits() { value >> (length - q.bits()) } else { value }
+}
+
+// value as ceil(qlen / 8) big-endian bytes, value < q
+fn int2octets(value: &BigInt, q: &BigInt) -> Zeroizing<Vec<u8>> {
+    let length = q.bits().div_ceil(8) as usize;
+    let (_, bytes) = value.to_bytes_be();
+    let bytes = Zeroizing::new(bytes);
+    let mut octets = Zeroizing::new(vec![0; length.saturating_sub(bytes.len())]);
+    octets.extend(bytes.iter());
+    octets
+}
+
+// k in [1, q - 1] from the secret x < q, the hash h1 of the message and the additional data extra (RFC 6979 3.2 and 3.6)
+pub fn rfc6979(q: &BigInt, x: &BigInt, h1: &[u8], extra: &[u8]) -> BigInt {
+    let x = int2octets(x, q);
+    let h1 = int2octets(&(bits2int(h1, q) % q), q);
+
+    let mut v = Zeroizing::new(vec![0x01; 32]);
+    let mut k = Zeroizing::new(vec![0x00; 32]);
+    k = hmac(&k, &[&v, &[0x00], &x, &h1, extra]);
+    v = hmac(&k, &[&v]);
+    k = hmac(&k, &[&v, &[0x01], &x, &h1, extra]);
+    v = hmac(&k, &[&v]);
+
+    let length = q.bits().div_ceil(8) as usize;
+    loop {
+        let mut t = Zeroizing::new(Vec::with_capacity(length + 32));
+        while t.len() < length {
+            v = hmac(&k, &[&v]);
+            t.extend(v.iter());
+        }
+        let candidate = bits2int(&t, q);
+        if candidate >= One::one() && &candidate < q {
+            return candidate;
+        }
+        k = hmac(&k, &[&v, &[0x00]]);
+        v = hmac(&k, &[&v]);
+    }
+}
+
+// The next value of the counter of path, kept across runs. The last value is also kept in memory, so that the counter
+// still goes up in this process when the file cannot be written.
+fn next_counter(path: &Path) -> u64 {
+    static LAST: Mutex<u64> = Mutex::new(0);
+    let mut last = LAST.lock().unwrap();
+    let stored = fs::read_to_string(path).ok().and_then(|counter| counter.trim().parse().ok()).unwrap_or(0);
+    let next = (*last).max(stored) + 1;
+    if let Err(error) = fs::write(path, next.to_string()) {
+        println!("Cannot write the nonce counter {}: {}", path.display(), error);
+    }
+    *last = next;
+    next
+}
+
+// The nonce k of a commitment made with x for context, e.g. the operation, the user and the credential.
+// The message is NONCE_DOMAIN || counter || time || group id || context: the counter as an 8-byte and the time since the
+// epoch in nanoseconds as a 16-byte big-endian integer, each string prefixed with its length as a 4-byte big-endian integer.
+pub fn hedged_nonce(params: &PublicParameters, x: &SecretNumber, context: &[&str]) -> SecretNumber {
+    let extra: Vec<u8> = (0..NONCE_EXTRA_LENGTH).map(|_| rand::random()).collect();
+    derive_nonce(params, x, context, Path::new(NONCE_COUNTER_PATH), &extra)
+}
+
+fn derive_nonce(params: &PublicParameters, x: &SecretNumber, context: &[&str], counter: &Path, extra: &[u8]) -> SecretNumber {
+    let time = SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.as_nanos()).unwrap_or(0);
+    let mut message = Sha256::new();
+    message.update(NONCE_DOMAIN);
+    message.update(next_counter(counter).to_be_bytes());
+    message.update(time.to_be_bytes());
+    for field in [params.group_id.as_str()].iter().chain(context) {
+        message.update((field.len() as u32).to_be_bytes());
+        message.update(field.as_bytes());
+    }
+
+    let x = SecretNumber::new(x.expose() % &params.q);
+    SecretNumber::new(rfc6979(&params.q, x.expose(), &message.finalize(), extra))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use num::Num;
+    use rfc6979::consts::U32;
+
+    // The example of RFC 6979 A.1.2: the curve K-163, its order q and the key x, with h1 = SHA-256("sample")
+    const K163_Q: &str = "4000000000000000000020108A2E0CC0D99F8A5EF";
+    const K163_X: &str = "09A4D6792295A7F730FC3F2B49CBC0F62E862272F";
+    const K163_SAMPLE_K: &str = "23AF4074C90A02B3FE61D286D5C87F425E6BDD81B";
+
+    // RFC 6979 A.2.5: the curve P-256, its order q and the key x, with SHA-256
+    const P256_Q: &str = "FFFFFFFF00000000FFFFFFFFFFFFFFFFBCE6FAADA7179E84F3B9CAC2FC632551";
+    const P256_X: &str = "C9AFA9D845BA75166B5C215767B1D6934E50C3DB36E89B127B8A622B120F6721";
+    const P256_KNOWN_ANSWERS: [(&[u8], &str); 2] = [
+        (b"sample", "A6E3C57DD01ABE90086538398355DD4C3B17AA873382B0F24D6129493D8AAD60"),
+        (b"test", "D16B6AE827F17175E040871A1C7EC3500192C4C92677336EC2537ACAEE0008E0"),
+    ];
+
+    fn hex(value: &str) -> BigInt {
+        BigInt::from_str_radix(value, 16).unwrap()
+    }
+
+    fn parameters(q: &str) -> PublicParameters {
+        PublicParameters {
+            group_id: "test".to_string(),
+            p: One::one(),
+            q: hex(q),
+            g: One::one(),
+            h: One::one(),
+            kdf: String::new(),
+            challenge_bits: 0,
+        }
+    }
+
+    fn random_below(q: &BigInt) -> BigInt {
+        let bytes: Vec<u8> = (0..q.bits().div_ceil(8) + 8).map(|_| rand::random()).collect();
+        BigInt::from_bytes_be(Sign::Plus, &bytes) % q
+    }
+
+    #[test]
+    fn rfc_6979_k163_example() {
+        let k = rfc6979(&hex(K163_Q), &hex(K163_X), &Sha256::digest(b"sample"), &[]);
+        assert_eq!(k, hex(K163_SAMPLE_K));
+    }
+
+    #[test]
+    fn rfc_6979_p256_vectors() {
+        for (message, expected) in P256_KNOWN_ANSWERS {
+            let k = rfc6979(&hex(P256_Q), &hex(P256_X), &Sha256::digest(message), &[]);
+            assert_eq!(k, hex(expected), "{}", String::from_utf8_lossy(message));
+        }
+    }
+
+    // The additional data of section 3.6, against the implementation of the rfc6979 crate
+    #[test]
+    fn additional_data_matches_the_rfc6979_crate() {
+        let q = hex(P256_Q);
+        let octets = |value: &BigInt| <[u8; 32]>::try_from(int2octets(value, &q).as_slice()).unwrap();
+        for _ in 0..32 {
+            let x = random_below(&q);
+            let h1 = Sha256::digest(random_below(&q).to_bytes_be().1);
+            let extra: Vec<u8> = (0..NONCE_EXTRA_LENGTH).map(|_| rand::random()).collect();
+
+            let reference = rfc6979::generate_k::<Sha256, U32>(
+                &octets(&x).into(), &octets(&q).into(), &octets(&(bits2int(&h1, &q) % &q)).into(), &extra);
+            assert_eq!(rfc6979(&q, &x, &h1, &extra), BigInt::from_bytes_be(Sign::Plus, &reference));
+        }
+    }
+
+    // q of 9 bits makes the derivation retry often
+    #[test]
+    fn hedged_nonce_is_in_range() {
+        for q in [K163_Q, P256_Q, "101"] {
+            let params = parameters(q);
+            let one = BigInt::one();
+            for _ in 0..64 {
+                let x = SecretNumber::new(random_below(&params.q));
+                let k = hedged_nonce(&params, &x, &["login", "alice", "default"]);
+                assert!(k.expose() >= &one && k.expose() < &params.q, "{} with q = {}", k.expose(), q);
+            }
+        }
+    }
+
+    #[test]
+    fn hedged_nonce_differs_under_different_extra_randomness() {
+        let params = parameters(P256_Q);
+        let x = SecretNumber::new(hex(P256_X));
+        let first = hedged_nonce(&params, &x, &["login", "alice", "default"]);
+        let second = hedged_nonce(&params, &x, &["login", "alice", "default"]);
+        assert_ne!(first.expose(), second.expose());
+
+        let h1 = Sha256::digest(b"sample");
+        assert_ne!(rfc6979(&params.q, x.expose(), &h1, &[0; 32]), rfc6979(&params.q, x.expose(), &h1, &[1; 32]));
+        assert_ne!(rfc6979(&params.q, x.expose(), &h1, &[0; 32]), rfc6979(&params.q, x.expose(), &h1, &[]));
+    }
+
+    // A generator that only returns zeros: the counter alone keeps k apart, even when the clock does not move
+    #[test]
+    fn hedged_nonce_differs_without_extra_randomness() {
+        let params = parameters(P256_Q);
+        let x = SecretNumber::new(hex(P256_X));
+        let counter = std::env::temp_dir().join(format!("zkp_nonce_counter_{}", std::process::id()));
+        let zeros = [0; NONCE_EXTRA_LENGTH];
+        let first = derive_nonce(&params, &x, &["login", "alice", "default"], &counter, &zeros);
+        let second = derive_nonce(&params, &x, &["login", "alice", "default"], &counter, &zeros);
+        assert_ne!(first.expose(), second.expose());
+
+        let stored: u64 = fs::read_to_string(&counter).unwrap().parse().unwrap();
+        fs::remove_file(&counter).unwrap();
+        assert!(stored >= 2);
+    }
+}
@@ -1,22 +1,218 @@
 #![allow(unused)]
 
 use num_bigint::{BigInt, Sign};
+use num_traits::One;
+use sha2::{Digest, Sha256};
+
+use crate::zkp_utils::{mod_exp, encode_bytes};
 
 // Public parameters
-// 2048-bit MODP Group described in RFC 3526:
-// p = 32317006071311007300338913926423828248817941241140239112842009751400741706634354222619689417363569347117901737909704191754605873209195028853758986185622153212175412514901774520270235796078236248884246189477587641105928646099411723245426622522193230540919037680524235519125679715870117001058055877651038861847280257976054903569732561526167081339361799541336476559160368317896729073178384589680639671900977202194168647225871031411336429319536193471636533209717077448227988588565369208645296636077250268955505928362751121174096972998068410554359584866583291642136218231078990999448652468262416972035911852507045361090559 (2048-bit prime)
-// q = 16158503035655503650169456963211914124408970620570119556421004875700370853317177111309844708681784673558950868954852095877302936604597514426879493092811076606087706257450887260135117898039118124442123094738793820552964323049705861622713311261096615270459518840262117759562839857935058500529027938825519430923640128988027451784866280763083540669680899770668238279580184158948364536589192294840319835950488601097084323612935515705668214659768096735818266604858538724113994294282684604322648318038625134477752964181375560587048486499034205277179792433291645821068109115539495499724326234131208486017955926253522680545279 ((p-1) / 2)
-// g = 2 
-// h = 4   
-
-pub fn public_params() -> (BigInt, BigInt, i32, i32) {
-    (   
-        BigInt::from_bytes_be(Sign::Plus, 
-            b"\xff\xff\xff\xff\xff\xff\xff\xff\xc9\x0f\xda\xa2!h\xc24\xc4\xc6b\x8b\x80\xdc\x1c\xd1)\x02N\x08\x8ag\xcct\x02\x0b\xbe\xa6;\x13\x9b\"QJ\x08y\x8e4\x04\xdd\xef\x95\x19\xb3\xcd:C\x1b0+\nm\xf2_\x147O\xe15mmQ\xc2E\xe4\x85\xb5vb^~\xc6\xf4LB\xe9\xa67\xedk\x0b\xff\\\xb6\xf4\x06\xb7\xed\xee8k\xfbZ\x89\x9f\xa5\xae\x9f$\x11|K\x1f\xe6I(fQ\xec\xe4[=\xc2\x00|\xb8\xa1c\xbf\x05\x98\xdaH6\x1cU\xd3\x9ai\x16?\xa8\xfd$\xcf_\x83e]#\xdc\xa3\xad\x96\x1cb\xf3V \x85R\xbb\x9e\xd5)\x07p\x96\x96mg\x0c5NJ\xbc\x98\x04\xf1tl\x08\xca\x18!|2\x90^F.6\xce;\xe3\x9ew,\x18\x0e\x86\x03\x9b\'\x83\xa2\xec\x07\xa2\x8f\xb5\xc5]\xf0oLR\xc9\xde+\xcb\xf6\x95X\x17\x189\x95I|\xea\x95j\xe5\x15\xd2&\x18\x98\xfa\x05\x10\x15r\x8eZ\x8a\xac\xaah\xff\xff\xff\xff\xff\xff\xff\xff"),
-
-        BigInt::from_bytes_be(Sign::Plus, 
-            b"\x7f\xff\xff\xff\xff\xff\xff\xff\xe4\x87\xedQ\x10\xb4a\x1abc1E\xc0n\x0eh\x94\x81\'\x04E3\xe6:\x01\x05\xdfS\x1d\x89\xcd\x91(\xa5\x04<\xc7\x1a\x02n\xf7\xca\x8c\xd9\xe6\x9d!\x8d\x98\x15\x856\xf9/\x8a\x1b\xa7\xf0\x9a\xb6\xb6\xa8\xe1\"\xf2B\xda\xbb1/?cz&!t\xd3\x1b\xf6\xb5\x85\xff\xae[z\x03[\xf6\xf7\x1c5\xfd\xadD\xcf\xd2\xd7O\x92\x08\xbe%\x8f\xf3$\x943(\xf6r-\x9e\xe1\x00>\\P\xb1\xdf\x82\xccm$\x1b\x0e*\xe9\xcd4\x8b\x1f\xd4~\x92g\xaf\xc1\xb2\xae\x91\xeeQ\xd6\xcb\x0e1y\xab\x10B\xa9]\xcfj\x94\x83\xb8KK6\xb3\x86\x1a\xa7%^L\x02x\xba6\x04e\x0c\x10\xbe\x19H/#\x17\x1bg\x1d\xf1\xcf;\x96\x0c\x07C\x01\xcd\x93\xc1\xd1v\x03\xd1G\xda\xe2\xae\xf87\xa6)d\xef\x15\xe5\xfbJ\xac\x0b\x8c\x1c\xca\xa4\xbeuJ\xb5r\x8a\xe9\x13\x0cL}\x02\x88\n\xb9G-EVU4\x7f\xff\xff\xff\xff\xff\xff\xff"),
-        2,
-        4
-    )
-}
\ No newline at end of file
+// The client no longer hardcodes the group: it fetches the parameters from the server with GetPublicParameters
+// and only accepts them if their fingerprint matches the one pinned below for that group.
+// Pinned groups: RFC 3526 2048/3072/4096-bit MODP groups and RFC 7919 ffdhe groups, all with g = 2, h = 4,
+// x is the little-endian integer of the UTF-8 password bytes (password-bytes-le)
+pub const PINNED_FINGERPRINTS: [(&str, &str); 8] = [
+    ("rfc3526-2048", "76bec26022d808c5f91ceda9a57634983781aed1b755064850ca2364ce69a1d2"),
+    ("rfc3526-3072", "0923d20292bf19ac28756829e4dd629c6a70a5b6d41f9a78f2a32e8d2b3c7a79"),
+    ("rfc3526-4096", "dd8da42cbd95d7662e6b3d93160350f81ba31b321c69248fe612a7656f205187"),
+    ("ffdhe2048", "39f27482f35a93c332d77cafa9338337b537465989688145c3172e7a7b3b0ee1"),
+    ("ffdhe3072", "d4bcf8811824a4b1977000a7d5d72ddeb691a07056bc9c3bad55e6cf08531052"),
+    ("ffdhe4096", "8a403c02cbeaa1dabe9d17d954efca025b97dcb65f91690c54cc384ed86392e2"),
+    ("ffdhe6144", "6d6c42cf1b1750099b5f51c3a78b4967d9f4a977dfae0bfdc5e74b1e70b21d7f"),
+    ("ffdhe8192", "70b941ced1cb41b44b8cb7ef3f8d27d96aa637495a98bd0301aa537e262031c2"),
+];
+
+// Version of the Auth service this client speaks, and the encoding of its numbers
+pub const PROTOCOL_VERSION: &str = "zkp_auth.v2.Auth";
+pub const ENCODING: &str = "bytes-be-fixed";
+
+// Domain separators of the challenges bound to an operation other than a login
+pub const CHANGE_PASSWORD_DOMAIN: &[u8] = b"zkp_auth/change-password";
+pub const UNREGISTER_DOMAIN: &[u8] = b"zkp_auth/unregister";
+pub const RECOVERY_DOMAIN: &[u8] = b"zkp_auth/recovery";
+
+// Public key of the server, as written by the server on its first start: "group_id:y" with y in hexadecimal.
+// The server signs the transcript of every challenge with the matching private key, and the client refuses to answer a
+// challenge without a valid signature, so that an impostor cannot collect commitments and answers.
+pub const PINNED_SERVER_KEY_PATH: &str = "../server/server_key.pub";
+
+// Connect over TLS 1.3, trusting only the certificate the server wrote on its first start, and bind the challenges to
+// the connection: c becomes SHA-256(CHANNEL_BINDING_DOMAIN || c || tls-exporter) mod q (see channel_bound_challenge in
+// zkp_utils.rs). Must match TLS_ENABLED of the server.
+pub const SERVER_ADDRESS: &str = "[::1]:8080";
+pub const TLS_ENABLED: bool = false;
+pub const PINNED_TLS_CERTIFICATE_PATH: &str = "../server/tls_cert.pem";
+pub const TLS_SERVER_NAME: &str = "localhost";
+pub const CHANNEL_BINDING_LABEL: &[u8] = b"EXPORTER-Channel-Binding";
+pub const CHANNEL_BINDING_LENGTH: usize = 32;
+pub const CHANNEL_BINDING_DOMAIN: &[u8] = b"zkp_auth/tls-exporter";
+
+// Domain separators of the signature of the server and of the transcript of a challenge
+pub const SERVER_SIGNATURE_DOMAIN: &[u8] = b"zkp_auth/server-signature";
+pub const CHALLENGE_TRANSCRIPT_DOMAIN: &[u8] = b"zkp_auth/challenge";
+
+// Optional key exchange run alongside the login (see derive_session_key in zkp_utils.rs): its name, the HKDF info of
+// the session key and of the confirmation key, the label of the key confirmation of the server, and the key length in bytes
+pub const KEY_EXCHANGE: &str = "dh-hkdf-sha256";
+pub const SESSION_KEY_INFO: &[u8] = b"zkp_auth/session-key";
+pub const CONFIRMATION_KEY_INFO: &[u8] = b"zkp_auth/confirmation-key";
+pub const SERVER_FINISHED: &[u8] = b"zkp_auth/server-finished";
+pub const KEY_LENGTH: usize = 32;
+
+// Derive the nonce k of the commitments from x, the context of the commitment, a counter kept in NONCE_COUNTER_PATH,
+// the time and NONCE_EXTRA_LENGTH fresh random bytes (hedged RFC 6979, see nonce.rs) instead of drawing it from the
+// random number generator alone
+pub const HEDGED_NONCE: bool = true;
+pub const NONCE_DOMAIN: &[u8] = b"zkp_auth/nonce";
+pub const NONCE_EXTRA_LENGTH: usize = 32;
+pub const NONCE_COUNTER_PATH: &str = "nonce_counter";
+
+// Log in over one Authenticate stream instead of CreateAuthenticationChallenge and VerifyAuthentication
+pub const LOGIN_STREAM: bool = true;
+
+// Puzzle the server asks for under load before issuing a challenge (see puzzle.rs): the domain separator of the hash,
+// the hardest puzzle the client accepts to solve (about 2^difficulty SHA-256 computations), and how many puzzles it
+// solves for one request
+pub const PUZZLE_DOMAIN: &[u8] = b"zkp_auth/puzzle";
+pub const PUZZLE_MAX_DIFFICULTY: u32 = 28;
+pub const PUZZLE_MAX_ATTEMPTS: u32 = 3;
+
+// Number of recovery codes generated at registration. A code looks like 3-ABCD-EFGH-JKLM-NPQR: the number of the code
+// followed by 16 random characters (80 bits). x is derived from the whole code like from a password.
+pub const RECOVERY_CODES: usize = 10;
+pub const RECOVERY_CODE_ALPHABET: &[u8] = b"ABCDEFGHJKLMNPQRSTUVWXYZ23456789";
+pub const RECOVERY_CODE_GROUPS: usize = 4;
+
+// Proofs of knowledge of x this client implements
+pub const SUPPORTED_PROOF_MODES: [&str; 2] = ["chaum-pedersen", "ring"];
+
+// Domain separator of the transcript of the challenge of an anonymous login (see zkp_authenticate_anonymously in zkp_utils.rs)
+pub const RING_TRANSCRIPT_DOMAIN: &[u8] = b"zkp_auth/ring-challenge";
+
+// Group to register new users with. Empty means the default group of the server.
+pub const REGISTRATION_GROUP_ID: &str = "";
+
+// Derivations of x from the password this client implements
+pub const SUPPORTED_KDFS: [&str; 1] = ["password-bytes-le"];
+
+// Smallest accepted size of p in bits
+pub const MIN_MODULUS_BITS: u64 = 2048;
+
+// Smallest accepted bit length of the challenges, when the server does not draw them from [0, q): a prover who does not
+// know x passes with probability 2^-bits, so the soundness error is at most 2^-128
+pub const MIN_CHALLENGE_BITS: u64 = 128;
+
+#[derive(Debug, Clone)]
+pub struct PublicParameters {
+    pub group_id: String,
+    pub p: BigInt,
+    pub q: BigInt,
+    pub g: BigInt,
+    pub h: BigInt,
+    pub kdf: String,
+    // Bit length of the challenges, 0 for [0, q). Not part of the fingerprint: it is a setting of the server, not of the group.
+    pub challenge_bits: u64,
+}
+
+impl PublicParameters {
+
+    // The fingerprint pinned for this group, if the group is known
+    pub fn pinned_fingerprint(&self) -> Option<&'static str> {
+        PINNED_FINGERPRINTS
+            .iter()
+            .find(|(group_id, _)| *group_id == self.group_id)
+            .map(|(_, fingerprint)| *fingerprint)
+    }
+
+    // Number of bytes of every number encoded on the wire: the byte length of p
+    pub fn byte_length(&self) -> usize {
+        self.p.bits().div_ceil(8) as usize
+    }
+
+    // Number of challenges: c is in [0, 2^challenge_bits), or in [0, q)
+    pub fn challenge_space(&self) -> BigInt {
+        match self.challenge_bits {
+            0 => self.q.clone(),
+            bits => BigInt::one() << bits,
+        }
+    }
+
+    // SHA-256 of "group_id:p:q:g:h:kdf" with the numbers in lowercase hexadecimal
+    pub fn fingerprint(&self) -> String {
+        let canonical = format!(
+            "{}:{}:{}:{}:{}:{}",
+            self.group_id,
+            self.p.to_str_radix(16),
+            self.q.to_str_radix(16),
+            self.g.to_str_radix(16),
+            self.h.to_str_radix(16),
+            self.kdf
+        );
+        Sha256::digest(canonical.as_bytes())
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect()
+    }
+
+    // Check that p = 2q + 1 is large enough and that g and h are distinct generators of the subgroup of order q
+    pub fn validate(&self) -> Result<(), String> {
+        let one: BigInt = One::one();
+
+        if self.p.bits() < MIN_MODULUS_BITS {
+            return Err(format!("p has {} bits, at least {} are required", self.p.bits(), MIN_MODULUS_BITS));
+        }
+        if self.p != &self.q * 2 + &one {
+            return Err("p is not equal to 2q + 1".to_string());
+        }
+        for (name, generator) in [("g", &self.g), ("h", &self.h)] {
+            if generator <= &one || generator >= &(&self.p - &one) {
+                return Err(format!("{} is not in the range (1, p - 1)", name));
+            }
+            if mod_exp(generator, &self.q, &self.p) != one {
+                return Err(format!("{} does not generate the subgroup of order q", name));
+            }
+        }
+        if self.g == self.h {
+            return Err("g and h are equal".to_string());
+        }
+        if !SUPPORTED_KDFS.contains(&self.kdf.as_str()) {
+            return Err(format!("unsupported KDF {}", self.kdf));
+        }
+        if self.challenge_bits != 0 && self.challenge_bits < MIN_CHALLENGE_BITS {
+            return Err(format!("{}-bit challenges are too short, at least {} bits are required", self.challenge_bits, MIN_CHALLENGE_BITS));
+        }
+        if self.challenge_bits >= self.q.bits() {
+            return Err(format!("{}-bit challenges do not fit in q", self.challenge_bits));
+        }
+
+        Ok(())
+    }
+}
+
+// The pinned public key of the server, in a pinned group
+#[derive(Debug, Clone)]
+pub struct ServerKey {
+    pub params: PublicParameters,
+    pub y: BigInt,
+}
+
+impl ServerKey {
+
+    // Check the Schnorr signature (e, s) of transcript: R' = g^s * y^e, and e must be equal to
+    // SHA-256(SERVER_SIGNATURE_DOMAIN || y || R' || transcript) mod q, with y and R' as fixed-length big-endian bytes
+    pub fn verify(&self, transcript: &[u8], e: &BigInt, s: &BigInt) -> bool {
+        let params = &self.params;
+        if e >= &params.q || s >= &params.q {
+            return false;
+        }
+
+        let r = (mod_exp(&params.g, s, &params.p) * mod_exp(&self.y, e, &params.p)) % &params.p;
+
+        let mut hasher = Sha256::new();
+        hasher.update(SERVER_SIGNATURE_DOMAIN);
+        hasher.update(encode_bytes(&self.y, params));
+        hasher.update(encode_bytes(&r, params));
+        hasher.update(transcript);
+        &(BigInt::from_bytes_be(Sign::Plus, &hasher.finalize()) % &params.q) == e
+    }
+}
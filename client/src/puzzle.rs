@@ -0,0 +1,119 @@
+use sha2::{Digest, Sha256};
+use tonic::{Code, Status};
+
+use crate::parameters::{PUZZLE_DOMAIN, PUZZLE_MAX_DIFFICULTY};
+
+// Client puzzle the server requires on CreateAuthenticationChallenge when it is under load. The RESOURCE_EXHAUSTED
+// response carries a seed and a difficulty in its metadata. The solution is a nonce such that
+//   SHA-256(PUZZLE_DOMAIN || seed || user || nonce)
+// starts with difficulty zero bits, with user prefixed with its length as a 4-byte big-endian integer and the nonce as
+// 8 big-endian bytes. The request is then sent again with the seed and the nonce.
+
+// Metadata of the RESOURCE_EXHAUSTED responses
+pub const SEED_METADATA: &str = "zkp-puzzle-seed-bin";
+pub const DIFFICULTY_METADATA: &str = "zkp-puzzle-difficulty";
+
+// A puzzle sent by the server
+#[derive(Debug, Clone)]
+pub struct Puzzle {
+    pub seed: Vec<u8>,
+    pub difficulty: u32,
+}
+
+impl Puzzle {
+
+    // The puzzle of a RESOURCE_EXHAUSTED status, None for any other status
+    pub fn from_status(status: &Status) -> Option<Result<Puzzle, String>> {
+        if status.code() != Code::ResourceExhausted {
+            return None;
+        }
+        let seed = status.metadata().get_bin(SEED_METADATA).and_then(|seed| seed.to_bytes().ok());
+        let difficulty = status.metadata().get(DIFFICULTY_METADATA)
+            .and_then(|difficulty| difficulty.to_str().ok())
+            .and_then(|difficulty| difficulty.parse::<u32>().ok());
+
+        Some(match (seed, difficulty) {
+            (Some(seed), Some(difficulty)) if difficulty <= PUZZLE_MAX_DIFFICULTY => Ok(Puzzle { seed: seed.to_vec(), difficulty }),
+            (Some(_), Some(difficulty)) => Err(format!("The server asks for a puzzle of difficulty {}, more than the {} this client solves", difficulty, PUZZLE_MAX_DIFFICULTY)),
+            _ => Err(format!("The server is out of resources: {}", status.message())),
+        })
+    }
+
+    // Search for a nonce from a random start, so that two requests of the same user do not find the same solution
+    pub fn solve(&self, user: &str) -> u64 {
+        let mut nonce: u64 = rand::random();
+        while leading_zero_bits(&puzzle_hash(&self.seed, user, nonce)) < self.difficulty {
+            nonce = nonce.wrapping_add(1);
+        }
+        nonce
+    }
+}
+
+// SHA-256(PUZZLE_DOMAIN || seed || user || nonce)
+fn puzzle_hash(seed: &[u8], user: &str, nonce: u64) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.update(PUZZLE_DOMAIN);
+    hasher.update(seed);
+    hasher.update((user.len() as u32).to_be_bytes());
+    hasher.update(user.as_bytes());
+    hasher.update(nonce.to_be_bytes());
+    hasher.finalize().to_vec()
+}
+
+fn leading_zero_bits(hash: &[u8]) -> u32 {
+    let mut bits = 0;
+    for byte in hash {
+        bits += byte.leading_zeros();
+        if *byte != 0 {
+            break;
+        }
+    }
+    bits
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tonic::metadata::{BinaryMetadataValue, MetadataValue};
+
+    // Known answers shared with server/src/puzzle.rs, so that both sides hash the same way: with the seed 0, 1, ..., 31,
+    // the user, a nonce and the leading zero bits of its hash
+    const SEED: [u8; 32] = [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 28, 29, 30, 31];
+    const ALICE_NONCE_0_HASH: &str = "6870aafbc8698268f463384787405f495ad0b999cb13f3f8ac84bb75949026eb";
+    const SOLUTIONS: [(&str, u64, u32); 4] = [("alice", 134, 8), ("alice", 2736, 12), ("", 70, 10), ("", 4507, 12)];
+
+    fn status(difficulty: &str) -> Status {
+        let mut status = Status::resource_exhausted("The server is under load");
+        status.metadata_mut().insert_bin(SEED_METADATA, BinaryMetadataValue::from_bytes(&SEED));
+        status.metadata_mut().insert(DIFFICULTY_METADATA, MetadataValue::try_from(difficulty).unwrap());
+        status
+    }
+
+    #[test]
+    fn known_answers() {
+        let hash: String = puzzle_hash(&SEED, "alice", 0).iter().map(|byte| format!("{:02x}", byte)).collect();
+        assert_eq!(hash, ALICE_NONCE_0_HASH);
+        for (user, nonce, bits) in SOLUTIONS {
+            assert_eq!(leading_zero_bits(&puzzle_hash(&SEED, user, nonce)), bits, "{:?} {}", user, nonce);
+        }
+    }
+
+    #[test]
+    fn solutions_have_the_difficulty() {
+        let puzzle = Puzzle { seed: SEED.to_vec(), difficulty: 10 };
+        for user in ["alice", ""] {
+            assert!(leading_zero_bits(&puzzle_hash(&SEED, user, puzzle.solve(user))) >= 10, "{:?}", user);
+        }
+    }
+
+    #[test]
+    fn puzzles_are_read_from_the_status() {
+        let puzzle = Puzzle::from_status(&status("12")).unwrap().unwrap();
+        assert_eq!((puzzle.seed.as_slice(), puzzle.difficulty), (SEED.as_slice(), 12));
+
+        assert!(Puzzle::from_status(&Status::unavailable("down")).is_none());
+        assert!(Puzzle::from_status(&Status::resource_exhausted("no puzzle")).unwrap().is_err());
+        assert!(Puzzle::from_status(&status("twelve")).unwrap().is_err());
+        assert!(Puzzle::from_status(&status(&(PUZZLE_MAX_DIFFICULTY + 1).to_string())).unwrap().is_err());
+    }
+}
@@ -0,0 +1,53 @@
+use std::fmt;
+use std::sync::atomic::{compiler_fence, Ordering};
+use num_bigint::{BigInt, Sign};
+use zeroize::{Zeroize, Zeroizing};
+
+// A secret number, x or k, whose digits are overwritten with zeros when it is dropped. It is never printed: Debug
+// shows a placeholder and there is no Display. The arithmetic of num-bigint allocates temporaries that cannot be wiped,
+// so this only covers the values the client keeps.
+pub struct SecretNumber(BigInt);
+
+impl SecretNumber {
+    pub fn new(value: BigInt) -> Self {
+        SecretNumber(value)
+    }
+
+    // x of a password or of a recovery code: the little-endian integer of its UTF-8 bytes
+    pub fn from_password(password: &str) -> Self {
+        SecretNumber(BigInt::from_bytes_le(Sign::Plus, password.as_bytes()))
+    }
+
+    pub fn expose(&self) -> &BigInt {
+        &self.0
+    }
+}
+
+// Overwrite the digits of value with zeros, in place, and leave value equal to 0. With Sign::NoSign, assign_from_slice
+// would drop the digits without writing them.
+pub fn wipe(value: &mut BigInt) {
+    let zeros = vec![0u32; value.iter_u32_digits().len()];
+    value.assign_from_slice(Sign::Plus, &zeros);
+    compiler_fence(Ordering::SeqCst);
+}
+
+impl Zeroize for SecretNumber {
+    fn zeroize(&mut self) {
+        wipe(&mut self.0);
+    }
+}
+
+impl Drop for SecretNumber {
+    fn drop(&mut self) {
+        self.zeroize();
+    }
+}
+
+impl fmt::Debug for SecretNumber {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SecretNumber(<redacted>)")
+    }
+}
+
+// A password as typed, wiped when dropped
+pub type Password = Zeroizing<String>;
@@ -0,0 +1,281 @@
+use num_bigint::{BigInt, ToBigInt};
+use num_traits::Zero;
+
+use crate::parameters::PublicParameters;
+use crate::secret::SecretNumber;
+use crate::zkp_utils::{mod_exp, random_big_int};
+
+// Sigma protocols: three-move proofs of knowledge (commitment, challenge, response) with special soundness and
+// honest-verifier zero knowledge. The instances below all live in a prime-order subgroup of Z_p^* and answer with
+// s = k - c * x (mod q), like the login. The client only proves: the proofs are checked by the verifiers of the server
+// (server/src/sigma.rs), which also compose them with And.
+pub trait SigmaProtocol {
+    // What the prover knows
+    type Witness;
+    // What the prover keeps between the commitment and the response
+    type Randomness;
+    type Commitment: Clone;
+    type Response: Clone;
+
+    // The first move: the commitment and the randomness behind it
+    fn commit(&self, witness: &Self::Witness) -> (Self::Randomness, Self::Commitment);
+
+    // Challenges are integers in [0, challenge_space())
+    fn challenge_space(&self) -> &BigInt;
+
+    // The third move: the answer to the challenge c. The randomness is borrowed, so that k is not copied.
+    fn respond(&self, witness: &Self::Witness, randomness: &Self::Randomness, c: &BigInt) -> Self::Response;
+
+    // A transcript (commitment, c, response) accepted by the verifier, computed without the witness for a challenge
+    // chosen in advance. Its distribution is the one of honest transcripts with that challenge.
+    fn simulate(&self, c: &BigInt) -> (Self::Commitment, Self::Response);
+}
+
+// A random exponent in {2, ..., q - 2}, like k
+fn random_exponent(q: &BigInt) -> BigInt {
+    random_big_int(2.to_bigint().unwrap(), q - 1)
+}
+
+// A random integer in [0, bound): a challenge, or a simulated response
+fn random_below(bound: &BigInt) -> BigInt {
+    random_big_int(Zero::zero(), bound.clone())
+}
+
+// A random k, wiped when dropped
+fn random_nonce(q: &BigInt) -> SecretNumber {
+    SecretNumber::new(random_exponent(q))
+}
+
+// k - c * x (mod q). c * x is wiped once s is computed.
+fn answer(k: &SecretNumber, c: &BigInt, x: &BigInt, q: &BigInt) -> BigInt {
+    let product = SecretNumber::new(c * x);
+    ((k.expose() - product.expose()) % q + q) % q
+}
+
+// Knowledge of x such that y = g^x: commitment r = g^k, checked with g^s * y^c = r
+#[derive(Debug, Clone)]
+pub struct Schnorr<'a> {
+    pub p: &'a BigInt,
+    pub q: &'a BigInt,
+    pub g: &'a BigInt,
+    pub y: &'a BigInt,
+}
+
+impl<'a> Schnorr<'a> {
+    pub fn new(params: &'a PublicParameters, y: &'a BigInt) -> Self {
+        Schnorr { p: &params.p, q: &params.q, g: &params.g, y }
+    }
+}
+
+impl SigmaProtocol for Schnorr<'_> {
+    type Witness = BigInt;
+    type Randomness = SecretNumber;
+    type Commitment = BigInt;
+    type Response = BigInt;
+
+    fn commit(&self, _: &BigInt) -> (SecretNumber, BigInt) {
+        let k = random_nonce(self.q);
+        let r = mod_exp(self.g, k.expose(), self.p);
+        (k, r)
+    }
+
+    fn challenge_space(&self) -> &BigInt {
+        self.q
+    }
+
+    fn respond(&self, x: &BigInt, k: &SecretNumber, c: &BigInt) -> BigInt {
+        answer(k, c, x, self.q)
+    }
+
+    fn simulate(&self, c: &BigInt) -> (BigInt, BigInt) {
+        let s = random_below(self.q);
+        let r = mod_exp(self.g, &s, self.p) * mod_exp(self.y, c, self.p) % self.p;
+        (r, s)
+    }
+}
+
+// Knowledge of x such that y1 = g^x and y2 = h^x, the proof of the login: commitment (r1, r2) = (g^k, h^k), checked
+// with g^s * y1^c = r1 and h^s * y2^c = r2
+#[derive(Debug, Clone)]
+pub struct ChaumPedersen<'a> {
+    pub p: &'a BigInt,
+    pub q: &'a BigInt,
+    pub g: &'a BigInt,
+    pub h: &'a BigInt,
+    pub y1: &'a BigInt,
+    pub y2: &'a BigInt,
+}
+
+impl<'a> ChaumPedersen<'a> {
+    pub fn new(params: &'a PublicParameters, y1: &'a BigInt, y2: &'a BigInt) -> Self {
+        ChaumPedersen { p: &params.p, q: &params.q, g: &params.g, h: &params.h, y1, y2 }
+    }
+}
+
+impl SigmaProtocol for ChaumPedersen<'_> {
+    type Witness = BigInt;
+    type Randomness = SecretNumber;
+    type Commitment = (BigInt, BigInt);
+    type Response = BigInt;
+
+    fn commit(&self, _: &BigInt) -> (SecretNumber, (BigInt, BigInt)) {
+        let k = random_nonce(self.q);
+        let commitment = (mod_exp(self.g, k.expose(), self.p), mod_exp(self.h, k.expose(), self.p));
+        (k, commitment)
+    }
+
+    fn challenge_space(&self) -> &BigInt {
+        self.q
+    }
+
+    fn respond(&self, x: &BigInt, k: &SecretNumber, c: &BigInt) -> BigInt {
+        answer(k, c, x, self.q)
+    }
+
+    fn simulate(&self, c: &BigInt) -> ((BigInt, BigInt), BigInt) {
+        let s = random_below(self.q);
+        let r1 = mod_exp(self.g, &s, self.p) * mod_exp(self.y1, c, self.p) % self.p;
+        let r2 = mod_exp(self.h, &s, self.p) * mod_exp(self.y2, c, self.p) % self.p;
+        ((r1, r2), s)
+    }
+}
+
+// Knowledge of the witness of one of any number of proofs, without telling which (Cramer–Damgård–Schoenmakers). The
+// prover simulates every other proof with a challenge of its choice and answers its own with the rest of c: the
+// challenges add up to c modulo the challenge space. There must be at least one proof, and all must have the same
+// challenge space.
+#[derive(Debug, Clone)]
+pub struct AnyOf<P>(pub Vec<P>);
+
+// The randomness of the proof the prover knows, and the simulated transcripts of the others with their challenges, None
+// at the index of the known proof
+pub struct AnyOfRandomness<P: SigmaProtocol> {
+    randomness: P::Randomness,
+    simulated: Vec<Option<(BigInt, P::Response)>>,
+}
+
+impl<P: SigmaProtocol> AnyOf<P> {
+
+    // c minus the sum of challenges, in [0, challenge space)
+    fn remaining<'a>(&self, c: &BigInt, challenges: impl Iterator<Item = &'a BigInt>) -> BigInt {
+        let space = self.challenge_space();
+        let sum = challenges.fold(BigInt::zero(), |sum, challenge| sum + challenge);
+        ((c - sum) % space + space) % space
+    }
+}
+
+impl<P: SigmaProtocol> SigmaProtocol for AnyOf<P> {
+    // The index of the known proof and its witness
+    type Witness = (usize, P::Witness);
+    type Randomness = AnyOfRandomness<P>;
+    type Commitment = Vec<P::Commitment>;
+    // The challenge and the response of each proof
+    type Response = Vec<(BigInt, P::Response)>;
+
+    fn commit(&self, (index, witness): &Self::Witness) -> (Self::Randomness, Self::Commitment) {
+        let (randomness, commitment) = self.0[*index].commit(witness);
+        let mut commitments = Vec::with_capacity(self.0.len());
+        let mut simulated = Vec::with_capacity(self.0.len());
+        for (i, proof) in self.0.iter().enumerate() {
+            if i == *index {
+                commitments.push(commitment.clone());
+                simulated.push(None);
+            } else {
+                let challenge = random_below(self.challenge_space());
+                let (commitment, response) = proof.simulate(&challenge);
+                commitments.push(commitment);
+                simulated.push(Some((challenge, response)));
+            }
+        }
+        (AnyOfRandomness { randomness, simulated }, commitments)
+    }
+
+    fn challenge_space(&self) -> &BigInt {
+        self.0[0].challenge_space()
+    }
+
+    fn respond(&self, (index, witness): &Self::Witness, randomness: &Self::Randomness, c: &BigInt) -> Self::Response {
+        let challenge = self.remaining(c, randomness.simulated.iter().flatten().map(|(challenge, _)| challenge));
+        let response = self.0[*index].respond(witness, &randomness.randomness, &challenge);
+        let mut known = Some((challenge, response));
+        randomness.simulated
+            .iter()
+            .map(|simulated| simulated.clone().or_else(|| known.take()).expect("The randomness was committed for another ring"))
+            .collect()
+    }
+
+    fn simulate(&self, c: &BigInt) -> (Self::Commitment, Self::Response) {
+        let mut challenges: Vec<BigInt> = self.0.iter().skip(1).map(|_| random_below(self.challenge_space())).collect();
+        challenges.insert(0, self.remaining(c, challenges.iter()));
+        self.0.iter()
+            .zip(challenges)
+            .map(|(proof, challenge)| {
+                let (commitment, response) = proof.simulate(&challenge);
+                (commitment, (challenge, response))
+            })
+            .unzip()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A safe prime p = 2q + 1 small enough for many rounds, with g = 4 and h = 16 of order q
+    fn parameters() -> PublicParameters {
+        PublicParameters {
+            group_id: "test".to_string(),
+            p: BigInt::from(2039),
+            q: BigInt::from(1019),
+            g: BigInt::from(4),
+            h: BigInt::from(16),
+            kdf: String::new(),
+            challenge_bits: 0,
+        }
+    }
+
+    // The check of the Schnorr verifier of the server: g^s * y^c = r
+    fn accepts(params: &PublicParameters, g: &BigInt, y: &BigInt, r: &BigInt, c: &BigInt, s: &BigInt) -> bool {
+        mod_exp(g, s, &params.p) * mod_exp(y, c, &params.p) % &params.p == *r
+    }
+
+    #[test]
+    fn chaum_pedersen_answers_and_simulations_verify() {
+        let params = parameters();
+        for _ in 0..64 {
+            let x = random_below(&params.q);
+            let (y1, y2) = (mod_exp(&params.g, &x, &params.p), mod_exp(&params.h, &x, &params.p));
+            let proof = ChaumPedersen::new(&params, &y1, &y2);
+            let c = random_below(&params.q);
+
+            let (k, (r1, r2)) = proof.commit(&x);
+            let s = proof.respond(&x, &k, &c);
+            assert!(accepts(&params, &params.g, &y1, &r1, &c, &s) && accepts(&params, &params.h, &y2, &r2, &c, &s));
+
+            let ((r1, r2), s) = proof.simulate(&c);
+            assert!(accepts(&params, &params.g, &y1, &r1, &c, &s) && accepts(&params, &params.h, &y2, &r2, &c, &s));
+        }
+    }
+
+    // The challenges of a ring answer add up to c, and every proof of the ring verifies, whichever key is known
+    #[test]
+    fn any_of_answers_verify() {
+        let params = parameters();
+        let keys: Vec<BigInt> = (0..5).map(|_| random_below(&params.q)).collect();
+        let ring: Vec<BigInt> = keys.iter().map(|x| mod_exp(&params.g, x, &params.p)).collect();
+        let proof = AnyOf(ring.iter().map(|y| Schnorr::new(&params, y)).collect());
+        for (index, x) in keys.iter().enumerate() {
+            let c = random_below(&params.q);
+            let witness = (index, x.clone());
+            let (randomness, commitments) = proof.commit(&witness);
+            let answers = proof.respond(&witness, &randomness, &c);
+
+            let sum = answers.iter().fold(BigInt::zero(), |sum, (challenge, _)| sum + challenge);
+            assert_eq!(sum % &params.q, c);
+            for ((y, r), (challenge, s)) in ring.iter().zip(&commitments).zip(&answers) {
+                assert!(challenge < &params.q);
+                assert!(accepts(&params, &params.g, y, r, challenge, s), "key {} of the ring", index);
+            }
+        }
+    }
+}
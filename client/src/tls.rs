@@ -0,0 +1,71 @@
+use std::error::Error;
+use std::fs;
+use std::io;
+use std::sync::{Arc, Mutex};
+use tokio::net::TcpStream;
+use tokio_rustls::rustls::{self, Certificate, ClientConfig, RootCertStore, ServerName};
+use tokio_rustls::TlsConnector;
+use tonic::transport::{Channel, Endpoint, Uri};
+
+use crate::parameters::{SERVER_ADDRESS, TLS_ENABLED, PINNED_TLS_CERTIFICATE_PATH, TLS_SERVER_NAME, CHANNEL_BINDING_LABEL, CHANNEL_BINDING_LENGTH};
+
+// tls-exporter (RFC 9266) of the connection of one channel to the server, None without TLS.
+// The channel reconnects on its own, so it is updated after every handshake of that channel, and of no other.
+#[derive(Clone, Default)]
+pub struct ChannelBinding(Arc<Mutex<Option<Vec<u8>>>>);
+
+impl ChannelBinding {
+    // The channel binding of the current connection of the channel, None without TLS
+    pub fn get(&self) -> Option<Vec<u8>> {
+        self.0.lock().unwrap().clone()
+    }
+}
+
+// Connect to the server, over TLS 1.3 with the pinned certificate as the only trusted root if TLS_ENABLED.
+// Returns the channel with its channel binding.
+pub async fn connect() -> Result<(Channel, ChannelBinding), Box<dyn Error>> {
+    let endpoint = Endpoint::from_shared(format!("http://{}", SERVER_ADDRESS))?;
+    let binding = ChannelBinding::default();
+    if !TLS_ENABLED {
+        return Ok((endpoint.connect().await?, binding));
+    }
+
+    let pem = fs::read(PINNED_TLS_CERTIFICATE_PATH)
+        .map_err(|error| format!("Cannot read the pinned TLS certificate {}: {}", PINNED_TLS_CERTIFICATE_PATH, error))?;
+    let mut roots = RootCertStore::empty();
+    for certificate in rustls_pemfile::certs(&mut pem.as_slice())? {
+        roots.add(&Certificate(certificate))?;
+    }
+
+    let mut config = ClientConfig::builder()
+        .with_safe_default_cipher_suites()
+        .with_safe_default_kx_groups()
+        .with_protocol_versions(&[&rustls::version::TLS13])?
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    config.alpn_protocols = vec![b"h2".to_vec()];
+    let connector = TlsConnector::from(Arc::new(config));
+
+    let slot = binding.0.clone();
+    let channel = endpoint
+        .connect_with_connector(tower::service_fn(move |_: Uri| {
+            let connector = connector.clone();
+            let slot = slot.clone();
+            async move {
+                let stream = TcpStream::connect(SERVER_ADDRESS).await?;
+                let server_name = ServerName::try_from(TLS_SERVER_NAME)
+                    .map_err(|error| io::Error::new(io::ErrorKind::InvalidInput, error))?;
+                let stream = connector.connect(server_name, stream).await?;
+
+                let channel_binding = stream.get_ref().1
+                    .export_keying_material(vec![0; CHANNEL_BINDING_LENGTH], CHANNEL_BINDING_LABEL, None)
+                    .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))?;
+                *slot.lock().unwrap() = Some(channel_binding);
+
+                Ok::<_, io::Error>(stream)
+            }
+        }))
+        .await?;
+
+    Ok((channel, binding))
+}
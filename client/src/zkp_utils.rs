@@ -2,18 +2,54 @@
 
 use std::hash::{Hash, Hasher};
 use std::collections::hash_map::DefaultHasher;
+use std::error::Error;
 use std::io;
 use std::io::Write;
 use colored::Colorize;
 use rpassword::read_password;
 use num_bigint::{BigInt, ToBigInt, RandBigInt, Sign};
 use num_traits::{Zero, One};
-use tonic::Request;
-use crate::parameters::public_params;
-use crate::zkp_auth::{
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::{Request, Status, Streaming};
+use tonic::transport::Channel;
+use sha2::{Digest, Sha256};
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use rand::Rng;
+use zeroize::Zeroizing;
+use crate::parameters::{PublicParameters, ServerKey, HEDGED_NONCE, PINNED_SERVER_KEY_PATH, CHALLENGE_TRANSCRIPT_DOMAIN, CHANNEL_BINDING_DOMAIN, SESSION_KEY_INFO, CONFIRMATION_KEY_INFO, SERVER_FINISHED, KEY_LENGTH, RECOVERY_CODE_ALPHABET, RECOVERY_CODE_GROUPS, PROTOCOL_VERSION, ENCODING, SUPPORTED_KDFS, SUPPORTED_PROOF_MODES, PUZZLE_MAX_ATTEMPTS, RING_TRANSCRIPT_DOMAIN};
+use crate::nonce;
+use crate::puzzle::Puzzle;
+use crate::secret::{wipe, Password, SecretNumber};
+use crate::sigma::{AnyOf, ChaumPedersen, Schnorr, SigmaProtocol};
+use crate::tls::ChannelBinding;
+use crate::zkp_auth::v2::{
+    auth_client::AuthClient,
+    CapabilitiesRequest,
+    CapabilitiesResponse,
     RegisterRequest, 
     AuthenticationChallengeRequest,
-    AuthenticationAnswerRequest};
+    AuthenticationChallengeResponse,
+    AuthenticationAnswerRequest,
+    AuthenticationAnswerResponse,
+    AuthenticateRequest,
+    AuthenticateResponse,
+    authenticate_request::Step as ClientStep,
+    authenticate_response::Step as ServerStep,
+    RingRequest,
+    RingCommitment,
+    RingAnswer,
+    AuthenticateAnonymouslyRequest,
+    AuthenticateAnonymouslyResponse,
+    authenticate_anonymously_request::Step as RingClientStep,
+    authenticate_anonymously_response::Step as RingServerStep,
+    ChangePasswordRequest,
+    UnregisterRequest,
+    AddCredentialRequest,
+    RecoverAccountRequest,
+    RecoveryKey,
+    PublicParametersRequest};
 
 
 // Fast algorithm for modular exponentiation
@@ -28,11 +64,11 @@ pub fn mod_exp(g: &BigInt, x: &BigInt, q: &BigInt) -> BigInt {
 
     let mut base = g % q;
     let mut exp = x.clone();
-    while &exp > &zero {
+    while exp > zero {
         if &exp % &two == one {
             result = result * &base % q;
         }        
-        exp = exp >> 1;
+        exp >>= 1;
         base = &base * &base % q
     }
 
@@ -44,7 +80,8 @@ pub fn random_big_int(from: BigInt, to: BigInt) -> BigInt {
     rand::thread_rng().gen_bigint_range(&from, &to)
 }
 
-pub fn get_user_credentials() -> (String, BigInt) {
+// Read the user, the credential (device) name and the password. Returns the user, the credential and x.
+pub fn get_user_credentials() -> (String, String, SecretNumber) {
     
     let mut username = String::new();
 
@@ -53,70 +90,696 @@ pub fn get_user_credentials() -> (String, BigInt) {
         .read_line(&mut username)
         .expect("Failed to read username");
 
+    let credential_id = get_credential_id();
+    let x = get_password();
+
+    (username, credential_id, x)
+}
+
+// Read the password alone, e.g. for an anonymous login. Returns x.
+pub fn get_password() -> SecretNumber {
+
     println!("Please input password:");
     
     std::io::stdout().flush().unwrap();
-    let password = read_password().unwrap();
+    let password = Password::new(read_password().unwrap());
 
     // Extract the BigInt value of x from the typed password
-    let x = BigInt::from_bytes_le(Sign::Plus, &password.as_bytes());
+    SecretNumber::from_password(&password)
+}
+
+// Generate count recovery codes, numbered from 1. They are wiped when dropped, like the passwords.
+pub fn generate_recovery_codes(count: usize) -> Zeroizing<Vec<String>> {
+    let mut rng = rand::thread_rng();
+    Zeroizing::new((1..=count)
+        .map(|code_id| {
+            let groups: Zeroizing<Vec<String>> = Zeroizing::new((0..RECOVERY_CODE_GROUPS)
+                .map(|_| (0..4)
+                    .map(|_| RECOVERY_CODE_ALPHABET[rng.gen_range(0..RECOVERY_CODE_ALPHABET.len())] as char)
+                    .collect())
+                .collect());
+            let groups = Zeroizing::new(groups.join("-"));
+            format!("{}-{}", code_id, groups.as_str())
+        })
+        .collect())
+}
+
+// The number of a recovery code and its secret x
+pub fn recovery_code_secret(code: &str) -> Result<(u32, SecretNumber), String> {
+    let code = Password::new(code.trim().to_uppercase());
+    let code_id = code
+        .split('-')
+        .next()
+        .and_then(|code_id| code_id.parse::<u32>().ok())
+        .filter(|code_id| *code_id > 0)
+        .ok_or_else(|| "This is not a recovery code".to_string())?;
+    Ok((code_id, SecretNumber::from_password(&code)))
+}
+
+// Read a recovery code
+pub fn get_recovery_code() -> Result<(u32, SecretNumber), String> {
+
+    println!("Please input a recovery code:");
+    std::io::stdout().flush().unwrap();
+    recovery_code_secret(&Password::new(read_password().unwrap()))
+}
+
+// Read a credential (device) name. Empty means the default credential of the server.
+pub fn get_credential_id() -> String {
+
+    let mut credential_id = String::new();
+
+    println!("Please input the credential (device) name, empty for default:");
+    io::stdin()
+        .read_line(&mut credential_id)
+        .expect("Failed to read the credential name");
+
+    credential_id.trim().to_string()
+}
+
+// Read a code of the authenticator app
+pub fn get_totp_code() -> String {
+
+    let mut code = String::new();
+
+    println!("Please input the code of your authenticator app:");
+    io::stdin()
+        .read_line(&mut code)
+        .expect("Failed to read the code");
+
+    code.trim().to_string()
+}
+
+// Ask a question and return true if the answer is yes
+pub fn confirm(question: &str) -> bool {
+
+    let mut answer = String::new();
+
+    println!("{}", question);
+    io::stdin()
+        .read_line(&mut answer)
+        .expect("Failed to read the answer");
+
+    answer.trim() == "yes"
+}
+
+// Read the new password twice and extract the BigInt value of the new x
+pub fn get_new_password() -> Result<SecretNumber, String> {
+
+    println!("Please input the new password:");
+    std::io::stdout().flush().unwrap();
+    let password = Password::new(read_password().unwrap());
+
+    println!("Please input the new password again:");
+    std::io::stdout().flush().unwrap();
+    if *Password::new(read_password().unwrap()) != *password {
+        return Err("The passwords do not match".to_string());
+    }
+
+    Ok(SecretNumber::from_password(&password))
+}
+
+// Encode a number as unsigned big-endian bytes of exactly the byte length of p, left-padded with zeros
+pub fn encode_bytes(value: &BigInt, params: &PublicParameters) -> Vec<u8> {
+    let (_, bytes) = value.to_bytes_be();
+    let mut encoded = vec![0; params.byte_length() - bytes.len()];
+    encoded.extend(bytes);
+    encoded
+}
 
-    println!("Your password = {}", x);
+// Decode a number sent by the server, refusing any length other than the byte length of p
+pub fn decode_bytes(value: &[u8], params: &PublicParameters, name: &str) -> Result<BigInt, String> {
+    if value.len() != params.byte_length() {
+        return Err(format!("{} is {} bytes long instead of {}", name, value.len(), params.byte_length()));
+    }
+    Ok(BigInt::from_bytes_be(Sign::Plus, value))
+}
+
+// Ask the server what it supports, and refuse to continue unless it serves the version of this client
+// with the same encoding, and accepts a KDF and a proof mode this client implements
+pub async fn negotiate_capabilities(client: &mut AuthClient<Channel>) -> Result<CapabilitiesResponse, Box<dyn Error>> {
 
-    (username, x)
+    let capabilities = client
+        .get_capabilities(CapabilitiesRequest {})
+        .await
+        .map_err(|status| format!("The server does not support {}: {}", PROTOCOL_VERSION, status.message()))?
+        .into_inner();
+
+    if !capabilities.versions.iter().any(|version| version.service == PROTOCOL_VERSION && version.encoding == ENCODING) {
+        return Err(format!("The server does not serve {} with the {} encoding", PROTOCOL_VERSION, ENCODING).into());
+    }
+    if !capabilities.kdfs.iter().any(|kdf| SUPPORTED_KDFS.contains(&kdf.as_str())) {
+        return Err(format!("The server supports none of the KDFs {:?}", SUPPORTED_KDFS).into());
+    }
+    if !capabilities.proof_modes.iter().any(|mode| SUPPORTED_PROOF_MODES.contains(&mode.as_str())) {
+        return Err(format!("The server supports none of the proof modes {:?}", SUPPORTED_PROOF_MODES).into());
+    }
+
+    Ok(capabilities)
 }
 
-// Return the RegisterRequest which coontains username, y1, and y2
-pub fn zkp_register(username: &String, x: &BigInt) -> Request<RegisterRequest>{
+// Fetch the public parameters from the server, and refuse them unless they are valid and match the pinned fingerprint.
+// With a user, the server answers with the group of the credential of the user. Otherwise with group_id, or its default group
+// if group_id is empty.
+// With recovery, the server answers with the group of the recovery codes of the user.
+pub async fn fetch_public_parameters(client: &mut AuthClient<Channel>, group_id: &str, user: &str, credential_id: &str, recovery: bool) -> Result<PublicParameters, Box<dyn Error>> {
 
-    let (p, q, g, h) = public_params();
+    let response = client
+        .get_public_parameters(PublicParametersRequest {
+            group_id: String::from(group_id),
+            user: String::from(user),
+            credential_id: String::from(credential_id),
+            recovery,
+        })
+        .await?
+        .into_inner();
 
-    println!("y1 = {}", mod_exp(&g.to_bigint().unwrap(), &x, &p));
-    println!("y2 = {}", mod_exp(&h.to_bigint().unwrap(), &x, &p));
+    // p has no leading zero byte, so its length gives the length of every other number
+    if response.p.first() == Some(&0) {
+        return Err("The server sent p with a leading zero byte".into());
+    }
+    let mut params = PublicParameters {
+        group_id: response.group_id,
+        p: BigInt::from_bytes_be(Sign::Plus, &response.p),
+        q: BigInt::zero(),
+        g: BigInt::zero(),
+        h: BigInt::zero(),
+        kdf: response.kdf,
+        challenge_bits: response.challenge_bits as u64,
+    };
+    params.q = decode_bytes(&response.q, &params, "q")?;
+    params.g = decode_bytes(&response.g, &params, "g")?;
+    params.h = decode_bytes(&response.h, &params, "h")?;
 
-    let y1 = mod_exp(&g.to_bigint().unwrap(), &x, &p).to_str_radix(16);
-    let y2 = mod_exp(&h.to_bigint().unwrap(), &x, &p).to_str_radix(16);
+    params.validate()
+        .map_err(|error| format!("The server sent invalid public parameters: {}", error))?;
+
+    let pinned_fingerprint = params.pinned_fingerprint()
+        .ok_or_else(|| format!("The server uses the group {} which is not pinned. Refusing to continue.", params.group_id))?;
+
+    let fingerprint = params.fingerprint();
+    if fingerprint != pinned_fingerprint {
+        return Err(format!(
+            "The public parameters of the group {} do not match the pinned ones (got fingerprint {}, expected {}). Refusing to continue.",
+            params.group_id,
+            fingerprint,
+            pinned_fingerprint
+        ).into());
+    }
+
+    Ok(params)
+}
+
+// Load the pinned public key of the server. Its group must be pinned too, and y must be in the subgroup of order q.
+pub async fn load_server_key(client: &mut AuthClient<Channel>) -> Result<ServerKey, Box<dyn Error>> {
+
+    let pinned = std::fs::read_to_string(PINNED_SERVER_KEY_PATH)
+        .map_err(|error| format!("Cannot read the pinned server key {}: {}. Copy it from the server.", PINNED_SERVER_KEY_PATH, error))?;
+    let (group_id, y) = pinned
+        .trim()
+        .split_once(':')
+        .ok_or_else(|| format!("The pinned server key {} is not group_id:y", PINNED_SERVER_KEY_PATH))?;
+    let y = BigInt::parse_bytes(y.as_bytes(), 16)
+        .ok_or_else(|| format!("The pinned server key {} is not hexadecimal", PINNED_SERVER_KEY_PATH))?;
+
+    let params = fetch_public_parameters(client, group_id, "", "", false).await?;
+    if y <= One::one() || y >= &params.p - 1 || mod_exp(&y, &params.q, &params.p) != One::one() {
+        return Err(format!("The pinned server key {} is not in the subgroup of order q", PINNED_SERVER_KEY_PATH).into());
+    }
+
+    Ok(ServerKey { params, y })
+}
+
+// The public key y1 = g^x, y2 = h^x of the secret x
+pub fn public_key(params: &PublicParameters, x: &BigInt) -> (BigInt, BigInt) {
+    (mod_exp(&params.g, x, &params.p), mod_exp(&params.h, x, &params.p))
+}
+
+// Return the RegisterRequest which coontains username, y1, and y2, and the public keys of the recovery codes
+pub fn zkp_register(params: &PublicParameters, username: &str, credential_id: &str, x: &SecretNumber, recovery_codes: &[String]) -> Request<RegisterRequest>{
+
+    let (y1, y2) = public_key(params, x.expose());
+
+    println!("y1 = {}", y1);
+    println!("y2 = {}", y2);
 
     tonic::Request::new(
         RegisterRequest {
             user:String::from(username),
-            y1: y1,
-            y2: y2
+            y1: encode_bytes(&y1, params),
+            y2: encode_bytes(&y2, params),
+            group_id: params.group_id.clone(),
+            credential_id: String::from(credential_id),
+            recovery_keys: recovery_codes
+                .iter()
+                .map(|code| {
+                    let (_, x) = recovery_code_secret(code).unwrap();
+                    let (y1, y2) = public_key(params, x.expose());
+                    RecoveryKey {
+                        y1: encode_bytes(&y1, params),
+                        y2: encode_bytes(&y2, params),
+                    }
+                })
+                .collect(),
         },
     )
 }
 
-// Returns a AuthenticationChallengeRequest based on the username and k
-pub fn zkp_authentication_challenge(username: &String, k: &BigInt) -> Request<AuthenticationChallengeRequest> {
+// The secrets of a commitment: k, and a for the share A = g^a of the key exchange if the client asks for one.
+// Both are wiped when the commitment is dropped.
+#[derive(Debug)]
+pub struct Commitment {
+    pub k: SecretNumber,
+    pub dh_secret: Option<SecretNumber>,
+}
+
+impl Commitment {
+
+    // k for the secret x, and a random a if key_exchange. With HEDGED_NONCE, k is derived from x, context (the operation,
+    // the user and the credential) and fresh random bytes, otherwise it is random. Random numbers are in {2, ..., q - 2}.
+    pub fn new(params: &PublicParameters, x: &SecretNumber, context: &[&str], key_exchange: bool) -> Self {
+        let random = || SecretNumber::new(random_big_int(2.to_bigint().unwrap(), &params.q - 2));
+        Commitment {
+            k: if HEDGED_NONCE { nonce::hedged_nonce(params, x, context) } else { random() },
+            dh_secret: key_exchange.then(random),
+        }
+    }
+
+    // The Chaum–Pedersen answer s = k - c * x (mod q) to the challenge c
+    pub fn answer(&self, params: &PublicParameters, x: &SecretNumber, c: &BigInt) -> BigInt {
+        let (y1, y2) = public_key(params, x.expose());
+        ChaumPedersen::new(params, &y1, &y2).respond(x.expose(), &self.k, c)
+    }
+}
+
+// A challenge received from the server, with what the key exchange needs if the client asked for one
+#[derive(Debug)]
+pub struct Challenge {
+    pub auth_id: String,
+    pub c: BigInt,
+    pub key_exchange: Option<KeyExchange>,
+}
+
+#[derive(Debug)]
+pub struct KeyExchange {
+    // Z = B^a
+    secret: SecretNumber,
+    // SHA-256 of the transcript signed by the server
+    transcript_hash: Vec<u8>,
+}
 
-    let (p, q, g, h) = public_params();
+// Returns a AuthenticationChallengeRequest based on the username and k, for the credential or the recovery code (0 for none)
+pub fn zkp_authentication_challenge(params: &PublicParameters, username: &str, credential_id: &str, recovery_code_id: u32, commitment: &Commitment) -> Request<AuthenticationChallengeRequest> {
 
     println!("{}", "Commitment".cyan());
 
-    let r1 = mod_exp(&g.to_bigint().unwrap(), &k, &p);
-    let r2 =  mod_exp(&h.to_bigint().unwrap(), &k, &p);
+    let k = commitment.k.expose();
+    let dh_share = commitment.dh_secret.as_ref().map(|a| mod_exp(&params.g, a.expose(), &params.p));
+
+    let r1 = mod_exp(&params.g, k, &params.p);
+    let r2 = mod_exp(&params.h, k, &params.p);
 
-    println!("Random k = {}", k);
     println!("r1 = {}", &r1);
     println!("r2 = {}", &r2);
-    println!("");
+    println!();
 
     tonic::Request::new(
         AuthenticationChallengeRequest {
             user:String::from(username),
-            r1: r1.to_str_radix(16),
-            r2: r2.to_str_radix(16),
+            r1: encode_bytes(&r1, params),
+            r2: encode_bytes(&r2, params),
+            credential_id: String::from(credential_id),
+            recovery_code_id,
+            dh_share: dh_share.map_or_else(Vec::new, |share| encode_bytes(&share, params)),
+            // Filled in when the server asks for a puzzle
+            puzzle_seed: Vec::new(),
+            puzzle_nonce: 0,
         },
     )
 }
 
+// Commit with k and receive the challenge: the auth_id and c. None if the user is not registered.
+// The commitment is made with the credential, or with the recovery code recovery_code_id if it is not 0.
+// Fails unless the challenge is signed with the pinned key of the server, so that nothing is answered to an impostor.
+// Over TLS, the returned c is already bound to the connection (see channel_bound_challenge).
+#[allow(clippy::too_many_arguments)]
+pub async fn zkp_request_challenge(client: &mut AuthClient<Channel>, channel_binding: &ChannelBinding, server_key: &ServerKey, params: &PublicParameters, username: &str, credential_id: &str, recovery_code_id: u32, commitment: &Commitment) -> Result<Option<Challenge>, Box<dyn Error>> {
+
+    // Send the parameters: user, r1 and r2 to the server. Under load, the server asks for the solution of a puzzle
+    // first: solve it and send the request again.
+    let mut request = zkp_authentication_challenge(params, username, credential_id, recovery_code_id, commitment).into_inner();
+    let mut attempts = 0;
+    let response = loop {
+        match client.create_authentication_challenge(request.clone()).await {
+            Ok(response) => break response.into_inner(),
+            Err(status) => (request.puzzle_seed, request.puzzle_nonce) = solve_puzzle(status, &request.user, &mut attempts)?,
+        }
+    };
+
+    check_challenge(channel_binding, server_key, params, &request, commitment, response)
+}
+
+// Commit with k on an Authenticate stream and receive the challenge, which is then answered on the stream with
+// LoginStream::answer. None if the user is not registered. Same checks as zkp_request_challenge.
+pub async fn zkp_authenticate(client: &mut AuthClient<Channel>, channel_binding: &ChannelBinding, server_key: &ServerKey, params: &PublicParameters, username: &str, credential_id: &str, commitment: &Commitment) -> Result<Option<(Challenge, LoginStream)>, Box<dyn Error>> {
+
+    // Under load, the stream ends with the puzzle to solve, and a new stream is opened with the solution
+    let mut request = zkp_authentication_challenge(params, username, credential_id, 0, commitment).into_inner();
+    let mut attempts = 0;
+    let (stream, response) = loop {
+        let (sender, receiver) = mpsc::channel(2);
+        sender.send(AuthenticateRequest { step: Some(ClientStep::Commitment(request.clone())) }).await?;
+        let opened = client.authenticate(ReceiverStream::new(receiver)).await;
+        let response = match opened {
+            Ok(response) => {
+                let mut responses = response.into_inner();
+                responses.message().await.map(|response| (responses, response))
+            }
+            Err(status) => Err(status),
+        };
+        match response {
+            Ok((responses, Some(AuthenticateResponse { step: Some(ServerStep::Challenge(response)) }))) => {
+                break (LoginStream { sender, responses }, response);
+            }
+            Ok(_) => return Err("The server did not answer the commitment with a challenge. Refusing to continue.".into()),
+            Err(status) => (request.puzzle_seed, request.puzzle_nonce) = solve_puzzle(status, &request.user, &mut attempts)?,
+        }
+    };
+
+    Ok(check_challenge(channel_binding, server_key, params, &request, commitment, response)?.map(|challenge| (challenge, stream)))
+}
+
+// An Authenticate stream waiting for the answer to its challenge
+pub struct LoginStream {
+    sender: mpsc::Sender<AuthenticateRequest>,
+    responses: Streaming<AuthenticateResponse>,
+}
+
+impl LoginStream {
+
+    // Send the answer s and receive the result of the login
+    pub async fn answer(mut self, params: &PublicParameters, s: &BigInt) -> Result<AuthenticationAnswerResponse, Box<dyn Error>> {
+        let answer = zkp_verify_authentication(params, s, "").into_inner();
+        self.sender.send(AuthenticateRequest { step: Some(ClientStep::Answer(answer)) }).await?;
+        match self.responses.message().await? {
+            Some(AuthenticateResponse { step: Some(ServerStep::Result(result)) }) => Ok(result),
+            _ => Err("The server did not answer with the result of the login".into()),
+        }
+    }
+}
+
+// Under load the server refuses a commitment with a puzzle: solve it for user and return the seed and the nonce to send,
+// unless the server asked too often or refused the commitment for another reason
+fn solve_puzzle(status: Status, user: &str, attempts: &mut u32) -> Result<(Vec<u8>, u64), Box<dyn Error>> {
+    let puzzle = match Puzzle::from_status(&status) {
+        Some(puzzle) if *attempts < PUZZLE_MAX_ATTEMPTS => puzzle?,
+        _ => return Err(status.into()),
+    };
+    println!("{}", format!("The server is under load, solving a puzzle of difficulty {}", puzzle.difficulty).yellow());
+    let nonce = puzzle.solve(user);
+    *attempts += 1;
+    Ok((puzzle.seed, nonce))
+}
+
+// The challenge of the response to request, checked against the pinned key of the server
+fn check_challenge(channel_binding: &ChannelBinding, server_key: &ServerKey, params: &PublicParameters, request: &AuthenticationChallengeRequest, commitment: &Commitment, response: AuthenticationChallengeResponse) -> Result<Option<Challenge>, Box<dyn Error>> {
+    let (username, credential_id, recovery_code_id) = (request.user.as_str(), request.credential_id.as_str(), request.recovery_code_id);
+
+    if response.auth_id == "UserNotRegistered" {
+        return Ok(None);
+    }
+
+    let c = decode_bytes(&response.c, params, "c")?;
+    if c >= params.challenge_space() {
+        return Err("The challenge is outside the challenge space of the server. Refusing to continue.".into());
+    }
+
+    // The share of the server, which must be in the subgroup of order q
+    let key_exchange = match &commitment.dh_secret {
+        Some(a) => {
+            let server_share = decode_bytes(&response.dh_share, params, "dh_share")?;
+            if server_share <= One::one() || server_share >= &params.p - 1 || mod_exp(&server_share, &params.q, &params.p) != One::one() {
+                return Err("The share of the server is not in the subgroup of order q. Refusing to continue.".into());
+            }
+            Some((mod_exp(&params.g, a.expose(), &params.p), server_share))
+        }
+        None => None,
+    };
+
+    // The transcript signed by the server: the request as sent, the group, the commitment and the challenge, then
+    // the shares of the key exchange if any, and the channel binding (empty without TLS). Strings are prefixed with their
+    // length as a 4-byte big-endian integer.
+    let (r1, r2) = public_key(params, commitment.k.expose());
+    let mut transcript = CHALLENGE_TRANSCRIPT_DOMAIN.to_vec();
+    for field in [username, credential_id] {
+        transcript.extend((field.len() as u32).to_be_bytes());
+        transcript.extend(field.as_bytes());
+    }
+    transcript.extend(recovery_code_id.to_be_bytes());
+    for field in [params.group_id.as_str(), response.auth_id.as_str()] {
+        transcript.extend((field.len() as u32).to_be_bytes());
+        transcript.extend(field.as_bytes());
+    }
+    for value in [&r1, &r2, &c] {
+        transcript.extend(encode_bytes(value, params));
+    }
+    if let Some((client_share, server_share)) = &key_exchange {
+        transcript.extend(encode_bytes(client_share, params));
+        transcript.extend(encode_bytes(server_share, params));
+    }
+    // The connection the request was sent on, after the call so that a reconnection is taken into account
+    let channel_binding = channel_binding.get();
+    let binding = channel_binding.as_deref().unwrap_or_default();
+    transcript.extend((binding.len() as u32).to_be_bytes());
+    transcript.extend(binding);
+
+    let signature = response.server_signature
+        .ok_or("The server did not sign the challenge. Refusing to continue.")?;
+    let e = decode_bytes(&signature.e, &server_key.params, "e")?;
+    let s = decode_bytes(&signature.s, &server_key.params, "s")?;
+    if !server_key.verify(&transcript, &e, &s) {
+        return Err("The signature of the challenge does not match the pinned server key. Refusing to continue.".into());
+    }
+
+    Ok(Some(Challenge {
+        auth_id: response.auth_id,
+        c: match &channel_binding {
+            Some(channel_binding) => channel_bound_challenge(params, &c, channel_binding),
+            None => c,
+        },
+        key_exchange: key_exchange.zip(commitment.dh_secret.as_ref()).map(|((_, server_share), a)| KeyExchange {
+            secret: SecretNumber::new(mod_exp(&server_share, a.expose(), &params.p)),
+            transcript_hash: Sha256::digest(&transcript).to_vec(),
+        }),
+    }))
+}
+
+// The ring of the key of the credential of the session, for anonymous logins: the group id and the keys, in ascending
+// order. Every member of the ring gets the same ring, so using it tells the server nothing more than the ring.
+pub async fn zkp_ring(client: &mut AuthClient<Channel>, session_id: &str) -> Result<(String, Vec<Vec<u8>>), Box<dyn Error>> {
+    let response = client.get_ring(RingRequest { session_id: session_id.to_string() }).await?.into_inner();
+    Ok((response.group_id, response.ring))
+}
+
+// Prove the knowledge of the x of one of the keys of ring over an AuthenticateAnonymously stream, without telling which:
+// the proof for y1 = g^x is answered, the others are simulated (see AnyOf in sigma.rs). Returns the session, tied to no
+// user, or None if the server refused the proof. Fails unless the challenge is signed with the pinned key of the server.
+pub async fn zkp_authenticate_anonymously(client: &mut AuthClient<Channel>, channel_binding: &ChannelBinding, server_key: &ServerKey, params: &PublicParameters, ring: &[BigInt], x: &SecretNumber) -> Result<Option<String>, Box<dyn Error>> {
+
+    let (y1, _) = public_key(params, x.expose());
+    let index = ring.iter().position(|member| *member == y1).ok_or("The password is not the password of a key of the ring")?;
+    let proof = AnyOf(ring.iter().map(|member| Schnorr::new(params, member)).collect());
+    let mut witness = (index, x.expose().clone());
+    let (randomness, commitments) = proof.commit(&witness);
+
+    // Under load, the stream ends with the puzzle to solve, for an empty user, and a new stream is opened with the solution
+    let mut request = RingCommitment {
+        group_id: params.group_id.clone(),
+        ring: ring.iter().map(|member| encode_bytes(member, params)).collect(),
+        commitments: commitments.iter().map(|r| encode_bytes(r, params)).collect(),
+        // Filled in when the server asks for a puzzle
+        puzzle_seed: Vec::new(),
+        puzzle_nonce: 0,
+    };
+    let mut attempts = 0;
+    let (sender, mut responses, response) = loop {
+        let (sender, receiver) = mpsc::channel(2);
+        sender.send(AuthenticateAnonymouslyRequest { step: Some(RingClientStep::Commitment(request.clone())) }).await?;
+        let opened = client.authenticate_anonymously(ReceiverStream::new(receiver)).await;
+        let response = match opened {
+            Ok(response) => {
+                let mut responses = response.into_inner();
+                responses.message().await.map(|response| (responses, response))
+            }
+            Err(status) => Err(status),
+        };
+        match response {
+            Ok((responses, Some(AuthenticateAnonymouslyResponse { step: Some(RingServerStep::Challenge(response)) }))) => {
+                break (sender, responses, response);
+            }
+            Ok(_) => return Err("The server did not answer the commitments with a challenge. Refusing to continue.".into()),
+            Err(status) => (request.puzzle_seed, request.puzzle_nonce) = solve_puzzle(status, "", &mut attempts)?,
+        }
+    };
+
+    let c = decode_bytes(&response.c, params, "c")?;
+    if c >= params.challenge_space() {
+        return Err("The challenge is outside the challenge space of the server. Refusing to continue.".into());
+    }
+
+    // The transcript signed by the server: the group, the ring prefixed with its size as a 4-byte big-endian integer,
+    // the commitments, c, and the channel binding (empty without TLS)
+    let mut transcript = RING_TRANSCRIPT_DOMAIN.to_vec();
+    transcript.extend((params.group_id.len() as u32).to_be_bytes());
+    transcript.extend(params.group_id.as_bytes());
+    transcript.extend((ring.len() as u32).to_be_bytes());
+    for value in ring.iter().chain(&commitments).chain([&c]) {
+        transcript.extend(encode_bytes(value, params));
+    }
+    let channel_binding = channel_binding.get();
+    let binding = channel_binding.as_deref().unwrap_or_default();
+    transcript.extend((binding.len() as u32).to_be_bytes());
+    transcript.extend(binding);
+
+    let signature = response.server_signature
+        .ok_or("The server did not sign the challenge. Refusing to continue.")?;
+    let e = decode_bytes(&signature.e, &server_key.params, "e")?;
+    let s = decode_bytes(&signature.s, &server_key.params, "s")?;
+    if !server_key.verify(&transcript, &e, &s) {
+        return Err("The signature of the challenge does not match the pinned server key. Refusing to continue.".into());
+    }
+
+    let c = match &channel_binding {
+        Some(channel_binding) => channel_bound_challenge(params, &c, channel_binding),
+        None => c,
+    };
+    let answers = proof.respond(&witness, &randomness, &c);
+    wipe(&mut witness.1);
+
+    let answer = RingAnswer {
+        challenges: answers.iter().map(|(c, _)| encode_bytes(c, params)).collect(),
+        responses: answers.iter().map(|(_, s)| encode_bytes(s, params)).collect(),
+    };
+    sender.send(AuthenticateAnonymouslyRequest { step: Some(RingClientStep::Answer(answer)) }).await?;
+    match responses.message().await? {
+        Some(AuthenticateAnonymouslyResponse { step: Some(RingServerStep::Result(result)) }) => match result.session_id.as_str() {
+            "WrongCredentials" => Ok(None),
+            _ => Ok(Some(result.session_id)),
+        },
+        _ => Err("The server did not answer with the result of the login".into()),
+    }
+}
+
+// The challenge of a commitment sent over TLS: SHA-256(CHANNEL_BINDING_DOMAIN || c || channel binding) mod q, with c as
+// fixed-length big-endian bytes. The answer is only accepted on the connection the commitment was sent on.
+pub fn channel_bound_challenge(params: &PublicParameters, c: &BigInt, channel_binding: &[u8]) -> BigInt {
+    let mut hasher = Sha256::new();
+    hasher.update(CHANNEL_BINDING_DOMAIN);
+    hasher.update(encode_bytes(c, params));
+    hasher.update(channel_binding);
+    BigInt::from_bytes_be(Sign::Plus, &hasher.finalize()) % &params.q
+}
+
+// Derive the session key once the answer s is accepted, and check the key confirmation of the server:
+//   salt = SHA-256(SHA-256(signed transcript) || s), session key = HKDF-SHA256(salt, Z, SESSION_KEY_INFO),
+//   confirmation key = HKDF-SHA256(salt, Z, CONFIRMATION_KEY_INFO), key confirmation = HMAC-SHA256(confirmation key, SERVER_FINISHED)
+// with Z and s as fixed-length big-endian bytes
+pub fn derive_session_key(params: &PublicParameters, key_exchange: &KeyExchange, s: &BigInt, key_confirmation: &[u8]) -> Result<Vec<u8>, String> {
+
+    let mut salt = Sha256::new();
+    salt.update(&key_exchange.transcript_hash);
+    salt.update(encode_bytes(s, params));
+    let salt = salt.finalize();
+
+    let hkdf = Hkdf::<Sha256>::new(Some(&salt), &encode_bytes(key_exchange.secret.expose(), params));
+    let mut session_key = vec![0; KEY_LENGTH];
+    let mut confirmation_key = vec![0; KEY_LENGTH];
+    hkdf.expand(SESSION_KEY_INFO, &mut session_key).expect("32 bytes is a valid HKDF-SHA256 output length");
+    hkdf.expand(CONFIRMATION_KEY_INFO, &mut confirmation_key).expect("32 bytes is a valid HKDF-SHA256 output length");
+
+    // verify_slice compares in constant time
+    let mut mac = Hmac::<Sha256>::new_from_slice(&confirmation_key).expect("HMAC accepts keys of any length");
+    mac.update(SERVER_FINISHED);
+    mac.verify_slice(key_confirmation)
+        .map_err(|_| "The key confirmation of the server does not match. The session key is not shared.".to_string())?;
+
+    Ok(session_key)
+}
+
 // Return AuthenticationAnswerRequest based on s and auth_id
-pub fn zkp_verify_authentication(s: &BigInt, auth_id: &str) -> Request<AuthenticationAnswerRequest> {
+pub fn zkp_verify_authentication(params: &PublicParameters, s: &BigInt, auth_id: &str) -> Request<AuthenticationAnswerRequest> {
 
     tonic::Request::new(
         AuthenticationAnswerRequest {
             auth_id:String::from(auth_id),
-            s: s.to_str_radix(16),
+            s: encode_bytes(s, params),
+        },
+    )
+}
+
+// The challenge to answer for an operation other than a login: SHA-256 of the domain separator of the operation,
+// the challenge c and the values of the operation, each encoded as fixed-length big-endian bytes, reduced mod q.
+// Same computation as the server.
+pub fn bound_challenge(params: &PublicParameters, domain: &[u8], c: &BigInt, values: &[&BigInt]) -> BigInt {
+    let mut hasher = Sha256::new();
+    hasher.update(domain);
+    hasher.update(encode_bytes(c, params));
+    for value in values {
+        hasher.update(encode_bytes(value, params));
+    }
+    BigInt::from_bytes_be(Sign::Plus, &hasher.finalize()) % &params.q
+}
+
+// Return ChangePasswordRequest based on s, auth_id and the new public key
+pub fn zkp_change_password(params: &PublicParameters, s: &BigInt, auth_id: &str, new_y1: &BigInt, new_y2: &BigInt) -> Request<ChangePasswordRequest> {
+
+    tonic::Request::new(
+        ChangePasswordRequest {
+            auth_id: String::from(auth_id),
+            s: encode_bytes(s, params),
+            new_y1: encode_bytes(new_y1, params),
+            new_y2: encode_bytes(new_y2, params),
         },
     )
-}
\ No newline at end of file
+}
+
+// Return UnregisterRequest based on s and auth_id
+pub fn zkp_unregister(params: &PublicParameters, s: &BigInt, auth_id: &str) -> Request<UnregisterRequest> {
+
+    tonic::Request::new(
+        UnregisterRequest {
+            auth_id: String::from(auth_id),
+            s: encode_bytes(s, params),
+        },
+    )
+}
+
+// Return AddCredentialRequest enrolling the credential credential_id with the public key of x, for the user of the session
+pub fn zkp_add_credential(params: &PublicParameters, session_id: &str, credential_id: &str, x: &SecretNumber) -> Request<AddCredentialRequest> {
+
+    let (y1, y2) = public_key(params, x.expose());
+
+    tonic::Request::new(
+        AddCredentialRequest {
+            session_id: String::from(session_id),
+            credential_id: String::from(credential_id),
+            group_id: params.group_id.clone(),
+            y1: encode_bytes(&y1, params),
+            y2: encode_bytes(&y2, params),
+        },
+    )
+}
+
+// Return RecoverAccountRequest based on s, auth_id and the new public key of the credential credential_id
+pub fn zkp_recover_account(params: &PublicParameters, s: &BigInt, auth_id: &str, credential_id: &str, new_y1: &BigInt, new_y2: &BigInt) -> Request<RecoverAccountRequest> {
+
+    tonic::Request::new(
+        RecoverAccountRequest {
+            auth_id: String::from(auth_id),
+            s: encode_bytes(s, params),
+            credential_id: String::from(credential_id),
+            new_y1: encode_bytes(new_y1, params),
+            new_y2: encode_bytes(new_y2, params),
+        },
+    )
+}
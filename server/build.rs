@@ -1,4 +1,14 @@
+use std::env;
+use std::path::PathBuf;
+
 fn main () -> Result<(), Box<dyn std::error::Error>> {
-    tonic_build::compile_protos("../protos/zkp.proto")?;
+    // The file descriptor set is served by the gRPC reflection service
+    let out_dir = PathBuf::from(env::var("OUT_DIR")?);
+    tonic_build::configure()
+        .file_descriptor_set_path(out_dir.join("zkp_descriptor.bin"))
+        .compile(&["../protos/zkp.proto", "../protos/zkp_v1.proto", "../protos/zkp_v2.proto"], &["../protos"])?;
+
+    // The migrations are embedded in the binary by sqlx::migrate!
+    println!("cargo:rerun-if-changed=migrations");
     Ok(())
-}
\ No newline at end of file
+}
@@ -0,0 +1,19 @@
+-- Public keys of the registered users. auth_id is the hexadecimal hash of the username
+create table if not exists register_request (
+    auth_id text primary key,
+    y1 text not null,
+    y2 text not null
+);
+
+-- Commitment (r1, r2) sent by a user who is logging in
+create table if not exists auth_commitment (
+    auth_id text primary key,
+    r1 text not null,
+    r2 text not null
+);
+
+-- Challenge c sent back to the user who is logging in
+create table if not exists auth_challenge (
+    auth_id text primary key,
+    c text not null
+);
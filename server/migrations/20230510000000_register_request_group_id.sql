@@ -0,0 +1,2 @@
+-- Group in which y1 and y2 were computed. The users registered before groups could be chosen used rfc3526-2048
+alter table register_request add column if not exists group_id text not null default 'rfc3526-2048';
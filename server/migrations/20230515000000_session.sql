@@ -0,0 +1,8 @@
+-- Sessions issued after a successful authentication, so that they can be invalidated
+create table if not exists session (
+    session_id text primary key,
+    auth_id text not null,
+    created_at timestamptz not null default now()
+);
+
+create index if not exists session_auth_id on session (auth_id);
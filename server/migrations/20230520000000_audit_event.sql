@@ -0,0 +1,9 @@
+-- Audit trail of the security relevant changes to the accounts
+create table if not exists audit_event (
+    id bigserial primary key,
+    auth_id text not null,
+    event text not null,
+    created_at timestamptz not null default now()
+);
+
+create index if not exists audit_event_auth_id on audit_event (auth_id);
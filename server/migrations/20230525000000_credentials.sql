@@ -0,0 +1,12 @@
+-- A user can enroll several credentials (devices), each with its own public key and group.
+-- The rows of register_request become the credentials of the users, the existing ones are named 'default'.
+alter table register_request add column if not exists credential_id text not null default 'default';
+alter table register_request drop constraint if exists register_request_pkey;
+alter table register_request add primary key (auth_id, credential_id);
+
+-- Credential proven by the pending commitment, and by the session
+alter table auth_commitment add column if not exists credential_id text not null default 'default';
+alter table session add column if not exists credential_id text not null default 'default';
+
+-- Credential concerned by the audit event, empty for the events of the whole account
+alter table audit_event add column if not exists credential_id text not null default '';
@@ -0,0 +1,16 @@
+-- Every stored challenge has its own id, sent to the client as the auth_id, so that two logins of a user in flight (e.g.
+-- on two credentials) do not overwrite each other. A challenge expires at expires_at (seconds since the Unix epoch).
+-- The challenges pending at the upgrade are dropped.
+delete from auth_challenge;
+delete from auth_commitment;
+
+alter table auth_commitment add column if not exists challenge_id text;
+alter table auth_commitment add column if not exists expires_at bigint not null default 0;
+alter table auth_commitment drop constraint if exists auth_commitment_pkey;
+alter table auth_commitment add primary key (challenge_id);
+create index if not exists auth_commitment_auth_id on auth_commitment (auth_id);
+
+alter table auth_challenge add column if not exists challenge_id text;
+alter table auth_challenge drop constraint if exists auth_challenge_pkey;
+alter table auth_challenge add primary key (challenge_id);
+create index if not exists auth_challenge_auth_id on auth_challenge (auth_id);
@@ -0,0 +1,12 @@
+-- Public keys of the one-time recovery codes of the users, numbered from 1. A code is deleted once used.
+create table if not exists recovery_code (
+    auth_id text not null,
+    code_id integer not null,
+    y1 text not null,
+    y2 text not null,
+    group_id text not null,
+    primary key (auth_id, code_id)
+);
+
+-- Recovery code proven by the pending commitment, null for a login with a credential
+alter table auth_commitment add column if not exists recovery_code_id integer;
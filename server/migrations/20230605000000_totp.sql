@@ -0,0 +1,17 @@
+-- TOTP (RFC 6238) second factor of the users. The secret is base32 encoded. It is only required at login once confirmed.
+-- last_step is the time step of the last accepted code, so that a code cannot be used twice.
+create table if not exists totp (
+    auth_id text primary key,
+    secret text not null,
+    confirmed boolean not null default false,
+    last_step bigint not null default -1
+);
+
+-- Logins whose proof was accepted and which wait for the second factor. expires_at is in seconds since the Unix epoch.
+create table if not exists second_factor (
+    token text primary key,
+    auth_id text not null,
+    credential_id text not null,
+    expires_at bigint not null,
+    attempts integer not null default 0
+);
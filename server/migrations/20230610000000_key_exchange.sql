@@ -0,0 +1,7 @@
+-- Optional key exchange of a login: Z and the SHA-256 of the signed challenge transcript, kept until the answer
+alter table auth_commitment add column if not exists dh_secret text;
+alter table auth_commitment add column if not exists dh_transcript_hash bytea;
+
+-- Session key derived by the key exchange, if the client asked for one. It waits with the login for the second factor.
+alter table session add column if not exists session_key bytea;
+alter table second_factor add column if not exists session_key bytea;
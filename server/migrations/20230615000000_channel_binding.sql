@@ -0,0 +1,3 @@
+-- TLS exporter (RFC 9266) of the connection the commitment was received on, null without TLS.
+-- The answer must come on the same connection.
+alter table auth_commitment add column if not exists channel_binding bytea;
@@ -0,0 +1,8 @@
+-- Fingerprints of the commitments (r1, r2) received recently for each user, see COMMITMENT_REUSE_WINDOW.
+-- seen_at is in seconds since the Unix epoch.
+create table if not exists seen_commitment (
+    auth_id text not null,
+    fingerprint bytea not null,
+    seen_at bigint not null,
+    primary key (auth_id, fingerprint)
+);
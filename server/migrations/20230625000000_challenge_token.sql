@@ -0,0 +1,3 @@
+-- Time at which the challenge of a commitment sealed in a challenge token was answered, in seconds since the Unix epoch,
+-- null until then. A token answered a second time is refused.
+alter table seen_commitment add column if not exists answered_at bigint;
@@ -0,0 +1,27 @@
+use colored::Colorize;
+use sqlx::postgres::PgConnection;
+
+use crate::metrics::timed;
+
+// Events recorded in the audit_event table
+pub const PASSWORD_CHANGED: &str = "password_changed";
+pub const UNREGISTERED: &str = "unregistered";
+pub const CREDENTIAL_ADDED: &str = "credential_added";
+pub const CREDENTIAL_REVOKED: &str = "credential_revoked";
+pub const RECOVERED: &str = "recovered";
+pub const TOTP_ENROLLED: &str = "totp_enrolled";
+pub const COMMITMENT_REUSED: &str = "commitment_reused";
+
+// Record an event of the credential credential_id of the account auth_id. It is written with the change it describes,
+// in the same transaction.
+pub async fn record(connection: &mut PgConnection, auth_id: &str, credential_id: &str, event: &str) {
+    timed("insert_audit_event", sqlx::query(
+        "insert into audit_event (auth_id, credential_id, event) values ($1, $2, $3)")
+        .bind(auth_id)
+        .bind(credential_id)
+        .bind(event)
+        .execute(connection))
+        .await
+        .expect("Audit event insertion error");
+    println!("{}", format!("Audit: {} {} {}", event, auth_id, credential_id).yellow());
+}
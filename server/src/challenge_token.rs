@@ -0,0 +1,415 @@
+use chacha20::cipher::{KeyIvInit, StreamCipher};
+use chacha20::ChaCha20;
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use num_bigint::{BigInt, Sign};
+use rand::RngCore;
+use sha2::Sha256;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+use tonic::Status;
+
+use crate::encoding::{decode_hex_bytes, encode_bytes};
+use crate::groups::{self, Group};
+use crate::zkp_utils::write_private;
+
+// Stateless challenges: instead of storing the commitment and the challenge in auth_commitment and auth_challenge, the
+// server seals them in a token sent back as the auth_id, and rebuilds them from the token when the answer comes. Any
+// replica holding the token keys can check the answer.
+//
+// Token: key_id "." hex(nonce || ciphertext || tag), encrypted then MACed with keys derived from the token key key_id:
+//   encryption key = HKDF-SHA256(secret, ENCRYPTION_INFO), MAC key = HKDF-SHA256(secret, MAC_INFO)
+//   ciphertext = ChaCha20(encryption key, nonce) XOR state, nonce random (12 bytes)
+//   tag = HMAC-SHA256(MAC key, TOKEN_DOMAIN || key_id || nonce || ciphertext)
+// The key exchange secret Z is in the state, hence the encryption.
+//
+// The token keys are read from a file with one "key_id:secret" line per key, the secret being 32 bytes in hexadecimal.
+// The first key seals the new tokens, all of them open tokens. To rotate, add a new key on the first line on every
+// replica, and remove the old one once the tokens it sealed have expired.
+
+pub const TOKEN_DOMAIN: &[u8] = b"zkp_auth/challenge-token";
+pub const ENCRYPTION_INFO: &[u8] = b"zkp_auth/challenge-token/encryption";
+pub const MAC_INFO: &[u8] = b"zkp_auth/challenge-token/mac";
+
+const SECRET_LENGTH: usize = 32;
+const NONCE_LENGTH: usize = 12;
+const TAG_LENGTH: usize = 32;
+
+type HmacSha256 = Hmac<Sha256>;
+
+// A token key, never printed
+pub struct TokenKey {
+    id: String,
+    encryption_key: [u8; 32],
+    mac_key: [u8; 32],
+}
+
+impl TokenKey {
+
+    fn new(id: &str, secret: &[u8]) -> TokenKey {
+        let hkdf = Hkdf::<Sha256>::new(None, secret);
+        let mut encryption_key = [0; 32];
+        let mut mac_key = [0; 32];
+        hkdf.expand(ENCRYPTION_INFO, &mut encryption_key).expect("32 bytes is a valid HKDF-SHA256 output length");
+        hkdf.expand(MAC_INFO, &mut mac_key).expect("32 bytes is a valid HKDF-SHA256 output length");
+        TokenKey { id: id.to_string(), encryption_key, mac_key }
+    }
+
+    fn mac(&self, nonce: &[u8], ciphertext: &[u8]) -> HmacSha256 {
+        let mut mac = HmacSha256::new_from_slice(&self.mac_key).expect("HMAC accepts keys of any length");
+        mac.update(TOKEN_DOMAIN);
+        mac.update(self.id.as_bytes());
+        mac.update(nonce);
+        mac.update(ciphertext);
+        mac
+    }
+
+    fn apply_keystream(&self, nonce: &[u8], data: &mut [u8]) {
+        ChaCha20::new(&self.encryption_key.into(), nonce.into()).apply_keystream(data);
+    }
+}
+
+impl fmt::Debug for TokenKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "TokenKey({})", self.id)
+    }
+}
+
+// The token keys of the server, the first one sealing the new tokens
+#[derive(Debug)]
+pub struct TokenKeys {
+    keys: Vec<TokenKey>,
+}
+
+impl TokenKeys {
+
+    // Load the token keys from path, or write a file with a new random key if there is none yet
+    pub fn load_or_generate(path: &Path) -> io::Result<TokenKeys> {
+        let content = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => {
+                let mut id = [0; 4];
+                rand::thread_rng().fill_bytes(&mut id);
+                let mut secret = [0; SECRET_LENGTH];
+                rand::thread_rng().fill_bytes(&mut secret);
+                let content = format!("{}:{}\n", hex(&id), hex(&secret));
+                write_private(path, &content)?;
+                content
+            }
+            Err(error) => return Err(error),
+        };
+
+        let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidData, message.to_string());
+        let mut keys = Vec::new();
+        for line in content.lines().map(str::trim).filter(|line| !line.is_empty()) {
+            let (id, secret) = line.split_once(':').ok_or_else(|| invalid("A token key is not key_id:secret"))?;
+            if id.is_empty() || !id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+                return Err(invalid("A token key id is not made of letters, digits, - and _"));
+            }
+            let secret = decode_hex_bytes(secret, "secret").ok().filter(|secret| secret.len() == SECRET_LENGTH)
+                .ok_or_else(|| invalid("A token key secret is not 32 bytes in hexadecimal"))?;
+            if keys.iter().any(|key: &TokenKey| key.id == id) {
+                return Err(invalid("Two token keys have the same id"));
+            }
+            keys.push(TokenKey::new(id, &secret));
+        }
+        if keys.is_empty() {
+            return Err(invalid("There is no token key"));
+        }
+
+        Ok(TokenKeys { keys })
+    }
+
+    // Id of the key sealing the new tokens
+    pub fn current_id(&self) -> &str {
+        &self.keys[0].id
+    }
+
+    pub fn seal(&self, state: &ChallengeState) -> String {
+        let key = &self.keys[0];
+        let mut nonce = [0; NONCE_LENGTH];
+        rand::thread_rng().fill_bytes(&mut nonce);
+
+        let mut ciphertext = state.encode();
+        key.apply_keystream(&nonce, &mut ciphertext);
+        let tag = key.mac(&nonce, &ciphertext).finalize().into_bytes();
+
+        let mut sealed = nonce.to_vec();
+        sealed.extend(ciphertext);
+        sealed.extend(tag);
+        format!("{}.{}", key.id, hex(&sealed))
+    }
+
+    // The state sealed in token. None if token is not a token at all, e.g. the auth_id of a challenge stored in the
+    // database. Fails if the token was sealed with an unknown key, was modified, or has expired at now.
+    pub fn open(&self, token: &str, now: u64) -> Option<Result<ChallengeState, Status>> {
+        let (id, sealed) = token.split_once('.')?;
+        Some(self.open_sealed(id, sealed, now))
+    }
+
+    fn open_sealed(&self, id: &str, sealed: &str, now: u64) -> Result<ChallengeState, Status> {
+        let invalid = || Status::unauthenticated("Invalid challenge token");
+        let key = self.keys.iter().find(|key| key.id == id)
+            .ok_or_else(|| Status::unauthenticated("The challenge token was sealed with an unknown key"))?;
+        let sealed = decode_hex_bytes(sealed, "auth_id").ok().filter(|sealed| sealed.len() >= NONCE_LENGTH + TAG_LENGTH).ok_or_else(invalid)?;
+
+        let (nonce, rest) = sealed.split_at(NONCE_LENGTH);
+        let (ciphertext, tag) = rest.split_at(rest.len() - TAG_LENGTH);
+        key.mac(nonce, ciphertext).verify_slice(tag).map_err(|_| invalid())?;
+
+        let mut state = ciphertext.to_vec();
+        key.apply_keystream(nonce, &mut state);
+        let state = ChallengeState::decode(&state).ok_or_else(invalid)?;
+        if state.expires_at <= now {
+            return Err(Status::deadline_exceeded("The challenge has expired"));
+        }
+        Ok(state)
+    }
+}
+
+// What the server keeps between the challenge and the answer, in the token: the request as sent, the commitment, the
+// challenge, the key exchange and the connection, until expires_at (seconds since the Unix epoch)
+pub struct ChallengeState {
+    pub user: String,
+    pub credential_id: String,
+    pub recovery_code_id: u32,
+    pub group: &'static Group,
+    pub r1: BigInt,
+    pub r2: BigInt,
+    pub c: BigInt,
+    // Shares A and B and secret Z of the key exchange, if the client asked for one
+    pub key_exchange: Option<(BigInt, BigInt, BigInt)>,
+    pub channel_binding: Option<Vec<u8>>,
+    pub expires_at: u64,
+}
+
+// The secret Z of the key exchange is never printed
+impl fmt::Debug for ChallengeState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ChallengeState")
+            .field("user", &self.user)
+            .field("credential_id", &self.credential_id)
+            .field("recovery_code_id", &self.recovery_code_id)
+            .field("group", &self.group.id)
+            .field("r1", &self.r1)
+            .field("r2", &self.r2)
+            .field("c", &self.c)
+            .field("key_exchange", &self.key_exchange.as_ref().map(|(a, b, _)| (a, b, format_args!("<redacted>"))))
+            .field("channel_binding", &self.channel_binding)
+            .field("expires_at", &self.expires_at)
+            .finish()
+    }
+}
+
+impl ChallengeState {
+
+    // Strings and byte strings are prefixed with their length as a 4-byte big-endian integer, numbers are fixed-length
+    // big-endian bytes of the group, the key exchange and the channel binding are prefixed with a presence byte
+    fn encode(&self) -> Vec<u8> {
+        let mut encoded = Vec::new();
+        for field in [self.user.as_bytes(), self.credential_id.as_bytes(), self.group.id.as_bytes()] {
+            encoded.extend((field.len() as u32).to_be_bytes());
+            encoded.extend(field);
+        }
+        encoded.extend(self.recovery_code_id.to_be_bytes());
+        for value in [&self.r1, &self.r2, &self.c] {
+            encoded.extend(encode_bytes(value, self.group));
+        }
+        match &self.key_exchange {
+            Some((client_share, server_share, secret)) => {
+                encoded.push(1);
+                for value in [client_share, server_share, secret] {
+                    encoded.extend(encode_bytes(value, self.group));
+                }
+            }
+            None => encoded.push(0),
+        }
+        match &self.channel_binding {
+            Some(channel_binding) => {
+                encoded.push(1);
+                encoded.extend((channel_binding.len() as u32).to_be_bytes());
+                encoded.extend(channel_binding);
+            }
+            None => encoded.push(0),
+        }
+        encoded.extend(self.expires_at.to_be_bytes());
+        encoded
+    }
+
+    fn decode(encoded: &[u8]) -> Option<ChallengeState> {
+        let mut reader = Reader(encoded);
+        let user = String::from_utf8(reader.prefixed()?.to_vec()).ok()?;
+        let credential_id = String::from_utf8(reader.prefixed()?.to_vec()).ok()?;
+        let group = groups::find(std::str::from_utf8(reader.prefixed()?).ok()?)?;
+        let recovery_code_id = u32::from_be_bytes(reader.take(4)?.try_into().ok()?);
+        let length = encode_bytes(&BigInt::from(0), group).len();
+        let mut number = || Some(BigInt::from_bytes_be(Sign::Plus, reader.take(length)?));
+        let (r1, r2, c) = (number()?, number()?, number()?);
+        let key_exchange = match reader.take(1)?[0] {
+            0 => None,
+            _ => {
+                let mut number = || Some(BigInt::from_bytes_be(Sign::Plus, reader.take(length)?));
+                Some((number()?, number()?, number()?))
+            }
+        };
+        let channel_binding = match reader.take(1)?[0] {
+            0 => None,
+            _ => Some(reader.prefixed()?.to_vec()),
+        };
+        let expires_at = u64::from_be_bytes(reader.take(8)?.try_into().ok()?);
+        if !reader.0.is_empty() {
+            return None;
+        }
+
+        Some(ChallengeState { user, credential_id, recovery_code_id, group, r1, r2, c, key_exchange, channel_binding, expires_at })
+    }
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+
+    fn take(&mut self, length: usize) -> Option<&'a [u8]> {
+        if self.0.len() < length {
+            return None;
+        }
+        let (taken, rest) = self.0.split_at(length);
+        self.0 = rest;
+        Some(taken)
+    }
+
+    fn prefixed(&mut self) -> Option<&'a [u8]> {
+        let length = u32::from_be_bytes(self.take(4)?.try_into().ok()?);
+        self.take(length as usize)
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parameters::DEFAULT_GROUP_ID;
+    use tonic::Code;
+
+    const NOW: u64 = 1_700_000_000;
+
+    // Token keys with fixed secrets, the first one sealing
+    fn keys(ids: &[(&str, u8)]) -> TokenKeys {
+        TokenKeys { keys: ids.iter().map(|(id, secret)| TokenKey::new(id, &[*secret; SECRET_LENGTH])).collect() }
+    }
+
+    fn state(key_exchange: bool, channel_binding: bool) -> ChallengeState {
+        ChallengeState {
+            user: "alice".to_string(),
+            credential_id: "laptop".to_string(),
+            recovery_code_id: 3,
+            group: groups::find(DEFAULT_GROUP_ID).unwrap(),
+            r1: BigInt::from(11),
+            r2: BigInt::from(121),
+            c: BigInt::from(7),
+            key_exchange: key_exchange.then(|| (BigInt::from(13), BigInt::from(17), BigInt::from(0x5ec2e7_u32))),
+            channel_binding: channel_binding.then(|| vec![0xcb; 32]),
+            expires_at: NOW + 60,
+        }
+    }
+
+    fn assert_same(opened: &ChallengeState, sealed: &ChallengeState) {
+        assert_eq!(opened.user, sealed.user);
+        assert_eq!(opened.credential_id, sealed.credential_id);
+        assert_eq!(opened.recovery_code_id, sealed.recovery_code_id);
+        assert_eq!(opened.group.id, sealed.group.id);
+        assert_eq!((&opened.r1, &opened.r2, &opened.c), (&sealed.r1, &sealed.r2, &sealed.c));
+        assert_eq!(opened.key_exchange, sealed.key_exchange);
+        assert_eq!(opened.channel_binding, sealed.channel_binding);
+        assert_eq!(opened.expires_at, sealed.expires_at);
+    }
+
+    fn open_error(keys: &TokenKeys, token: &str, now: u64) -> Code {
+        keys.open(token, now).expect("a token").expect_err("the token opened").code()
+    }
+
+    #[test]
+    fn sealed_states_open() {
+        let keys = keys(&[("current", 1)]);
+        for (key_exchange, channel_binding) in [(false, false), (true, false), (false, true), (true, true)] {
+            let sealed = state(key_exchange, channel_binding);
+            let token = keys.seal(&sealed);
+            assert!(token.starts_with("current."));
+            assert_same(&keys.open(&token, NOW).unwrap().unwrap(), &sealed);
+        }
+    }
+
+    #[test]
+    fn database_ids_are_not_tokens() {
+        assert!(keys(&[("current", 1)]).open("0123456789abcdef", NOW).is_none());
+    }
+
+    // A flipped byte in the nonce, the ciphertext or the tag
+    #[test]
+    fn modified_tokens_are_refused() {
+        let keys = keys(&[("current", 1)]);
+        let token = keys.seal(&state(true, true));
+        let (id, sealed) = token.split_once('.').unwrap();
+        let sealed = decode_hex_bytes(sealed, "token").unwrap();
+        for index in [0, NONCE_LENGTH - 1, NONCE_LENGTH, sealed.len() - TAG_LENGTH - 1, sealed.len() - TAG_LENGTH, sealed.len() - 1] {
+            let mut modified = sealed.clone();
+            modified[index] ^= 0x01;
+            assert_eq!(open_error(&keys, &format!("{}.{}", id, hex(&modified)), NOW), Code::Unauthenticated, "byte {}", index);
+        }
+        assert_eq!(open_error(&keys, &format!("{}.{}", id, hex(&sealed[..sealed.len() - 1])), NOW), Code::Unauthenticated);
+    }
+
+    #[test]
+    fn unknown_keys_are_refused() {
+        let token = keys(&[("other", 1)]).seal(&state(false, false));
+        assert_eq!(open_error(&keys(&[("current", 1)]), &token, NOW), Code::Unauthenticated);
+        // The same secret under another id does not open it either
+        let (_, sealed) = token.split_once('.').unwrap();
+        assert_eq!(open_error(&keys(&[("current", 1)]), &format!("current.{}", sealed), NOW), Code::Unauthenticated);
+    }
+
+    #[test]
+    fn expired_tokens_are_refused() {
+        let keys = keys(&[("current", 1)]);
+        let sealed = state(false, false);
+        let token = keys.seal(&sealed);
+        assert!(keys.open(&token, sealed.expires_at - 1).unwrap().is_ok());
+        assert_eq!(open_error(&keys, &token, sealed.expires_at), Code::DeadlineExceeded);
+        assert_eq!(open_error(&keys, &token, sealed.expires_at + 1), Code::DeadlineExceeded);
+    }
+
+    // The new key goes on the first line, the old one stays until its tokens have expired
+    #[test]
+    fn tokens_of_the_old_key_open_after_a_rotation() {
+        let sealed = state(true, false);
+        let token = keys(&[("old", 1)]).seal(&sealed);
+
+        let rotated = keys(&[("new", 2), ("old", 1)]);
+        assert_eq!(rotated.current_id(), "new");
+        assert_same(&rotated.open(&token, NOW).unwrap().unwrap(), &sealed);
+        assert!(rotated.seal(&sealed).starts_with("new."));
+        assert_eq!(open_error(&keys(&[("new", 2)]), &token, NOW), Code::Unauthenticated);
+    }
+
+    #[test]
+    fn trailing_bytes_are_refused() {
+        let encoded = state(true, true).encode();
+        assert!(ChallengeState::decode(&encoded).is_some());
+        assert!(ChallengeState::decode(&[encoded.as_slice(), &[0]].concat()).is_none());
+        assert!(ChallengeState::decode(&encoded[..encoded.len() - 1]).is_none());
+    }
+
+    #[test]
+    fn debug_output_hides_the_secret() {
+        let state = state(true, false);
+        let printed = format!("{:?}", state);
+        let (_, _, secret) = state.key_exchange.as_ref().unwrap();
+        assert!(!printed.contains(&secret.to_string()), "{}", printed);
+        assert!(printed.contains("<redacted>"), "{}", printed);
+    }
+}
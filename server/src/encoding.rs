@@ -0,0 +1,130 @@
+use num::Num;
+use num_bigint::{BigInt, Sign};
+use num_traits::{One, Zero};
+use tonic::Status;
+
+use crate::groups::Group;
+use crate::zkp_utils::mod_exp;
+
+// Encoding of the numbers on the wire
+// zkp_auth and zkp_auth.v1: hexadecimal string, as produced by to_str_radix(16)
+// zkp_auth.v2: unsigned big-endian bytes of exactly the byte length of p of the group, left-padded with zeros.
+//              Every number has a single valid encoding, any other length is rejected.
+
+// Names of the encodings reported by GetCapabilities
+pub const HEX_ENCODING: &str = "hex";
+pub const BYTES_ENCODING: &str = "bytes-be-fixed";
+
+// Number of bytes of every number encoded in v2 for the group
+pub fn byte_length(group: &Group) -> usize {
+    group.p.bits().div_ceil(8) as usize
+}
+
+pub fn encode_hex(value: &BigInt) -> String {
+    value.to_str_radix(16)
+}
+
+pub fn encode_bytes(value: &BigInt, group: &Group) -> Vec<u8> {
+    let (_, bytes) = value.to_bytes_be();
+    let mut encoded = vec![0; byte_length(group) - bytes.len()];
+    encoded.extend(bytes);
+    encoded
+}
+
+// Bytes received as a hexadecimal string, e.g. the seed of a puzzle
+pub fn decode_hex_bytes(value: &str, name: &str) -> Result<Vec<u8>, Status> {
+    if !value.len().is_multiple_of(2) || !value.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(Status::invalid_argument(format!("{} is not a hexadecimal string", name)));
+    }
+    Ok((0..value.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&value[i..i + 2], 16).expect("Checked hexadecimal digits"))
+        .collect())
+}
+
+// A number received in a request. It is decoded once the group it belongs to is known,
+// since the v2 encoding depends on the size of the group.
+#[derive(Debug, Clone, Copy)]
+pub enum WireNumber<'a> {
+    Hex(&'a str),
+    Bytes(&'a [u8]),
+}
+
+impl WireNumber<'_> {
+
+    // Decode a number of any size. name is the field name used in the error message.
+    pub fn decode(&self, name: &str, group: &Group) -> Result<BigInt, Status> {
+        match self {
+            WireNumber::Hex(value) => {
+                // from_str_radix would also accept a sign
+                if value.is_empty() || !value.chars().all(|c| c.is_ascii_hexdigit()) {
+                    return Err(Status::invalid_argument(format!("{} is not a hexadecimal number", name)));
+                }
+                Num::from_str_radix(value, 16)
+                    .map_err(|_| Status::invalid_argument(format!("{} is not a hexadecimal number", name)))
+            }
+            WireNumber::Bytes(value) => {
+                if value.len() != byte_length(group) {
+                    return Err(Status::invalid_argument(format!(
+                        "{} must be {} bytes long for the group {}, got {}",
+                        name,
+                        byte_length(group),
+                        group.id,
+                        value.len()
+                    )));
+                }
+                Ok(BigInt::from_bytes_be(Sign::Plus, value))
+            }
+        }
+    }
+
+    // Decode an element of the group: y1, y2, r1, r2 in [1, p - 1] and in the subgroup of order q (value^q = 1 mod p).
+    // p = 2q + 1 in every group, so the only other elements are those of order 2 or 2q, e.g. p - 1, which would leak
+    // the parity of the exponents they are raised to.
+    pub fn decode_element(&self, name: &str, group: &Group) -> Result<BigInt, Status> {
+        let value = self.decode_nonzero(name, group)?;
+        if !mod_exp(&value, &group.q, &group.p).is_one() {
+            return Err(Status::invalid_argument(format!("{} is not in the subgroup of order q", name)));
+        }
+        Ok(value)
+    }
+
+    // Decode a number in [1, p - 1], without the subgroup check of decode_element. Only for a value that is then
+    // compared to the keys registered in the group, which were checked when registered.
+    pub fn decode_nonzero(&self, name: &str, group: &Group) -> Result<BigInt, Status> {
+        let value = self.decode(name, group)?;
+        if value < One::one() || value >= group.p {
+            return Err(Status::invalid_argument(format!("{} is not in [1, p - 1]", name)));
+        }
+        Ok(value)
+    }
+
+    // Decode an exponent: s in [0, q - 1]
+    pub fn decode_exponent(&self, name: &str, group: &Group) -> Result<BigInt, Status> {
+        let value = self.decode(name, group)?;
+        if value < Zero::zero() || value >= group.q {
+            return Err(Status::invalid_argument(format!("{} is not in [0, q - 1]", name)));
+        }
+        Ok(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::groups;
+
+    #[test]
+    fn elements_must_be_in_the_subgroup() {
+        for group in groups::groups() {
+            let element = |value: &BigInt| WireNumber::Bytes(&encode_bytes(value, group)).decode_element("y1", group);
+
+            assert!(element(&One::one()).is_ok(), "{}", group.id);
+            assert!(element(&group.g).is_ok(), "{}", group.id);
+            assert!(element(&group.h).is_ok(), "{}", group.id);
+            assert!(element(&(&group.p - &group.g)).is_err(), "{}", group.id);
+            assert!(element(&(&group.p - 1)).is_err(), "{}", group.id);
+            assert!(element(&Zero::zero()).is_err(), "{}", group.id);
+        }
+    }
+}
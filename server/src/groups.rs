@@ -0,0 +1,322 @@
+use lazy_static::lazy_static;
+use num::{Integer, Num};
+use num_bigint::{BigInt, ToBigInt};
+use num_traits::{One, ToPrimitive, Zero};
+
+// Registry of the groups a user can register with.
+// Every p below is a safe prime p = 2q + 1. g = 2 and h = 4 are quadratic residues modulo p
+// (p = 7 mod 8 for all of them), so both generate the subgroup of prime order q.
+// h = g^2, so log_g(h) = 2 is public. The login does not need more: Chaum–Pedersen stays sound whatever the relation
+// between g and h. These groups must not be used for a proof whose soundness rests on log_g(h) being unknown, such as
+// Okamoto or a Pedersen commitment; an attacker knowing log_g(h) can open those to anything. verify refuses any other h,
+// so that a group with an independent h cannot be added without going through this again.
+
+// Group of the clients which do not send a group id (it was the only group before the registry existed)
+pub const LEGACY_GROUP_ID: &str = "rfc3526-2048";
+
+#[derive(Debug)]
+pub struct Group {
+    pub id: &'static str,
+    pub p: BigInt,
+    pub q: BigInt,
+    pub g: BigInt,
+    pub h: BigInt,
+}
+
+// 2048-bit MODP Group, RFC 3526 section 3
+const RFC3526_2048_P: &str = "\
+    FFFFFFFFFFFFFFFFC90FDAA22168C234C4C6628B80DC1CD129024E088A67CC74\
+    020BBEA63B139B22514A08798E3404DDEF9519B3CD3A431B302B0A6DF25F1437\
+    4FE1356D6D51C245E485B576625E7EC6F44C42E9A637ED6B0BFF5CB6F406B7ED\
+    EE386BFB5A899FA5AE9F24117C4B1FE649286651ECE45B3DC2007CB8A163BF05\
+    98DA48361C55D39A69163FA8FD24CF5F83655D23DCA3AD961C62F356208552BB\
+    9ED529077096966D670C354E4ABC9804F1746C08CA18217C32905E462E36CE3B\
+    E39E772C180E86039B2783A2EC07A28FB5C55DF06F4C52C9DE2BCBF695581718\
+    3995497CEA956AE515D2261898FA051015728E5A8AACAA68FFFFFFFFFFFFFFFF";
+
+// 3072-bit MODP Group, RFC 3526 section 4
+const RFC3526_3072_P: &str = "\
+    FFFFFFFFFFFFFFFFC90FDAA22168C234C4C6628B80DC1CD129024E088A67CC74\
+    020BBEA63B139B22514A08798E3404DDEF9519B3CD3A431B302B0A6DF25F1437\
+    4FE1356D6D51C245E485B576625E7EC6F44C42E9A637ED6B0BFF5CB6F406B7ED\
+    EE386BFB5A899FA5AE9F24117C4B1FE649286651ECE45B3DC2007CB8A163BF05\
+    98DA48361C55D39A69163FA8FD24CF5F83655D23DCA3AD961C62F356208552BB\
+    9ED529077096966D670C354E4ABC9804F1746C08CA18217C32905E462E36CE3B\
+    E39E772C180E86039B2783A2EC07A28FB5C55DF06F4C52C9DE2BCBF695581718\
+    3995497CEA956AE515D2261898FA051015728E5A8AAAC42DAD33170D04507A33\
+    A85521ABDF1CBA64ECFB850458DBEF0A8AEA71575D060C7DB3970F85A6E1E4C7\
+    ABF5AE8CDB0933D71E8C94E04A25619DCEE3D2261AD2EE6BF12FFA06D98A0864\
+    D87602733EC86A64521F2B18177B200CBBE117577A615D6C770988C0BAD946E2\
+    08E24FA074E5AB3143DB5BFCE0FD108E4B82D120A93AD2CAFFFFFFFFFFFFFFFF";
+
+// 4096-bit MODP Group, RFC 3526 section 5
+const RFC3526_4096_P: &str = "\
+    FFFFFFFFFFFFFFFFC90FDAA22168C234C4C6628B80DC1CD129024E088A67CC74\
+    020BBEA63B139B22514A08798E3404DDEF9519B3CD3A431B302B0A6DF25F1437\
+    4FE1356D6D51C245E485B576625E7EC6F44C42E9A637ED6B0BFF5CB6F406B7ED\
+    EE386BFB5A899FA5AE9F24117C4B1FE649286651ECE45B3DC2007CB8A163BF05\
+    98DA48361C55D39A69163FA8FD24CF5F83655D23DCA3AD961C62F356208552BB\
+    9ED529077096966D670C354E4ABC9804F1746C08CA18217C32905E462E36CE3B\
+    E39E772C180E86039B2783A2EC07A28FB5C55DF06F4C52C9DE2BCBF695581718\
+    3995497CEA956AE515D2261898FA051015728E5A8AAAC42DAD33170D04507A33\
+    A85521ABDF1CBA64ECFB850458DBEF0A8AEA71575D060C7DB3970F85A6E1E4C7\
+    ABF5AE8CDB0933D71E8C94E04A25619DCEE3D2261AD2EE6BF12FFA06D98A0864\
+    D87602733EC86A64521F2B18177B200CBBE117577A615D6C770988C0BAD946E2\
+    08E24FA074E5AB3143DB5BFCE0FD108E4B82D120A92108011A723C12A787E6D7\
+    88719A10BDBA5B2699C327186AF4E23C1A946834B6150BDA2583E9CA2AD44CE8\
+    DBBBC2DB04DE8EF92E8EFC141FBECAA6287C59474E6BC05D99B2964FA090C3A2\
+    233BA186515BE7ED1F612970CEE2D7AFB81BDD762170481CD0069127D5B05AA9\
+    93B4EA988D8FDDC186FFB7DC90A6C08F4DF435C934063199FFFFFFFFFFFFFFFF";
+
+// ffdhe2048, RFC 7919 appendix A.1
+const FFDHE2048_P: &str = "\
+    FFFFFFFFFFFFFFFFADF85458A2BB4A9AAFDC5620273D3CF1D8B9C583CE2D3695\
+    A9E13641146433FBCC939DCE249B3EF97D2FE363630C75D8F681B202AEC4617A\
+    D3DF1ED5D5FD65612433F51F5F066ED0856365553DED1AF3B557135E7F57C935\
+    984F0C70E0E68B77E2A689DAF3EFE8721DF158A136ADE73530ACCA4F483A797A\
+    BC0AB182B324FB61D108A94BB2C8E3FBB96ADAB760D7F4681D4F42A3DE394DF4\
+    AE56EDE76372BB190B07A7C8EE0A6D709E02FCE1CDF7E2ECC03404CD28342F61\
+    9172FE9CE98583FF8E4F1232EEF28183C3FE3B1B4C6FAD733BB5FCBC2EC22005\
+    C58EF1837D1683B2C6F34A26C1B2EFFA886B423861285C97FFFFFFFFFFFFFFFF";
+
+// ffdhe3072, RFC 7919 appendix A.2
+const FFDHE3072_P: &str = "\
+    FFFFFFFFFFFFFFFFADF85458A2BB4A9AAFDC5620273D3CF1D8B9C583CE2D3695\
+    A9E13641146433FBCC939DCE249B3EF97D2FE363630C75D8F681B202AEC4617A\
+    D3DF1ED5D5FD65612433F51F5F066ED0856365553DED1AF3B557135E7F57C935\
+    984F0C70E0E68B77E2A689DAF3EFE8721DF158A136ADE73530ACCA4F483A797A\
+    BC0AB182B324FB61D108A94BB2C8E3FBB96ADAB760D7F4681D4F42A3DE394DF4\
+    AE56EDE76372BB190B07A7C8EE0A6D709E02FCE1CDF7E2ECC03404CD28342F61\
+    9172FE9CE98583FF8E4F1232EEF28183C3FE3B1B4C6FAD733BB5FCBC2EC22005\
+    C58EF1837D1683B2C6F34A26C1B2EFFA886B4238611FCFDCDE355B3B6519035B\
+    BC34F4DEF99C023861B46FC9D6E6C9077AD91D2691F7F7EE598CB0FAC186D91C\
+    AEFE130985139270B4130C93BC437944F4FD4452E2D74DD364F2E21E71F54BFF\
+    5CAE82AB9C9DF69EE86D2BC522363A0DABC521979B0DEADA1DBF9A42D5C4484E\
+    0ABCD06BFA53DDEF3C1B20EE3FD59D7C25E41D2B66C62E37FFFFFFFFFFFFFFFF";
+
+// ffdhe4096, RFC 7919 appendix A.3
+const FFDHE4096_P: &str = "\
+    FFFFFFFFFFFFFFFFADF85458A2BB4A9AAFDC5620273D3CF1D8B9C583CE2D3695\
+    A9E13641146433FBCC939DCE249B3EF97D2FE363630C75D8F681B202AEC4617A\
+    D3DF1ED5D5FD65612433F51F5F066ED0856365553DED1AF3B557135E7F57C935\
+    984F0C70E0E68B77E2A689DAF3EFE8721DF158A136ADE73530ACCA4F483A797A\
+    BC0AB182B324FB61D108A94BB2C8E3FBB96ADAB760D7F4681D4F42A3DE394DF4\
+    AE56EDE76372BB190B07A7C8EE0A6D709E02FCE1CDF7E2ECC03404CD28342F61\
+    9172FE9CE98583FF8E4F1232EEF28183C3FE3B1B4C6FAD733BB5FCBC2EC22005\
+    C58EF1837D1683B2C6F34A26C1B2EFFA886B4238611FCFDCDE355B3B6519035B\
+    BC34F4DEF99C023861B46FC9D6E6C9077AD91D2691F7F7EE598CB0FAC186D91C\
+    AEFE130985139270B4130C93BC437944F4FD4452E2D74DD364F2E21E71F54BFF\
+    5CAE82AB9C9DF69EE86D2BC522363A0DABC521979B0DEADA1DBF9A42D5C4484E\
+    0ABCD06BFA53DDEF3C1B20EE3FD59D7C25E41D2B669E1EF16E6F52C3164DF4FB\
+    7930E9E4E58857B6AC7D5F42D69F6D187763CF1D5503400487F55BA57E31CC7A\
+    7135C886EFB4318AED6A1E012D9E6832A907600A918130C46DC778F971AD0038\
+    092999A333CB8B7A1A1DB93D7140003C2A4ECEA9F98D0ACC0A8291CDCEC97DCF\
+    8EC9B55A7F88A46B4DB5A851F44182E1C68A007E5E655F6AFFFFFFFFFFFFFFFF";
+
+// ffdhe6144, RFC 7919 appendix A.4
+const FFDHE6144_P: &str = "\
+    FFFFFFFFFFFFFFFFADF85458A2BB4A9AAFDC5620273D3CF1D8B9C583CE2D3695\
+    A9E13641146433FBCC939DCE249B3EF97D2FE363630C75D8F681B202AEC4617A\
+    D3DF1ED5D5FD65612433F51F5F066ED0856365553DED1AF3B557135E7F57C935\
+    984F0C70E0E68B77E2A689DAF3EFE8721DF158A136ADE73530ACCA4F483A797A\
+    BC0AB182B324FB61D108A94BB2C8E3FBB96ADAB760D7F4681D4F42A3DE394DF4\
+    AE56EDE76372BB190B07A7C8EE0A6D709E02FCE1CDF7E2ECC03404CD28342F61\
+    9172FE9CE98583FF8E4F1232EEF28183C3FE3B1B4C6FAD733BB5FCBC2EC22005\
+    C58EF1837D1683B2C6F34A26C1B2EFFA886B4238611FCFDCDE355B3B6519035B\
+    BC34F4DEF99C023861B46FC9D6E6C9077AD91D2691F7F7EE598CB0FAC186D91C\
+    AEFE130985139270B4130C93BC437944F4FD4452E2D74DD364F2E21E71F54BFF\
+    5CAE82AB9C9DF69EE86D2BC522363A0DABC521979B0DEADA1DBF9A42D5C4484E\
+    0ABCD06BFA53DDEF3C1B20EE3FD59D7C25E41D2B669E1EF16E6F52C3164DF4FB\
+    7930E9E4E58857B6AC7D5F42D69F6D187763CF1D5503400487F55BA57E31CC7A\
+    7135C886EFB4318AED6A1E012D9E6832A907600A918130C46DC778F971AD0038\
+    092999A333CB8B7A1A1DB93D7140003C2A4ECEA9F98D0ACC0A8291CDCEC97DCF\
+    8EC9B55A7F88A46B4DB5A851F44182E1C68A007E5E0DD9020BFD64B645036C7A\
+    4E677D2C38532A3A23BA4442CAF53EA63BB454329B7624C8917BDD64B1C0FD4C\
+    B38E8C334C701C3ACDAD0657FCCFEC719B1F5C3E4E46041F388147FB4CFDB477\
+    A52471F7A9A96910B855322EDB6340D8A00EF092350511E30ABEC1FFF9E3A26E\
+    7FB29F8C183023C3587E38DA0077D9B4763E4E4B94B2BBC194C6651E77CAF992\
+    EEAAC0232A281BF6B3A739C1226116820AE8DB5847A67CBEF9C9091B462D538C\
+    D72B03746AE77F5E62292C311562A846505DC82DB854338AE49F5235C95B9117\
+    8CCF2DD5CACEF403EC9D1810C6272B045B3B71F9DC6B80D63FDD4A8E9ADB1E69\
+    62A69526D43161C1A41D570D7938DAD4A40E329CD0E40E65FFFFFFFFFFFFFFFF";
+
+// ffdhe8192, RFC 7919 appendix A.5
+const FFDHE8192_P: &str = "\
+    FFFFFFFFFFFFFFFFADF85458A2BB4A9AAFDC5620273D3CF1D8B9C583CE2D3695\
+    A9E13641146433FBCC939DCE249B3EF97D2FE363630C75D8F681B202AEC4617A\
+    D3DF1ED5D5FD65612433F51F5F066ED0856365553DED1AF3B557135E7F57C935\
+    984F0C70E0E68B77E2A689DAF3EFE8721DF158A136ADE73530ACCA4F483A797A\
+    BC0AB182B324FB61D108A94BB2C8E3FBB96ADAB760D7F4681D4F42A3DE394DF4\
+    AE56EDE76372BB190B07A7C8EE0A6D709E02FCE1CDF7E2ECC03404CD28342F61\
+    9172FE9CE98583FF8E4F1232EEF28183C3FE3B1B4C6FAD733BB5FCBC2EC22005\
+    C58EF1837D1683B2C6F34A26C1B2EFFA886B4238611FCFDCDE355B3B6519035B\
+    BC34F4DEF99C023861B46FC9D6E6C9077AD91D2691F7F7EE598CB0FAC186D91C\
+    AEFE130985139270B4130C93BC437944F4FD4452E2D74DD364F2E21E71F54BFF\
+    5CAE82AB9C9DF69EE86D2BC522363A0DABC521979B0DEADA1DBF9A42D5C4484E\
+    0ABCD06BFA53DDEF3C1B20EE3FD59D7C25E41D2B669E1EF16E6F52C3164DF4FB\
+    7930E9E4E58857B6AC7D5F42D69F6D187763CF1D5503400487F55BA57E31CC7A\
+    7135C886EFB4318AED6A1E012D9E6832A907600A918130C46DC778F971AD0038\
+    092999A333CB8B7A1A1DB93D7140003C2A4ECEA9F98D0ACC0A8291CDCEC97DCF\
+    8EC9B55A7F88A46B4DB5A851F44182E1C68A007E5E0DD9020BFD64B645036C7A\
+    4E677D2C38532A3A23BA4442CAF53EA63BB454329B7624C8917BDD64B1C0FD4C\
+    B38E8C334C701C3ACDAD0657FCCFEC719B1F5C3E4E46041F388147FB4CFDB477\
+    A52471F7A9A96910B855322EDB6340D8A00EF092350511E30ABEC1FFF9E3A26E\
+    7FB29F8C183023C3587E38DA0077D9B4763E4E4B94B2BBC194C6651E77CAF992\
+    EEAAC0232A281BF6B3A739C1226116820AE8DB5847A67CBEF9C9091B462D538C\
+    D72B03746AE77F5E62292C311562A846505DC82DB854338AE49F5235C95B9117\
+    8CCF2DD5CACEF403EC9D1810C6272B045B3B71F9DC6B80D63FDD4A8E9ADB1E69\
+    62A69526D43161C1A41D570D7938DAD4A40E329CCFF46AAA36AD004CF600C838\
+    1E425A31D951AE64FDB23FCEC9509D43687FEB69EDD1CC5E0B8CC3BDF64B10EF\
+    86B63142A3AB8829555B2F747C932665CB2C0F1CC01BD70229388839D2AF05E4\
+    54504AC78B7582822846C0BA35C35F5C59160CC046FD8251541FC68C9C86B022\
+    BB7099876A460E7451A8A93109703FEE1C217E6C3826E52C51AA691E0E423CFC\
+    99E9E31650C1217B624816CDAD9A95F9D5B8019488D9C0A0A1FE3075A577E231\
+    83F81D4A3F2FA4571EFC8CE0BA8A4FE8B6855DFE72B0A66EDED2FBABFBE58A30\
+    FAFABE1C5D71A87E2F741EF8C1FE86FEA6BBFDE530677F0D97D11D49F7A8443D\
+    0822E506A9F4614E011E2A94838FF88CD68C8BB7C5C6424CFFFFFFFFFFFFFFFF";
+
+fn group(id: &'static str, p_hex: &str) -> Group {
+    let p: BigInt = Num::from_str_radix(p_hex, 16).unwrap();
+    let q: BigInt = (&p - 1) / 2;
+    Group {
+        id,
+        p,
+        q,
+        g: 2.to_bigint().unwrap(),
+        h: 4.to_bigint().unwrap(),
+    }
+}
+
+lazy_static! {
+    static ref GROUPS: Vec<Group> = vec![
+        group("rfc3526-2048", RFC3526_2048_P),
+        group("rfc3526-3072", RFC3526_3072_P),
+        group("rfc3526-4096", RFC3526_4096_P),
+        group("ffdhe2048", FFDHE2048_P),
+        group("ffdhe3072", FFDHE3072_P),
+        group("ffdhe4096", FFDHE4096_P),
+        group("ffdhe6144", FFDHE6144_P),
+        group("ffdhe8192", FFDHE8192_P),
+    ];
+}
+
+// All the registered groups
+pub fn groups() -> &'static [Group] {
+    &GROUPS
+}
+
+// Look up a group by its id
+pub fn find(id: &str) -> Option<&'static Group> {
+    GROUPS.iter().find(|group| group.id == id)
+}
+
+// Jacobi symbol (a/n) for an odd n > 0. When n is prime it is the Legendre symbol: 1 if a is a non-zero
+// quadratic residue modulo n, -1 if it is not a quadratic residue, 0 if n divides a.
+fn jacobi(a: &BigInt, n: &BigInt) -> i32 {
+    let mut a = a.mod_floor(n);
+    let mut n = n.clone();
+    let mut result = 1;
+
+    while !a.is_zero() {
+        while a.is_even() {
+            a >>= 1;
+            let n_mod_8 = (&n % 8u32).to_u32().unwrap();
+            if n_mod_8 == 3 || n_mod_8 == 5 {
+                result = -result;
+            }
+        }
+        std::mem::swap(&mut a, &mut n);
+        if (&a % 4u32).to_u32().unwrap() == 3 && (&n % 4u32).to_u32().unwrap() == 3 {
+            result = -result;
+        }
+        a = a.mod_floor(&n);
+    }
+
+    if n.is_one() { result } else { 0 }
+}
+
+// Check that g and h are distinct generators of the subgroup of order q in every group.
+pub fn verify() -> Result<(), String> {
+    GROUPS.iter().try_for_each(verify_group)
+}
+
+// For a safe prime p, the subgroup of prime order q is the set of quadratic residues modulo p,
+// so any quadratic residue other than 1 generates it. The Jacobi symbol checks this without computing g^q.
+fn verify_group(group: &Group) -> Result<(), String> {
+    let one: BigInt = One::one();
+
+    if group.p != &group.q * 2 + &one {
+        return Err(format!("{}: p is not equal to 2q + 1", group.id));
+    }
+    for (name, generator) in [("g", &group.g), ("h", &group.h)] {
+        if generator <= &one || generator >= &(&group.p - &one) {
+            return Err(format!("{}: {} is not in the range (1, p - 1)", group.id, name));
+        }
+        if jacobi(generator, &group.p) != 1 {
+            return Err(format!("{}: {} does not generate the subgroup of order q", group.id, name));
+        }
+    }
+    if group.g == group.h {
+        return Err(format!("{}: g and h are equal", group.id));
+    }
+    if group.h != (&group.g * &group.g) % &group.p {
+        return Err(format!("{}: h is not g^2, the only relation the proofs are checked for", group.id));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A copy of the group, with one value changed
+    fn tampered(group: &Group, change: impl FnOnce(&mut Group)) -> Group {
+        let mut copy = Group { id: group.id, p: group.p.clone(), q: group.q.clone(), g: group.g.clone(), h: group.h.clone() };
+        change(&mut copy);
+        copy
+    }
+
+    #[test]
+    fn every_group_verifies() {
+        for group in groups() {
+            assert_eq!(verify_group(group), Ok(()), "{}", group.id);
+        }
+        assert_eq!(verify(), Ok(()));
+    }
+
+    // Legendre symbols computed by Euler's criterion a^((n - 1) / 2) mod n for small primes
+    #[test]
+    fn jacobi_matches_euler_criterion() {
+        for n in [3u32, 5, 7, 11, 23, 47, 2039] {
+            let n = BigInt::from(n);
+            for a in 0..60u32 {
+                let a = BigInt::from(a);
+                let euler = a.modpow(&((&n - 1u32) / 2u32), &n);
+                let expected = if euler.is_zero() { 0 } else if euler.is_one() { 1 } else { -1 };
+                assert_eq!(jacobi(&a, &n), expected, "({}/{})", a, n);
+            }
+        }
+    }
+
+    #[test]
+    fn tampered_groups_are_refused() {
+        for group in groups() {
+            // The smallest quadratic non-residue, which generates the whole group of order 2q
+            let non_residue = (3u32..).map(BigInt::from).find(|a| jacobi(a, &group.p) == -1).unwrap();
+            let tampered_groups = [
+                tampered(group, |group| group.q += 1u32),
+                tampered(group, |group| group.p += 2u32),
+                tampered(group, |group| group.g = One::one()),
+                tampered(group, |group| group.h = &group.p - 1u32),
+                tampered(group, |group| group.h = group.p.clone()),
+                tampered(group, |group| group.g = non_residue.clone()),
+                tampered(group, |group| group.h = group.g.clone()),
+                tampered(group, |group| group.h = BigInt::from(9)),
+            ];
+            for (index, tampered) in tampered_groups.iter().enumerate() {
+                assert!(verify_group(tampered).is_err(), "{}: tampered group {} verifies", group.id, index);
+            }
+        }
+    }
+}
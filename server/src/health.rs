@@ -0,0 +1,80 @@
+use colored::Colorize;
+use sqlx::migrate::Migrator;
+use sqlx::postgres::PgPool;
+use sqlx::Row;
+use tonic_health::server::HealthReporter;
+use tonic_health::ServingStatus;
+
+use crate::metrics::timed;
+use crate::parameters::HEALTH_CHECK_INTERVAL;
+
+// Schema migrations embedded from server/migrations
+pub static MIGRATOR: Migrator = sqlx::migrate!();
+
+// Apply the migrations which are not yet in the database
+pub async fn migrate(pool: &PgPool) -> Result<(), sqlx::migrate::MigrateError> {
+    MIGRATOR.run(pool).await
+}
+
+// The server is ready when the database answers and every embedded migration has been applied
+async fn is_ready(pool: &PgPool) -> Result<bool, sqlx::Error> {
+    let applied = timed("select_migrations", sqlx::query(
+        "select version from _sqlx_migrations where success")
+        .fetch_all(pool))
+        .await?
+        .iter()
+        .map(|row| row.get::<i64, usize>(0))
+        .collect::<Vec<i64>>()
+    ;
+
+    Ok(MIGRATOR.iter().all(|migration| applied.contains(&migration.version)))
+}
+
+// Periodically check the readiness and report it for the whole server ("") and for each of the given services.
+// While the server is not ready, the migrations are retried: the database may have been down at startup.
+pub async fn watch(mut reporter: HealthReporter, pool: PgPool, services: Vec<&'static str>) {
+    let mut previous = ServingStatus::Unknown;
+    let mut migration_failed = false;
+
+    loop {
+        if previous == ServingStatus::NotServing {
+            match migrate(&pool).await {
+                Ok(()) => migration_failed = false,
+                Err(error) => {
+                    if !migration_failed {
+                        println!("{}", format!("Database migration failed, retrying every {:?}: {}", HEALTH_CHECK_INTERVAL, error).red());
+                    }
+                    migration_failed = true;
+                }
+            }
+        }
+
+        let status = match is_ready(&pool).await {
+            Ok(true) => ServingStatus::Serving,
+            Ok(false) => {
+                if previous != ServingStatus::NotServing {
+                    println!("{}", "Not ready: database migrations are pending".red());
+                }
+                ServingStatus::NotServing
+            }
+            Err(error) => {
+                if previous != ServingStatus::NotServing {
+                    println!("{}", format!("Not ready: database check failed ({})", error).red());
+                }
+                ServingStatus::NotServing
+            }
+        };
+
+        if status == ServingStatus::Serving && previous != ServingStatus::Serving {
+            println!("{}", "Ready: database reachable and migrations applied".green());
+        }
+
+        reporter.set_service_status("", status).await;
+        for service in &services {
+            reporter.set_service_status(service, status).await;
+        }
+        previous = status;
+
+        tokio::time::sleep(HEALTH_CHECK_INTERVAL).await;
+    }
+}
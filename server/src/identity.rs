@@ -0,0 +1,200 @@
+use num::Num;
+use num_bigint::{BigInt, Sign, ToBigInt};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::encoding::{encode_bytes, encode_hex};
+use crate::groups::{self, Group};
+use crate::parameters::IDENTITY_GROUP_ID;
+use crate::zkp_utils::{mod_exp, random_big_int, write_private};
+
+// Long-term identity of the server, so that clients can tell it from an impostor collecting commitments and answers.
+// The key is a Schnorr key pair in the group IDENTITY_GROUP_ID: x random in [2, q - 2] and y = g^x mod p.
+// The server signs the transcript of every challenge, and clients check the signature against the y they pinned
+// before answering.
+//
+// Signature of a transcript t: k random, R = g^k, e = SHA-256(SIGNATURE_DOMAIN || y || R || t) mod q, s = k - e * x (mod q).
+// The signature is (e, s). It is checked by computing R' = g^s * y^e and comparing SHA-256(SIGNATURE_DOMAIN || y || R' || t) mod q with e.
+// y and R are encoded as fixed-length big-endian bytes of the identity group.
+pub const SIGNATURE_DOMAIN: &[u8] = b"zkp_auth/server-signature";
+
+// Domain separator of the transcript of a challenge
+pub const CHALLENGE_TRANSCRIPT_DOMAIN: &[u8] = b"zkp_auth/challenge";
+
+// Domain separator of the transcript of a ring challenge
+pub const RING_TRANSCRIPT_DOMAIN: &[u8] = b"zkp_auth/ring-challenge";
+
+pub struct ServerKey {
+    x: BigInt,
+    pub y: BigInt,
+    pub group: &'static Group,
+}
+
+#[derive(Debug, Clone)]
+pub struct Signature {
+    pub e: BigInt,
+    pub s: BigInt,
+    // The identity group, whose byte length encodes e and s in v2
+    pub group: &'static Group,
+}
+
+impl ServerKey {
+
+    // Load the private key from path, or generate a new key pair if there is none yet. The public key is written to
+    // public_path in both cases, ready to be pinned by the clients.
+    pub fn load_or_generate(path: &Path, public_path: &Path) -> io::Result<ServerKey> {
+        let group = groups::find(IDENTITY_GROUP_ID)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("Unknown identity group {}", IDENTITY_GROUP_ID)))?;
+
+        let x = match fs::read_to_string(path) {
+            Ok(content) => {
+                let x: BigInt = Num::from_str_radix(content.trim(), 16)
+                    .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "The server key is not a hexadecimal number"))?;
+                if x < 2.to_bigint().unwrap() || x > &group.q - 2 {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, "The server key is not in [2, q - 2]"));
+                }
+                x
+            }
+            Err(error) if error.kind() == io::ErrorKind::NotFound => {
+                let x = random_big_int(2.to_bigint().unwrap(), &group.q - 1);
+                write_private(path, &encode_hex(&x))?;
+                x
+            }
+            Err(error) => return Err(error),
+        };
+
+        let y = mod_exp(&group.g, &x, &group.p);
+        fs::write(public_path, format!("{}:{}\n", group.id, encode_hex(&y)))?;
+
+        Ok(ServerKey { x, y, group })
+    }
+
+    // SHA-256 of "group_id:y" with y in lowercase hexadecimal, to compare with the key pinned by the clients
+    pub fn fingerprint(&self) -> String {
+        Sha256::digest(format!("{}:{}", self.group.id, encode_hex(&self.y)).as_bytes())
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect()
+    }
+
+    // A value only the server can compute: SHA-256(domain || x || message), x as fixed-length big-endian bytes
+    pub fn keyed_hash(&self, domain: &[u8], message: &[u8]) -> [u8; 32] {
+        Sha256::new()
+            .chain_update(domain)
+            .chain_update(encode_bytes(&self.x, self.group))
+            .chain_update(message)
+            .finalize()
+            .into()
+    }
+
+    pub fn sign(&self, transcript: &[u8]) -> Signature {
+        let group = self.group;
+        let k = random_big_int(2.to_bigint().unwrap(), &group.q - 1);
+        let r = mod_exp(&group.g, &k, &group.p);
+        let e = signature_challenge(&self.y, &r, transcript, group);
+        let s = ((&k - &e * &self.x) % &group.q + &group.q) % &group.q;
+        Signature { e, s, group }
+    }
+}
+
+// The private key is never printed
+impl fmt::Debug for ServerKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ServerKey")
+            .field("group", &self.group.id)
+            .field("fingerprint", &self.fingerprint())
+            .finish()
+    }
+}
+
+fn signature_challenge(y: &BigInt, r: &BigInt, transcript: &[u8], group: &Group) -> BigInt {
+    let mut hasher = Sha256::new();
+    hasher.update(SIGNATURE_DOMAIN);
+    hasher.update(encode_bytes(y, group));
+    hasher.update(encode_bytes(r, group));
+    hasher.update(transcript);
+    BigInt::from_bytes_be(Sign::Plus, &hasher.finalize()) % &group.q
+}
+
+// Transcript of a challenge: the request as sent by the client (user, credential and recovery code), the group of the
+// user, the commitment (r1, r2) and the answer of the server (auth_id, c)
+#[derive(Debug)]
+pub struct ChallengeTranscript<'a> {
+    pub user: &'a str,
+    pub credential_id: &'a str,
+    pub recovery_code_id: u32,
+    pub group: &'a Group,
+    pub r1: &'a BigInt,
+    pub r2: &'a BigInt,
+    pub auth_id: &'a str,
+    pub c: &'a BigInt,
+    // Shares (A, B) of the client and of the server if the client asked for a key exchange
+    pub dh_shares: Option<(&'a BigInt, &'a BigInt)>,
+    // TLS exporter of the connection of the request, None without TLS
+    pub channel_binding: Option<&'a [u8]>,
+}
+
+impl ChallengeTranscript<'_> {
+
+    // Strings are prefixed with their length as a 4-byte big-endian integer, numbers are encoded as fixed-length
+    // big-endian bytes of the group of the user. The shares of the key exchange, if any, come next, and the channel
+    // binding last, length-prefixed like a string (empty without TLS).
+    pub fn encode(&self) -> Vec<u8> {
+        let mut transcript = CHALLENGE_TRANSCRIPT_DOMAIN.to_vec();
+        for field in [self.user, self.credential_id] {
+            transcript.extend((field.len() as u32).to_be_bytes());
+            transcript.extend(field.as_bytes());
+        }
+        transcript.extend(self.recovery_code_id.to_be_bytes());
+        for field in [self.group.id, self.auth_id] {
+            transcript.extend((field.len() as u32).to_be_bytes());
+            transcript.extend(field.as_bytes());
+        }
+        for value in [self.r1, self.r2, self.c] {
+            transcript.extend(encode_bytes(value, self.group));
+        }
+        if let Some((client_share, server_share)) = self.dh_shares {
+            transcript.extend(encode_bytes(client_share, self.group));
+            transcript.extend(encode_bytes(server_share, self.group));
+        }
+        let channel_binding = self.channel_binding.unwrap_or_default();
+        transcript.extend((channel_binding.len() as u32).to_be_bytes());
+        transcript.extend(channel_binding);
+        transcript
+    }
+}
+
+// Transcript of a ring challenge of an anonymous login: the group, the ring of public keys, the commitments and the
+// challenge c
+#[derive(Debug)]
+pub struct RingTranscript<'a> {
+    pub group: &'a Group,
+    pub ring: &'a [BigInt],
+    pub commitments: &'a [BigInt],
+    pub c: &'a BigInt,
+    // TLS exporter of the connection of the request, None without TLS
+    pub channel_binding: Option<&'a [u8]>,
+}
+
+impl RingTranscript<'_> {
+
+    // The group id is prefixed with its length, the ring with its size, both as 4-byte big-endian integers. The keys,
+    // then the commitments, then c are encoded as fixed-length big-endian bytes of the group, and the channel binding
+    // comes last, length-prefixed (empty without TLS).
+    pub fn encode(&self) -> Vec<u8> {
+        let mut transcript = RING_TRANSCRIPT_DOMAIN.to_vec();
+        transcript.extend((self.group.id.len() as u32).to_be_bytes());
+        transcript.extend(self.group.id.as_bytes());
+        transcript.extend((self.ring.len() as u32).to_be_bytes());
+        for value in self.ring.iter().chain(self.commitments).chain([self.c]) {
+            transcript.extend(encode_bytes(value, self.group));
+        }
+        let channel_binding = self.channel_binding.unwrap_or_default();
+        transcript.extend((channel_binding.len() as u32).to_be_bytes());
+        transcript.extend(channel_binding);
+        transcript
+    }
+}
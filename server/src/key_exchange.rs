@@ -0,0 +1,118 @@
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use num_bigint::{BigInt, ToBigInt};
+use num_traits::One;
+use sha2::{Digest, Sha256};
+use std::fmt;
+use tonic::Status;
+
+use crate::encoding::encode_bytes;
+use crate::groups::Group;
+use crate::zkp_utils::{mod_exp, random_big_int};
+
+// Optional authenticated key exchange run alongside a login, in the group of the user.
+// The client sends an ephemeral share A = g^a with its commitment, the server answers with B = g^b next to the challenge.
+// Both shares are part of the challenge transcript signed by the server (see identity.rs), so neither can be replaced in transit.
+// Once the answer s is accepted, both sides derive from Z = g^(ab):
+//   salt = SHA-256(SHA-256(signed transcript) || s)
+//   session key = HKDF-SHA256(salt, Z, SESSION_KEY_INFO), 32 bytes
+//   confirmation key = HKDF-SHA256(salt, Z, CONFIRMATION_KEY_INFO), 32 bytes
+// and the server returns the key confirmation HMAC-SHA256(confirmation key, SERVER_FINISHED), which the client checks
+// before using the session key. Z, s and the hashes are encoded as fixed-length big-endian bytes of the group.
+
+// Name of the key exchange reported by GetCapabilities
+pub const KEY_EXCHANGE: &str = "dh-hkdf-sha256";
+
+pub const SESSION_KEY_INFO: &[u8] = b"zkp_auth/session-key";
+pub const CONFIRMATION_KEY_INFO: &[u8] = b"zkp_auth/confirmation-key";
+pub const SERVER_FINISHED: &[u8] = b"zkp_auth/server-finished";
+
+// Length in bytes of the session key and of the confirmation key
+pub const KEY_LENGTH: usize = 32;
+
+// What the server keeps between the challenge and the answer
+pub struct PendingKeyExchange {
+    // Z = A^b
+    pub secret: BigInt,
+    // SHA-256 of the signed challenge transcript
+    pub transcript_hash: Vec<u8>,
+}
+
+pub struct SessionKeys {
+    pub session_key: Vec<u8>,
+    pub key_confirmation: Vec<u8>,
+}
+
+// Z is never printed
+impl fmt::Debug for PendingKeyExchange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PendingKeyExchange")
+            .field("secret", &format_args!("<redacted>"))
+            .field("transcript_hash", &self.transcript_hash)
+            .finish()
+    }
+}
+
+// Nor is the session key
+impl fmt::Debug for SessionKeys {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SessionKeys")
+            .field("session_key", &format_args!("<redacted>"))
+            .field("key_confirmation", &self.key_confirmation)
+            .finish()
+    }
+}
+
+// Check the share of the client and pick the share of the server. Returns B and Z.
+// A must be in the subgroup of order q, otherwise Z could be confined to a small subgroup.
+pub fn respond(client_share: &BigInt, group: &Group) -> Result<(BigInt, BigInt), Status> {
+    if client_share.is_one() || mod_exp(client_share, &group.q, &group.p) != One::one() {
+        return Err(Status::invalid_argument("dh_share is not in the subgroup of order q"));
+    }
+
+    let b = random_big_int(2.to_bigint().unwrap(), &group.q - 1);
+    Ok((mod_exp(&group.g, &b, &group.p), mod_exp(client_share, &b, &group.p)))
+}
+
+pub fn transcript_hash(transcript: &[u8]) -> Vec<u8> {
+    Sha256::digest(transcript).to_vec()
+}
+
+// Derive the session key and the key confirmation once the answer s is accepted
+pub fn derive(pending: &PendingKeyExchange, s: &BigInt, group: &Group) -> SessionKeys {
+    let mut salt = Sha256::new();
+    salt.update(&pending.transcript_hash);
+    salt.update(encode_bytes(s, group));
+    let salt = salt.finalize();
+
+    let hkdf = Hkdf::<Sha256>::new(Some(&salt), &encode_bytes(&pending.secret, group));
+    let mut session_key = vec![0; KEY_LENGTH];
+    let mut confirmation_key = vec![0; KEY_LENGTH];
+    hkdf.expand(SESSION_KEY_INFO, &mut session_key).expect("32 bytes is a valid HKDF-SHA256 output length");
+    hkdf.expand(CONFIRMATION_KEY_INFO, &mut confirmation_key).expect("32 bytes is a valid HKDF-SHA256 output length");
+
+    let mut mac = Hmac::<Sha256>::new_from_slice(&confirmation_key).expect("HMAC accepts keys of any length");
+    mac.update(SERVER_FINISHED);
+
+    SessionKeys {
+        session_key,
+        key_confirmation: mac.finalize().into_bytes().to_vec(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn debug_does_not_print_secrets() {
+        let secret: BigInt = "123456789123456789123456789".parse().unwrap();
+        let pending = PendingKeyExchange { secret: secret.clone(), transcript_hash: vec![1, 2, 3] };
+        let printed = format!("{:?}", pending);
+        assert!(!printed.contains(&secret.to_string()), "{}", printed);
+        assert!(printed.contains("<redacted>"), "{}", printed);
+
+        let keys = SessionKeys { session_key: vec![0xab; KEY_LENGTH], key_confirmation: vec![1] };
+        assert!(!format!("{:?}", keys).contains("171"), "{:?}", keys);
+    }
+}
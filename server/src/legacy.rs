@@ -0,0 +1,102 @@
+use tonic::{Request, Response, Status};
+
+use crate::encoding::{encode_hex, WireNumber};
+use crate::parameters::KDF;
+use crate::service::{AuthService, Authentication, Commitment, USER_NOT_REGISTERED, WRONG_CREDENTIALS, SECOND_FACTOR_REQUIRED};
+use crate::zkp_auth::auth_server::Auth;
+use crate::zkp_auth::{
+    RegisterRequest,
+    RegisterResponse,
+    AuthenticationChallengeRequest,
+    AuthenticationChallengeResponse,
+    AuthenticationAnswerRequest,
+    AuthenticationAnswerResponse,
+    PublicParametersRequest,
+    PublicParametersResponse
+};
+
+// zkp_auth.Auth: the unversioned service of the clients built before zkp_auth.v1, the numbers are hexadecimal strings
+#[tonic::async_trait]
+impl Auth for AuthService {
+
+    // Implementing the Register method that allows registering users by providing username, y1 and y2
+    async fn register(&self, request:Request<RegisterRequest>) -> Result<Response<RegisterResponse>, Status> {
+
+        let request = request.get_ref();
+        self.register_user(
+            &request.user,
+            "",
+            &request.group_id,
+            WireNumber::Hex(&request.y1),
+            WireNumber::Hex(&request.y2),
+            &[],
+        ).await?;
+
+        Ok(Response::new(RegisterResponse{}))
+    }
+
+    // Implementing the CreateAuthenticationChallenge challenge which creates the challenge c based on username, r1, and r2
+    async fn create_authentication_challenge(&self, request:Request<AuthenticationChallengeRequest>) -> Result<Response<AuthenticationChallengeResponse>, Status> {
+
+        let request = request.get_ref();
+        let challenge = self.create_challenge(
+            &request.user,
+            "",
+            None,
+            Commitment {
+                r1: WireNumber::Hex(&request.r1),
+                r2: WireNumber::Hex(&request.r2),
+                dh_share: None,
+            },
+            // The clients of this version do not know about puzzles, they are refused under load
+            None,
+            // The clients of this version do not know about channel binding, their challenges are not bound
+            None,
+        ).await?;
+
+        // Send back the random challenge c
+        // If user is not registered, set auth_id to UserNotRegistered
+        Ok(Response::new(match challenge {
+            Some(challenge) => AuthenticationChallengeResponse{
+                auth_id: challenge.auth_id,
+                c: encode_hex(&challenge.c),
+            },
+            None => AuthenticationChallengeResponse{
+                auth_id: USER_NOT_REGISTERED.to_string(),
+                c: String::new(),
+            },
+        }))
+    }
+
+    // Implementing the VerifyAuthentication method based on the received s
+    async fn verify_authentication(&self, request:Request<AuthenticationAnswerRequest>) -> Result<Response<AuthenticationAnswerResponse>, Status> {
+
+        let request = request.get_ref();
+        let authentication = self.verify_answer(&request.auth_id, WireNumber::Hex(&request.s), None).await?;
+
+        // The clients of this version cannot send the second factor
+        Ok(Response::new(AuthenticationAnswerResponse{
+            session_id: match authentication {
+                Authentication::Authenticated { session, .. } => session.session_id,
+                Authentication::SecondFactorRequired { .. } => SECOND_FACTOR_REQUIRED.to_string(),
+                Authentication::WrongCredentials => WRONG_CREDENTIALS.to_string(),
+            }
+        }))
+    }
+
+    // Implementing the GetPublicParameters method which lets the clients fetch the group and the KDF instead of hardcoding them
+    async fn get_public_parameters(&self, request:Request<PublicParametersRequest>) -> Result<Response<PublicParametersResponse>, Status> {
+
+        let request = request.get_ref();
+        let group = self.public_parameters(&request.group_id, &request.user, "", false).await?;
+
+        Ok(Response::new(PublicParametersResponse{
+            group_id: group.id.to_string(),
+            p: encode_hex(&group.p),
+            q: encode_hex(&group.q),
+            g: encode_hex(&group.g),
+            h: encode_hex(&group.h),
+            kdf: KDF.to_string(),
+        }))
+    }
+}
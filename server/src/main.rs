@@ -1,259 +1,204 @@
+// tonic::Status is large, and helpers of the RPC handlers return it like the handlers do
+#![allow(clippy::result_large_err)]
+
+mod audit;
+mod challenge_token;
+mod encoding;
+mod groups;
+mod health;
+mod identity;
+mod key_exchange;
+mod legacy;
+mod metrics;
 mod parameters;
+mod puzzle;
+mod service;
+mod shutdown;
+mod sigma;
+mod tls;
+mod totp;
+#[cfg(test)]
+mod transcripts;
+mod v1;
+mod v2;
 mod zkp_utils;
 pub mod zkp_auth {
   tonic::include_proto!("zkp_auth");
-}
-
-use num::Num;
-use num_bigint::{BigInt, ToBigInt};
-use tonic::{transport::Server, Request, Response, Status};
-use sqlx::postgres::PgPoolOptions;
-use sqlx::Row;
-use colored::Colorize;
-
-use parameters::{public_params, DATABASE_URL};
-use zkp_utils::{
-  mod_exp,
-  random_big_int,
-  random_string,
-  default_hash
-};
-use zkp_auth::auth_server::{Auth, AuthServer};
-use zkp_auth::{
-    RegisterRequest, 
-    RegisterResponse,
-    AuthenticationChallengeRequest,
-    AuthenticationChallengeResponse,
-    AuthenticationAnswerRequest,
-    AuthenticationAnswerResponse
-};
-
-#[derive(Debug, Default)]
-pub struct AuthService {}
-
-#[tonic::async_trait]
-impl Auth for AuthService {
-  
-  // Implementing the Register method that allows registering users by providing username, y1 and y2
-  async fn register(&self, request:Request<RegisterRequest>) -> Result<Response<RegisterResponse>, Status> {
-
-    println!("Request={:?}", request);
-
-    let user = &request.get_ref().user;
-    let y1 = &request.get_ref().y1;
-    let y2 = &request.get_ref().y2;
-
-    let pool = PgPoolOptions::new()
-    .max_connections(1)
-    .connect(DATABASE_URL)
-    .await
-    .expect("connection error");
-
-    // user_is_registered is boolean to determine if the user is registered. 
-    // The column register_request:auth_id contains the hash of the usernames. 
-    // We can determine if the user is registered by checking if the hash of the username exists in that column.  
-    let user_is_registered = sqlx::query(
-        "select exists(select 1 from register_request where auth_id=($1))")
-        .bind(&default_hash(user).to_str_radix(16))
-        .fetch_one(&pool)
-        .await
-        .expect("Check User registered failed")
-        .get::<bool, usize>(0)
-    ;
 
-    if user_is_registered == false {
-
-        // Add the user into the database
-        sqlx::query(
-            "insert into register_request (auth_id, y1, y2) values ($1, $2, $3)")
-            .bind(&default_hash(user).to_str_radix(16))
-            .bind(y1)
-            .bind(y2)
-            .execute(&pool)
-            .await
-            .expect("user insertion error")
-        ;
-        println!("{}", "Registration successful!".green());
-    }
-    else {
-        println!("{}", "Already registered. Please login instead".red());
-    }
+  pub mod v1 {
+    tonic::include_proto!("zkp_auth.v1");
+  }
 
-    pool.close().await;
+  pub mod v2 {
+    tonic::include_proto!("zkp_auth.v2");
+  }
 
-    Ok(Response::new(RegisterResponse{}))
+  pub const FILE_DESCRIPTOR_SET: &[u8] = tonic::include_file_descriptor_set!("zkp_descriptor");
 }
 
-// Implementing the CreateAuthenticationChallenge challenge which creates the challenge c based on username, r1, and r2
-async fn create_authentication_challenge(&self, request:Request<AuthenticationChallengeRequest>) -> Result<Response<AuthenticationChallengeResponse>, Status> {
-
-    println!("Request={:?}", request);
-
-    let q = public_params().1;
-    let user = &request.get_ref().user;
-    let r1 = &request.get_ref().r1;
-    let r2 = &request.get_ref().r2;
-
-    let c_hex = random_big_int(2.to_bigint().unwrap(), &q - 1).to_str_radix(16);
-
-    let pool = PgPoolOptions::new()
-    .max_connections(1)
-    .connect(DATABASE_URL)
-    .await
-    .expect("Database pool connection failed");
-
-    let user_is_registered = sqlx::query(
-        "select exists(select 1 from register_request where auth_id= ($1))")
-        .bind(&default_hash(user).to_str_radix(16))
-        .fetch_one(&pool)
-        .await
-        .expect("Check User registered failed")
-        .get::<bool, usize>(0)
-    ;
-        
-    // Set register_request:auth_id to hash(user)
-    // If user is not registered, set register_request:auth_id to UserNotRegistered
-    let mut auth_id = String::new();
-
-    if user_is_registered == false {
-        auth_id.push_str("UserNotRegistered");
-    }
-    else {
-        auth_id.push_str(&default_hash(user).to_str_radix(16));
-
-        // Add the commitment into the database
-        sqlx::query(
-            "insert into auth_commitment (auth_id, r1, r2) values ($1, $2, $3) 
-            on conflict (auth_id) do update set r1 = $2, r2 = $3")
-            .bind(&auth_id)
-            .bind(r1)
-            .bind(r2)
-            .execute(&pool)
-            .await
-            .expect("Commitment insertion error");
+use tonic::transport::{NamedService, Server};
+use sqlx::postgres::PgPoolOptions;
+use tokio::net::TcpListener;
+use colored::Colorize;
+use std::io::Write;
+use std::path::Path;
+use std::sync::Arc;
+
+use metrics::MetricsLayer;
+use parameters::{
+  DEFAULT_GROUP_ID,
+  DATABASE_URL,
+  DATABASE_MAX_CONNECTIONS,
+  DATABASE_CONNECT_TIMEOUT,
+  METRICS_ADDRESS,
+  HEALTH_ENABLED,
+  REFLECTION_ENABLED,
+  SERVER_KEY_PATH,
+  SERVER_PUBLIC_KEY_PATH,
+  CHALLENGE_TOKENS_ENABLED,
+  CHALLENGE_TOKEN_KEYS_PATH,
+  SHUTDOWN_GRACE_PERIOD,
+  TLS_ENABLED,
+  TLS_CERTIFICATE_PATH,
+  TLS_KEY_PATH
+};
+use challenge_token::TokenKeys;
+use identity::ServerKey;
+use service::AuthService;
+use zkp_auth::auth_server::AuthServer;
+use zkp_auth::v1::auth_server::AuthServer as AuthServerV1;
+use zkp_auth::v2::auth_server::AuthServer as AuthServerV2;
 
-        // Add the challenge into the database
-        sqlx::query(
-            "insert into auth_challenge (auth_id, c) values ($1, $2) 
-            on conflict (auth_id) do update set c = $2")
-            .bind(&auth_id)
-            .bind(&c_hex)
-            .execute(&pool)
-            .await
-            .expect("Challenge insertion error");
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+  let address: std::net::SocketAddr = "[::1]:8080".parse().unwrap();
+  let metrics_address = METRICS_ADDRESS.parse().unwrap();
+
+  // Refuse to start with a group whose generators are wrong
+  groups::verify()?;
+  groups::find(DEFAULT_GROUP_ID).ok_or(format!("The default group {} is not in the registry", DEFAULT_GROUP_ID))?;
+
+  // Refuse to start with a challenge length out of range
+  service::check_challenge_bits()?;
+
+  // Long-term key the clients pin to authenticate the server
+  let identity = ServerKey::load_or_generate(Path::new(SERVER_KEY_PATH), Path::new(SERVER_PUBLIC_KEY_PATH))
+    .map_err(|error| format!("Cannot load the server key {}: {}", SERVER_KEY_PATH, error))?;
+  println!("Server key {} written to {} (fingerprint {})", identity.group.id, SERVER_PUBLIC_KEY_PATH, identity.fingerprint());
+
+  // Keys sealing the stateless challenges, shared by the replicas
+  let token_keys = if CHALLENGE_TOKENS_ENABLED {
+    let token_keys = TokenKeys::load_or_generate(Path::new(CHALLENGE_TOKEN_KEYS_PATH))
+      .map_err(|error| format!("Cannot load the challenge token keys {}: {}", CHALLENGE_TOKEN_KEYS_PATH, error))?;
+    println!("Challenge tokens sealed with the key {} of {}", token_keys.current_id(), CHALLENGE_TOKEN_KEYS_PATH);
+    Some(token_keys)
+  } else {
+    None
+  };
+
+  // Certificate the clients pin when TLS is on
+  let acceptor = if TLS_ENABLED {
+    let acceptor = tls::load_or_generate(Path::new(TLS_CERTIFICATE_PATH), Path::new(TLS_KEY_PATH))
+      .map_err(|error| format!("Cannot load the TLS certificate {}: {}", TLS_CERTIFICATE_PATH, error))?;
+    println!("TLS certificate written to {}", TLS_CERTIFICATE_PATH);
+    Some(acceptor)
+  } else {
+    None
+  };
+
+  // Connections are opened on first use, so the server starts even if the database is not up yet
+  let pool = PgPoolOptions::new()
+    .max_connections(DATABASE_MAX_CONNECTIONS)
+    .connect_timeout(DATABASE_CONNECT_TIMEOUT)
+    .connect_lazy(DATABASE_URL)?;
+  // The same service answers every version: zkp_auth.Auth and zkp_auth.v1.Auth (hexadecimal strings), zkp_auth.v2.Auth (bytes)
+  let zkp_auth_service = AuthService::new(pool.clone(), Arc::new(identity));
+  let zkp_auth_service = Arc::new(match token_keys {
+    Some(token_keys) => zkp_auth_service.with_challenge_tokens(token_keys),
+    None => zkp_auth_service,
+  });
+
+  // Expose the Prometheus metrics on a separate HTTP listener
+  metrics::register();
+  tokio::spawn(async move {
+    if let Err(error) = metrics::serve(metrics_address).await {
+      println!("{}", format!("Metrics endpoint failed: {}", error).red());
     }
+  });
 
-    pool.close().await;
-
-    // Send back the random challenge c
-    Ok(Response::new(AuthenticationChallengeResponse{
-        auth_id: auth_id,
-        c: c_hex,
-    }))
-}
-
-// Implementing the VerifyAuthentication method based on the received s
-async fn verify_authentication(&self, request:Request<AuthenticationAnswerRequest>) -> Result<Response<AuthenticationAnswerResponse>, Status> {
-    
-    println!("Request={:?}", request);
-
-    let auth_id = &request.get_ref().auth_id;
-    let s = &request.get_ref().s;
-
-    let pool = PgPoolOptions::new()
-    .max_connections(1)
-    .connect(DATABASE_URL)
-    .await
-    .expect("Database pool connection failed");
-
-    // Retrieving the required parameters (y1, y2, r1 and r2) based on the auth_id for verification
-    let y1 = sqlx::query(
-        "select y1 from register_request where auth_id = ($1)")
-        .bind(auth_id).fetch_one(&pool).await.expect("Error retrieving y1")
-        .get::<String, usize>(0)
-    ;
-    let y2 = sqlx::query(
-        "select y2 from register_request where auth_id = ($1)")
-        .bind(auth_id).fetch_one(&pool).await.expect("Error retrieving y2")
-        .get::<String, usize>(0)
-    ;
-    let r1 = sqlx::query(
-        "select r1 from auth_commitment where auth_id = ($1)")
-        .bind(auth_id).fetch_one(&pool).await.expect("Error retrieving r1")
-        .get::<String, usize>(0)
-    ;
-    let r2 = sqlx::query(
-        "select r2 from auth_commitment where auth_id = ($1)")
-        .bind(auth_id).fetch_one(&pool).await.expect("Error retrieving r2")
-        .get::<String, usize>(0)
-    ;
-    
-    // We can delete the commitment after retrieving it because it is not going to be used anymore
-    sqlx::query(
-        "delete from auth_commitment where auth_id = ($1)")
-        .bind(auth_id).execute(&pool).await.expect("Error deleting commitment")
-    ;
-    let c = sqlx::query(
-        "select c from auth_challenge where auth_id = ($1)")
-        .bind(auth_id).fetch_one(&pool).await.expect("Error retrieving c")
-        .get::<String, usize>(0)
-    ;
-
-    sqlx::query(
-        "delete from auth_challenge where auth_id = ($1)")
-        .bind(auth_id).execute(&pool).await.expect("Error deleting challenge")
-    ;
-
-    pool.close().await;
-
-    // Convert parameters back to BigInt
-    let y1: BigInt = Num::from_str_radix(&y1, 16).unwrap();
-    let y2: BigInt = Num::from_str_radix(&y2, 16).unwrap();
-    let r1: BigInt = Num::from_str_radix(&r1, 16).unwrap();
-    let r2: BigInt = Num::from_str_radix(&r2, 16).unwrap();
-    let c: BigInt = Num::from_str_radix(&c, 16).unwrap();
-    let s: BigInt = Num::from_str_radix(s, 16).unwrap();
-
-    let (p, _q, g, h) = public_params();
-
-    let part1 = ((mod_exp(&g.to_bigint().unwrap(), &s, &p ) * mod_exp(&y1, &c, &p) % &p) + &p) % &p;
-    let part2 = ((mod_exp(&h.to_bigint().unwrap(), &s, &p) * mod_exp(&y2, &c, &p) % &p) + &p) % &p;
-    
-    println!("r1 = {}", &r1);
-    println!("g^s * y1^c = {}", part1);
-    println!("r2 = {}", &r2);
-    println!("h^s * y2^c = {}", part2);
-
-    let mut session_id = String::new();
+  // Bring the database schema up to date. If the database is not reachable yet the health service
+  // reports NOT_SERVING and retries the migrations until they have been applied.
+  if let Err(error) = health::migrate(&pool).await {
+    println!("{}", format!("Database migration failed: {}", error).red());
+  }
 
-    // Verify if the calculated parts have the expected values
-    match &r1 == &part1 && &r2 == &part2 {
-        true => {
-            println!("{}", "Authentication successful!".green());
-            session_id.push_str(&random_string());
-        }
-        false => {
-            println!("{}", "Authentication FAILED!".red());
-            session_id.push_str("WrongCredentials");
+  // Standard grpc.health.v1.Health service reporting the readiness of the server
+  let services = vec![
+    AuthServer::<AuthService>::NAME,
+    AuthServerV1::<AuthService>::NAME,
+    AuthServerV2::<AuthService>::NAME
+  ];
+  let (health_reporter, health_service) = if HEALTH_ENABLED {
+    let (reporter, health_service) = tonic_health::server::health_reporter();
+    let watcher = tokio::spawn(health::watch(reporter.clone(), pool.clone(), services.clone()));
+    (Some((reporter, watcher)), Some(health_service))
+  } else {
+    (None, None)
+  };
+
+  // gRPC server reflection describing every version of zkp_auth.Auth and grpc.health.v1.Health
+  let reflection_service = if REFLECTION_ENABLED {
+    Some(tonic_reflection::server::Builder::configure()
+      .register_encoded_file_descriptor_set(zkp_auth::FILE_DESCRIPTOR_SET)
+      .register_encoded_file_descriptor_set(tonic_health::pb::FILE_DESCRIPTOR_SET)
+      .build()?)
+  } else {
+    None
+  };
+
+  let listener = TcpListener::bind(address).await?;
+  println!("Server listening on {}{}", address, if TLS_ENABLED { " (TLS)" } else { "" });
+  println!("Metrics available on http://{}/metrics", metrics_address);
+  let (draining_sender, draining_receiver) = tokio::sync::oneshot::channel();
+  let server = Server::builder()
+    .add_service(tower::ServiceBuilder::new()
+      .layer(MetricsLayer)
+      .service(AuthServer::from_arc(zkp_auth_service.clone())))
+    .add_service(tower::ServiceBuilder::new()
+      .layer(MetricsLayer)
+      .service(AuthServerV1::from_arc(zkp_auth_service.clone())))
+    .add_service(tower::ServiceBuilder::new()
+      .layer(MetricsLayer)
+      .service(AuthServerV2::from_arc(zkp_auth_service)))
+    .add_optional_service(health_service)
+    .add_optional_service(reflection_service)
+    .serve_with_incoming_shutdown(tls::incoming(listener, acceptor), async move {
+      shutdown::signal().await;
+      println!("{}", "Shutting down: no longer accepting connections, draining in-flight requests".yellow());
+
+      // Tell the orchestrator to stop routing traffic to this instance
+      if let Some((mut reporter, watcher)) = health_reporter {
+        watcher.abort();
+        reporter.set_service_status("", tonic_health::ServingStatus::NotServing).await;
+        for service in services {
+          reporter.set_service_status(service, tonic_health::ServingStatus::NotServing).await;
         }
+      }
+      let _ = draining_sender.send(());
+    });
+
+  // Once the shutdown has started, the in-flight requests are given SHUTDOWN_GRACE_PERIOD to finish
+  tokio::select! {
+    result = server => result?,
+    _ = shutdown::deadline(draining_receiver) => {
+      println!("{}", format!("In-flight requests still running after {:?}, aborting them", SHUTDOWN_GRACE_PERIOD).red());
     }
-
-    Ok(Response::new(AuthenticationAnswerResponse{
-        session_id: session_id
-    }))
   }
-}
-
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-  let address = "[::1]:8080".parse().unwrap();
-  let zkp_auth_service = AuthService::default();
 
-  println!("Server listening on {}", address);
-  Server::builder().add_service(AuthServer::new(zkp_auth_service))
-    .serve(address)
-    .await?;
+  pool.close().await;
+  metrics::flush();
+  println!("{}", "Server stopped".yellow());
+  std::io::stdout().flush()?;
   Ok(())
-     
 }
 
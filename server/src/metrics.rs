@@ -0,0 +1,361 @@
+use std::convert::Infallible;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Instant;
+
+use hyper::body::{Bytes, HttpBody, SizeHint};
+use hyper::header::CONTENT_TYPE;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, HeaderMap, Method, StatusCode};
+use lazy_static::lazy_static;
+use prometheus::{
+    register_histogram, register_histogram_vec, register_int_counter, register_int_counter_vec, register_int_gauge,
+    core::Collector, Encoder, Histogram, HistogramVec, IntCounter, IntCounterVec, IntGauge, TextEncoder,
+};
+use tonic::body::BoxBody;
+use tonic::transport::NamedService;
+use tonic::Status;
+use tower::{Layer, Service};
+
+lazy_static! {
+    // Finished RPCs labelled by gRPC service, method and status code (0 = OK)
+    pub static ref RPC_REQUESTS: IntCounterVec = register_int_counter_vec!(
+        "zkp_rpc_requests_total",
+        "Number of finished RPCs by service, method and gRPC status code",
+        &["service", "method", "code"]
+    ).unwrap();
+
+    pub static ref RPC_DURATION: HistogramVec = register_histogram_vec!(
+        "zkp_rpc_duration_seconds",
+        "Time taken to answer an RPC",
+        &["service", "method"]
+    ).unwrap();
+
+    // Business outcome of each RPC, e.g. rpc="VerifyAuthentication", outcome="success"
+    pub static ref AUTH_OUTCOMES: IntCounterVec = register_int_counter_vec!(
+        "zkp_auth_outcomes_total",
+        "Number of handled requests by RPC and outcome",
+        &["rpc", "outcome"]
+    ).unwrap();
+
+    pub static ref VERIFICATION_DURATION: Histogram = register_histogram!(
+        "zkp_verification_duration_seconds",
+        "Time taken to check g^s * y1^c and h^s * y2^c against the commitment"
+    ).unwrap();
+
+    pub static ref DATABASE_DURATION: HistogramVec = register_histogram_vec!(
+        "zkp_database_duration_seconds",
+        "Time taken by each database operation",
+        &["operation"]
+    ).unwrap();
+
+    pub static ref OUTSTANDING_CHALLENGES: IntGauge = register_int_gauge!(
+        "zkp_outstanding_challenges",
+        "Number of challenges issued and not yet answered"
+    ).unwrap();
+
+    pub static ref ACTIVE_SESSIONS: IntGauge = register_int_gauge!(
+        "zkp_active_sessions",
+        "Number of sessions stored in the database"
+    ).unwrap();
+
+    // Alert on any increase: a client sent a commitment it already used, its random number generator is broken
+    pub static ref COMMITMENT_REUSE: IntCounter = register_int_counter!(
+        "zkp_commitment_reuse_total",
+        "Number of refused commitments already received from the same user"
+    ).unwrap();
+
+    // 0 while CreateAuthenticationChallenge is not under load (see puzzle.rs)
+    pub static ref PUZZLE_DIFFICULTY: IntGauge = register_int_gauge!(
+        "zkp_puzzle_difficulty",
+        "Number of leading zero bits required of the puzzle solutions"
+    ).unwrap();
+}
+
+// Register every metric so that the endpoint exposes them before the first request
+pub fn register() {
+    lazy_static::initialize(&RPC_REQUESTS);
+    lazy_static::initialize(&RPC_DURATION);
+    lazy_static::initialize(&AUTH_OUTCOMES);
+    lazy_static::initialize(&VERIFICATION_DURATION);
+    lazy_static::initialize(&DATABASE_DURATION);
+    lazy_static::initialize(&OUTSTANDING_CHALLENGES);
+    lazy_static::initialize(&ACTIVE_SESSIONS);
+    lazy_static::initialize(&COMMITMENT_REUSE);
+    lazy_static::initialize(&PUZZLE_DIFFICULTY);
+}
+
+// Record the outcome of a handled request
+pub fn record_outcome(rpc: &str, outcome: &str) {
+    AUTH_OUTCOMES.with_label_values(&[rpc, outcome]).inc();
+}
+
+// Await a database operation and record how long it took
+pub async fn timed<F: Future>(operation: &str, future: F) -> F::Output {
+    let start = Instant::now();
+    let output = future.await;
+    DATABASE_DURATION
+        .with_label_values(&[operation])
+        .observe(start.elapsed().as_secs_f64());
+    output
+}
+
+// Tower layer counting and timing every RPC of the wrapped gRPC service
+#[derive(Debug, Clone, Default)]
+pub struct MetricsLayer;
+
+impl<S> Layer<S> for MetricsLayer {
+    type Service = MetricsService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        MetricsService { inner }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MetricsService<S> {
+    inner: S,
+}
+
+impl<S, ReqBody> Service<http::Request<ReqBody>> for MetricsService<S>
+where
+    S: Service<http::Request<ReqBody>, Response = http::Response<BoxBody>> + NamedService,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: http::Request<ReqBody>) -> Self::Future {
+        // The path of a gRPC call is /<service>/<method>
+        let method = request
+            .uri()
+            .path()
+            .rsplit('/')
+            .next()
+            .unwrap_or_default()
+            .to_string();
+        let start = Instant::now();
+        let future = self.inner.call(request);
+
+        Box::pin(async move {
+            let mut rpc = Rpc { service: S::NAME, method, start, code: None };
+            match future.await {
+                // The status is only known once the body is sent, see MetricsBody
+                Ok(response) => {
+                    rpc.code = response
+                        .headers()
+                        .get("grpc-status")
+                        .and_then(|code| code.to_str().ok())
+                        .map(str::to_string);
+                    Ok(response.map(|body| BoxBody::new(MetricsBody { inner: body, rpc: Some(rpc) })))
+                }
+                Err(error) => {
+                    rpc.finish("transport");
+                    Err(error)
+                }
+            }
+        })
+    }
+}
+
+// An RPC being answered, counted and timed once it is finished
+struct Rpc {
+    service: &'static str,
+    method: String,
+    start: Instant,
+    // grpc-status of the headers: errors returned by the handlers are trailers-only responses
+    code: Option<String>,
+}
+
+impl Rpc {
+    fn finish(self, code: &str) {
+        RPC_REQUESTS.with_label_values(&[self.service, &self.method, code]).inc();
+        RPC_DURATION
+            .with_label_values(&[self.service, &self.method])
+            .observe(self.start.elapsed().as_secs_f64());
+    }
+}
+
+// The body of a response, recording the RPC when it ends. Successful calls, streaming calls and errors sent after the
+// first messages carry grpc-status in the trailers. A body dropped before its trailers is a call cancelled by the client.
+pub struct MetricsBody {
+    inner: BoxBody,
+    rpc: Option<Rpc>,
+}
+
+impl MetricsBody {
+    fn finish(&mut self, code: Option<&str>) {
+        if let Some(rpc) = self.rpc.take() {
+            let code = code.map(str::to_string).or_else(|| rpc.code.clone());
+            rpc.finish(code.as_deref().unwrap_or("unknown"));
+        }
+    }
+}
+
+impl HttpBody for MetricsBody {
+    type Data = Bytes;
+    type Error = Status;
+
+    fn poll_data(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Bytes, Status>>> {
+        let polled = Pin::new(&mut self.inner).poll_data(cx);
+        if let Poll::Ready(Some(Err(status))) = &polled {
+            self.finish(Some(&(status.code() as i32).to_string()));
+        }
+        polled
+    }
+
+    fn poll_trailers(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<Option<HeaderMap>, Status>> {
+        let polled = Pin::new(&mut self.inner).poll_trailers(cx);
+        match &polled {
+            Poll::Ready(Ok(trailers)) => {
+                let code = trailers
+                    .as_ref()
+                    .and_then(|trailers| trailers.get("grpc-status"))
+                    .and_then(|code| code.to_str().ok())
+                    .map(str::to_string);
+                self.finish(code.as_deref());
+            }
+            Poll::Ready(Err(status)) => self.finish(Some(&(status.code() as i32).to_string())),
+            Poll::Pending => {}
+        }
+        polled
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+impl Drop for MetricsBody {
+    fn drop(&mut self) {
+        // A trailers-only response has no body to send, otherwise the client went away
+        let code = self.rpc.as_ref().and_then(|rpc| rpc.code.clone()).unwrap_or_else(|| "cancelled".to_string());
+        self.finish(Some(&code));
+    }
+}
+
+impl<S: NamedService> NamedService for MetricsService<S> {
+    const NAME: &'static str = S::NAME;
+}
+
+async fn handle(request: hyper::Request<Body>) -> Result<hyper::Response<Body>, Infallible> {
+    if request.method() != Method::GET || request.uri().path() != "/metrics" {
+        let mut not_found = hyper::Response::new(Body::empty());
+        *not_found.status_mut() = StatusCode::NOT_FOUND;
+        return Ok(not_found);
+    }
+
+    let encoder = TextEncoder::new();
+    let mut buffer = Vec::new();
+    encoder
+        .encode(&prometheus::gather(), &mut buffer)
+        .expect("Metrics encoding failed");
+
+    let mut response = hyper::Response::new(Body::from(buffer));
+    response.headers_mut().insert(
+        CONTENT_TYPE,
+        encoder.format_type().parse().expect("Invalid metrics content type"),
+    );
+    Ok(response)
+}
+
+// Write the final value of the outcome counters and gauges to the log, as they are lost once the process exits
+pub fn flush() {
+    for family in AUTH_OUTCOMES.collect() {
+        for metric in family.get_metric() {
+            let labels = metric
+                .get_label()
+                .iter()
+                .map(|label| format!("{}=\"{}\"", label.get_name(), label.get_value()))
+                .collect::<Vec<String>>()
+                .join(",");
+            println!("{}{{{}}} {}", family.get_name(), labels, metric.get_counter().get_value());
+        }
+    }
+    println!("zkp_outstanding_challenges {}", OUTSTANDING_CHALLENGES.get());
+    println!("zkp_active_sessions {}", ACTIVE_SESSIONS.get());
+}
+
+// Serve the Prometheus text exposition format on http://<address>/metrics
+pub async fn serve(address: SocketAddr) -> Result<(), hyper::Error> {
+    let make_service = make_service_fn(|_| async { Ok::<_, Infallible>(service_fn(handle)) });
+    hyper::Server::bind(&address).serve(make_service).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A body with no data and the given trailers
+    struct Trailers(Option<HeaderMap>);
+
+    impl HttpBody for Trailers {
+        type Data = Bytes;
+        type Error = Status;
+
+        fn poll_data(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Option<Result<Bytes, Status>>> {
+            Poll::Ready(None)
+        }
+
+        fn poll_trailers(mut self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<Option<HeaderMap>, Status>> {
+            Poll::Ready(Ok(self.0.take()))
+        }
+    }
+
+    fn body(method: &str, header_code: Option<&str>, trailer_code: Option<&str>) -> MetricsBody {
+        let trailers = trailer_code.map(|code| {
+            let mut trailers = HeaderMap::new();
+            trailers.insert("grpc-status", code.parse().unwrap());
+            trailers
+        });
+        let rpc = Rpc { service: "test", method: method.to_string(), start: Instant::now(), code: header_code.map(str::to_string) };
+        MetricsBody { inner: BoxBody::new(Trailers(trailers)), rpc: Some(rpc) }
+    }
+
+    fn count(method: &str, code: &str) -> u64 {
+        RPC_REQUESTS.with_label_values(&["test", method, code]).get()
+    }
+
+    async fn send(mut body: MetricsBody) {
+        while std::future::poll_fn(|cx| Pin::new(&mut body).poll_data(cx)).await.is_some() {}
+        std::future::poll_fn(|cx| Pin::new(&mut body).poll_trailers(cx)).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn records_the_status_of_the_trailers() {
+        send(body("Trailers", None, Some("16"))).await;
+        assert_eq!(count("Trailers", "16"), 1);
+        assert_eq!(count("Trailers", "0"), 0);
+        assert_eq!(RPC_DURATION.with_label_values(&["test", "Trailers"]).get_sample_count(), 1);
+    }
+
+    #[tokio::test]
+    async fn records_the_status_of_a_trailers_only_response() {
+        drop(body("TrailersOnly", Some("5"), None));
+        assert_eq!(count("TrailersOnly", "5"), 1);
+    }
+
+    #[tokio::test]
+    async fn records_a_cancelled_call_once() {
+        let mut cancelled = body("Cancelled", None, Some("0"));
+        std::future::poll_fn(|cx| Pin::new(&mut cancelled).poll_data(cx)).await;
+        drop(cancelled);
+        assert_eq!(count("Cancelled", "cancelled"), 1);
+        assert_eq!(count("Cancelled", "0"), 0);
+
+        send(body("Finished", None, Some("0"))).await;
+        assert_eq!(count("Finished", "0"), 1);
+        assert_eq!(count("Finished", "cancelled"), 0);
+    }
+}
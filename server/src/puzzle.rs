@@ -0,0 +1,287 @@
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::sync::Mutex;
+use std::time::Instant;
+use tonic::metadata::{BinaryMetadataValue, MetadataValue};
+use tonic::Status;
+
+use crate::metrics;
+use crate::parameters::{PUZZLE_ENABLED, PUZZLE_WINDOW, PUZZLE_LOAD_THRESHOLD, PUZZLE_MIN_DIFFICULTY, PUZZLE_MAX_DIFFICULTY,
+    PUZZLE_SEED_LIFETIME};
+
+// Hashcash-style client puzzle on CreateAuthenticationChallenge, which writes to the database on every call.
+// Under load, the server refuses the requests without a solution with RESOURCE_EXHAUSTED and puts the puzzle in the
+// metadata of the response: a random seed and a difficulty. A solution is a nonce such that
+//   SHA-256(PUZZLE_DOMAIN || seed || user || nonce)
+// starts with at least difficulty zero bits, with user prefixed with its length as a 4-byte big-endian integer and the
+// nonce as 8 big-endian bytes. It takes the client about 2^difficulty hashes, and the server one hash and no database
+// access to check. A solution is accepted once, with the current or the previous seed, and with one bit less than the
+// current difficulty for the load to grow while the client solves it.
+// The difficulty grows by one bit each time the number of requests per PUZZLE_WINDOW doubles past PUZZLE_LOAD_THRESHOLD.
+
+pub const PUZZLE_DOMAIN: &[u8] = b"zkp_auth/puzzle";
+
+// Metadata of the RESOURCE_EXHAUSTED responses
+pub const SEED_METADATA: &str = "zkp-puzzle-seed-bin";
+pub const DIFFICULTY_METADATA: &str = "zkp-puzzle-difficulty";
+
+const SEED_LENGTH: usize = 32;
+
+// A solution sent with a request
+#[derive(Debug, Clone, Copy)]
+pub struct Solution<'a> {
+    pub seed: &'a [u8],
+    pub nonce: u64,
+}
+
+#[derive(Debug)]
+struct State {
+    seed: Vec<u8>,
+    previous_seed: Option<Vec<u8>>,
+    seed_created: Instant,
+    // Hashes of the solutions accepted with seed and with previous_seed
+    spent: HashSet<Vec<u8>>,
+    previous_spent: HashSet<Vec<u8>>,
+    // Requests in the current window and in the one before
+    window_start: Instant,
+    requests: u64,
+    previous_requests: u64,
+}
+
+// The puzzles of a server, shared by all its connections
+#[derive(Debug)]
+pub struct Puzzles {
+    state: Mutex<State>,
+}
+
+impl Puzzles {
+
+    pub fn new() -> Self {
+        let now = Instant::now();
+        Puzzles {
+            state: Mutex::new(State {
+                seed: random_seed(),
+                previous_seed: None,
+                seed_created: now,
+                spent: HashSet::new(),
+                previous_spent: HashSet::new(),
+                window_start: now,
+                requests: 0,
+                previous_requests: 0,
+            }),
+        }
+    }
+
+    // Count a request of user and let it through if the load is low or if it carries a valid solution. Otherwise refuse
+    // it with RESOURCE_EXHAUSTED and the puzzle to solve.
+    pub fn admit(&self, user: &str, solution: Option<Solution<'_>>) -> Result<(), Status> {
+        if !PUZZLE_ENABLED {
+            return Ok(());
+        }
+
+        let mut state = self.state.lock().expect("The puzzle state is poisoned");
+        state.rotate(Instant::now());
+        state.requests += 1;
+
+        let difficulty = difficulty(state.requests.max(state.previous_requests));
+        metrics::PUZZLE_DIFFICULTY.set(difficulty as i64);
+        if difficulty == 0 {
+            return Ok(());
+        }
+
+        let refused = match solution {
+            None => "puzzle_required",
+            Some(solution) => match state.accept(user, solution, difficulty) {
+                Ok(()) => {
+                    metrics::record_outcome("CreateAuthenticationChallenge", "puzzle_solved");
+                    return Ok(());
+                }
+                Err(refused) => refused,
+            },
+        };
+        metrics::record_outcome("CreateAuthenticationChallenge", refused);
+
+        let mut status = Status::resource_exhausted(format!("The server is under load, solve the puzzle of difficulty {} and retry", difficulty));
+        status.metadata_mut().insert_bin(SEED_METADATA, BinaryMetadataValue::from_bytes(&state.seed));
+        status.metadata_mut().insert(DIFFICULTY_METADATA, MetadataValue::from(difficulty));
+        Err(status)
+    }
+}
+
+impl State {
+
+    // Start a new window and a new seed when their time is up
+    fn rotate(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.window_start);
+        if elapsed >= PUZZLE_WINDOW {
+            // A window without any request in between means no load
+            self.previous_requests = if elapsed < 2 * PUZZLE_WINDOW { self.requests } else { 0 };
+            self.requests = 0;
+            self.window_start = now;
+        }
+        if now.duration_since(self.seed_created) >= PUZZLE_SEED_LIFETIME {
+            self.previous_seed = Some(std::mem::replace(&mut self.seed, random_seed()));
+            self.previous_spent = std::mem::take(&mut self.spent);
+            self.seed_created = now;
+        }
+    }
+
+    // Check a solution against the current or the previous seed, and spend it. The error is the outcome of the refusal.
+    fn accept(&mut self, user: &str, solution: Solution<'_>, difficulty: u32) -> Result<(), &'static str> {
+        let spent = if solution.seed == self.seed.as_slice() {
+            &mut self.spent
+        } else if self.previous_seed.as_deref() == Some(solution.seed) {
+            &mut self.previous_spent
+        } else {
+            return Err("puzzle_expired");
+        };
+
+        let hash = puzzle_hash(solution.seed, user, solution.nonce);
+        if leading_zero_bits(&hash) + 1 < difficulty {
+            return Err("puzzle_wrong");
+        }
+        if !spent.insert(hash) {
+            return Err("puzzle_replayed");
+        }
+        Ok(())
+    }
+}
+
+// Difficulty in bits for a number of requests per window: 0 up to the threshold, then PUZZLE_MIN_DIFFICULTY plus one
+// bit each time the load doubles, at most PUZZLE_MAX_DIFFICULTY
+pub fn difficulty(requests: u64) -> u32 {
+    if requests <= PUZZLE_LOAD_THRESHOLD {
+        return 0;
+    }
+    let doublings = (requests / PUZZLE_LOAD_THRESHOLD.max(1)).ilog2();
+    (PUZZLE_MIN_DIFFICULTY + doublings).min(PUZZLE_MAX_DIFFICULTY)
+}
+
+// SHA-256(PUZZLE_DOMAIN || seed || user || nonce)
+pub fn puzzle_hash(seed: &[u8], user: &str, nonce: u64) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.update(PUZZLE_DOMAIN);
+    hasher.update(seed);
+    hasher.update((user.len() as u32).to_be_bytes());
+    hasher.update(user.as_bytes());
+    hasher.update(nonce.to_be_bytes());
+    hasher.finalize().to_vec()
+}
+
+pub fn leading_zero_bits(hash: &[u8]) -> u32 {
+    let mut bits = 0;
+    for byte in hash {
+        bits += byte.leading_zeros();
+        if *byte != 0 {
+            break;
+        }
+    }
+    bits
+}
+
+fn random_seed() -> Vec<u8> {
+    let mut seed = vec![0; SEED_LENGTH];
+    rand::thread_rng().fill_bytes(&mut seed);
+    seed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Known answers shared with client/src/puzzle.rs, so that both sides hash the same way: with the seed 0, 1, ..., 31,
+    // the user, a nonce and the leading zero bits of its hash
+    const SEED: [u8; SEED_LENGTH] = [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 28, 29, 30, 31];
+    const ALICE_NONCE_0_HASH: &str = "6870aafbc8698268f463384787405f495ad0b999cb13f3f8ac84bb75949026eb";
+    const SOLUTIONS: [(&str, u64, u32); 4] = [("alice", 134, 8), ("alice", 2736, 12), ("", 70, 10), ("", 4507, 12)];
+
+    fn state(now: Instant) -> State {
+        State {
+            seed: SEED.to_vec(),
+            previous_seed: None,
+            seed_created: now,
+            spent: HashSet::new(),
+            previous_spent: HashSet::new(),
+            window_start: now,
+            requests: 0,
+            previous_requests: 0,
+        }
+    }
+
+    fn solution(nonce: u64) -> Solution<'static> {
+        Solution { seed: &SEED, nonce }
+    }
+
+    #[test]
+    fn known_answers() {
+        let hash: String = puzzle_hash(&SEED, "alice", 0).iter().map(|byte| format!("{:02x}", byte)).collect();
+        assert_eq!(hash, ALICE_NONCE_0_HASH);
+        for (user, nonce, bits) in SOLUTIONS {
+            assert_eq!(leading_zero_bits(&puzzle_hash(&SEED, user, nonce)), bits, "{:?} {}", user, nonce);
+        }
+    }
+
+    #[test]
+    fn leading_zero_bits_of_bytes() {
+        assert_eq!(leading_zero_bits(&[0x80, 0]), 0);
+        assert_eq!(leading_zero_bits(&[0x01, 0xff]), 7);
+        assert_eq!(leading_zero_bits(&[0, 0x10]), 11);
+        assert_eq!(leading_zero_bits(&[0, 0]), 16);
+    }
+
+    #[test]
+    fn solutions_are_accepted() {
+        let mut state = state(Instant::now());
+        assert_eq!(state.accept("alice", solution(2736), 12), Ok(()));
+        assert_eq!(state.accept("", solution(4507), 12), Ok(()));
+    }
+
+    // One bit less than the difficulty is enough, two bits less is not
+    #[test]
+    fn solutions_one_bit_below_the_difficulty_are_accepted() {
+        let mut state = state(Instant::now());
+        assert_eq!(state.accept("alice", solution(134), 10), Err("puzzle_wrong"));
+        assert_eq!(state.accept("alice", solution(134), 9), Ok(()));
+    }
+
+    // A solution for the seed of before the rotation is still accepted, with one bit less, until the next rotation
+    #[test]
+    fn solutions_of_the_previous_seed_are_accepted_after_a_rotation() {
+        let start = Instant::now();
+        let mut state = state(start);
+        state.rotate(start + PUZZLE_SEED_LIFETIME);
+        assert_ne!(state.seed, SEED);
+        assert_eq!(state.previous_seed.as_deref(), Some(SEED.as_slice()));
+        assert_eq!(state.accept("alice", solution(2736), 13), Ok(()));
+        assert_eq!(state.accept("alice", solution(2736), 13), Err("puzzle_replayed"));
+
+        state.rotate(start + 2 * PUZZLE_SEED_LIFETIME);
+        assert_eq!(state.accept("", solution(4507), 12), Err("puzzle_expired"));
+    }
+
+    #[test]
+    fn spent_solutions_are_refused() {
+        let mut state = state(Instant::now());
+        assert_eq!(state.accept("alice", solution(2736), 12), Ok(()));
+        assert_eq!(state.accept("alice", solution(2736), 12), Err("puzzle_replayed"));
+        // The same nonce for another user is another solution, and a wrong one
+        assert_eq!(state.accept("bob", solution(2736), 12), Err("puzzle_wrong"));
+    }
+
+    #[test]
+    fn wrong_seeds_and_difficulties_are_refused() {
+        let mut state = state(Instant::now());
+        assert_eq!(state.accept("alice", Solution { seed: &[0; SEED_LENGTH], nonce: 2736 }, 12), Err("puzzle_expired"));
+        assert_eq!(state.accept("alice", solution(2736), 14), Err("puzzle_wrong"));
+        assert_eq!(state.accept("alice", solution(2735), 12), Err("puzzle_wrong"));
+    }
+
+    #[test]
+    fn difficulty_grows_with_the_load() {
+        assert_eq!(difficulty(PUZZLE_LOAD_THRESHOLD), 0);
+        assert_eq!(difficulty(PUZZLE_LOAD_THRESHOLD + 1), PUZZLE_MIN_DIFFICULTY);
+        assert_eq!(difficulty(2 * PUZZLE_LOAD_THRESHOLD), PUZZLE_MIN_DIFFICULTY + 1);
+        assert_eq!(difficulty(u64::MAX), PUZZLE_MAX_DIFFICULTY);
+    }
+}
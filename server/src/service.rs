@@ -0,0 +1,1743 @@
+use colored::Colorize;
+use num_bigint::{BigInt, Sign};
+use num_traits::{One, Zero};
+use sha2::{Digest, Sha256};
+use sqlx::postgres::{PgConnection, PgPool, Postgres};
+use sqlx::{Row, Transaction};
+use std::sync::Arc;
+use std::time::Instant;
+use tonic::Status;
+
+use tonic::transport::NamedService;
+
+use crate::audit;
+use crate::challenge_token::{ChallengeState, TokenKeys};
+use crate::encoding::{encode_bytes, encode_hex, WireNumber, HEX_ENCODING, BYTES_ENCODING};
+use crate::groups::{self, Group};
+use crate::identity::{ChallengeTranscript, RingTranscript, ServerKey, Signature};
+use crate::key_exchange::{self, PendingKeyExchange, KEY_EXCHANGE};
+use crate::metrics::{self, timed};
+use crate::puzzle::{Puzzles, Solution};
+use crate::sigma::{AnyOf, ChaumPedersen, Schnorr, SigmaProtocol};
+use crate::parameters::{DEFAULT_GROUP_ID, KDF, PROOF_MODES, CHALLENGE_BITS, MIN_CHALLENGE_BITS, MAX_CREDENTIAL_ID_LENGTH, MAX_RECOVERY_CODES, SESSION_ID_LENGTH, CHALLENGE_ID_LENGTH, CHALLENGE_LIFETIME,
+    SECOND_FACTOR_TIMEOUT, MAX_SECOND_FACTOR_ATTEMPTS, COMMITMENT_REUSE_WINDOW, CHALLENGE_TOKEN_LIFETIME, AUTHENTICATE_STREAM_DEADLINE,
+    RING_MIN_SIZE, RING_MAX_SIZE, RING_SIZE};
+use crate::totp::{self, Clock, SystemClock};
+use crate::zkp_auth;
+use crate::zkp_utils::{
+    random_big_int,
+    random_string,
+    default_hash
+};
+
+// auth_id sent back instead of a challenge when the user is not registered
+pub const USER_NOT_REGISTERED: &str = "UserNotRegistered";
+
+// session_id sent back instead of a session when the answer is wrong
+pub const WRONG_CREDENTIALS: &str = "WrongCredentials";
+
+// session_id sent back instead of a session when the proof is right but the user must also send a TOTP code
+pub const SECOND_FACTOR_REQUIRED: &str = "SecondFactorRequired";
+
+// Credential of the requests which do not name one, and of the users registered before credentials could be named
+pub const DEFAULT_CREDENTIAL_ID: &str = "default";
+
+// auth_id and credential_id of the anonymous sessions, which belong to no user
+pub const ANONYMOUS_AUTH_ID: &str = "";
+
+// Domain separators of the challenges bound to an intent (see bound_challenge)
+pub const CHANGE_PASSWORD_DOMAIN: &[u8] = b"zkp_auth/change-password";
+pub const UNREGISTER_DOMAIN: &[u8] = b"zkp_auth/unregister";
+pub const RECOVERY_DOMAIN: &[u8] = b"zkp_auth/recovery";
+
+// Domain separator of the fingerprints of the commitments (see commitment_fingerprint)
+pub const COMMITMENT_DOMAIN: &[u8] = b"zkp_auth/commitment";
+
+// Domain separator of the decoy of the users GetPublicParameters does not know (see public_parameters)
+pub const DECOY_DOMAIN: &[u8] = b"zkp_auth/decoy";
+
+// Domain separator of the challenges of the commitments received over TLS (see channel_bound_challenge)
+pub const CHANNEL_BINDING_DOMAIN: &[u8] = b"zkp_auth/tls-exporter";
+
+// The versions of the Auth service served side by side, with the encoding of their numbers
+pub const PROTOCOL_VERSIONS: [(&str, &str); 3] = [
+    (zkp_auth::auth_server::AuthServer::<AuthService>::NAME, HEX_ENCODING),
+    (zkp_auth::v1::auth_server::AuthServer::<AuthService>::NAME, HEX_ENCODING),
+    (zkp_auth::v2::auth_server::AuthServer::<AuthService>::NAME, BYTES_ENCODING),
+];
+
+// The logic of the Auth service, shared by every version of the protocol.
+// The versions only differ in how the numbers are encoded on the wire (see encoding.rs).
+#[derive(Debug, Clone)]
+pub struct AuthService {
+    pool: PgPool,
+    // Signs the transcript of the challenges
+    identity: Arc<ServerKey>,
+    // Time of the TOTP second factor
+    clock: Arc<dyn Clock>,
+    // Puzzles required before issuing a challenge under load
+    puzzles: Arc<Puzzles>,
+    // Keys sealing the challenges in tokens instead of storing them, None to store them in the database
+    challenge_tokens: Option<Arc<TokenKeys>>,
+}
+
+// What the server supports, sent back by GetCapabilities
+#[derive(Debug)]
+pub struct Capabilities {
+    pub versions: Vec<(&'static str, &'static str)>,
+    pub groups: Vec<&'static str>,
+    pub default_group_id: &'static str,
+    pub kdfs: Vec<&'static str>,
+    pub proof_modes: Vec<&'static str>,
+    pub key_exchanges: Vec<&'static str>,
+}
+
+// A credential (device) of a user, with its own public key and group
+#[derive(Debug)]
+pub struct Credential {
+    pub credential_id: String,
+    pub group_id: String,
+}
+
+// A session opened by a successful authentication, with the credential that was proven
+#[derive(Debug)]
+pub struct Session {
+    pub session_id: String,
+    pub credential_id: String,
+}
+
+// The outcome of an answer to a challenge. key_confirmation is set when the client asked for a key exchange.
+#[derive(Debug)]
+pub enum Authentication {
+    Authenticated { session: Session, key_confirmation: Option<Vec<u8>> },
+    // The proof is right, the second factor must be sent with this token
+    SecondFactorRequired { token: String, key_confirmation: Option<Vec<u8>> },
+    WrongCredentials,
+}
+
+// Who keeps the commitment and the challenge until the answer: the database, the client in a challenge token, or the
+// Authenticate stream
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Custody {
+    Database,
+    Token,
+    Stream,
+}
+
+// A commitment (r1, r2) received in a request, with the share A of the key exchange if the client asked for one
+#[derive(Debug, Clone, Copy)]
+pub struct Commitment<'a> {
+    pub r1: WireNumber<'a>,
+    pub r2: WireNumber<'a>,
+    pub dh_share: Option<WireNumber<'a>>,
+}
+
+// The challenge c issued for a commitment, in the group of the user
+#[derive(Debug)]
+pub struct Challenge {
+    pub auth_id: String,
+    pub c: BigInt,
+    pub group: &'static Group,
+    // Signature of the server over the transcript of the challenge
+    pub signature: Signature,
+    // Share B = g^b of the server if the client sent a share for a key exchange
+    pub dh_share: Option<BigInt>,
+}
+
+// The fixed ring of a key, for anonymous logins
+#[derive(Debug)]
+pub struct Ring {
+    pub group: &'static Group,
+    pub members: Vec<BigInt>,
+}
+
+// The challenge c issued for the commitments of an anonymous login, signed by the server
+#[derive(Debug)]
+pub struct RingChallenge {
+    pub c: BigInt,
+    pub group: &'static Group,
+    pub signature: Signature,
+}
+
+// What an AuthenticateAnonymously stream keeps until the answer: the ring, the commitments and the challenge, bound to
+// the connection if any. Nothing identifies the client.
+#[derive(Debug)]
+pub struct RingState {
+    group: &'static Group,
+    ring: Vec<BigInt>,
+    commitments: Vec<BigInt>,
+    c: BigInt,
+}
+
+impl AuthService {
+
+    pub fn new(pool: PgPool, identity: Arc<ServerKey>) -> Self {
+        Self::with_clock(pool, identity, Arc::new(SystemClock))
+    }
+
+    // A service whose TOTP codes are checked against clock, e.g. a FixedClock
+    pub fn with_clock(pool: PgPool, identity: Arc<ServerKey>, clock: Arc<dyn Clock>) -> Self {
+        AuthService { pool, identity, clock, puzzles: Arc::new(Puzzles::new()), challenge_tokens: None }
+    }
+
+    // A service issuing stateless challenges sealed with tokens (see challenge_token.rs). Challenges stored in the
+    // database before are still answered.
+    pub fn with_challenge_tokens(self, challenge_tokens: TokenKeys) -> Self {
+        AuthService { challenge_tokens: Some(Arc::new(challenge_tokens)), ..self }
+    }
+
+    // Register a user by storing y1 and y2 as the first credential of the user. Clients which do not send a group id
+    // compute y1 and y2 in the legacy group. Further credentials are enrolled with add_credential.
+    // recovery_keys are the public keys (y1, y2) of the recovery codes of the user, in the same group, numbered from 1.
+    pub async fn register_user(&self, user: &str, credential_id: &str, group_id: &str, y1: WireNumber<'_>, y2: WireNumber<'_>, recovery_keys: &[(WireNumber<'_>, WireNumber<'_>)]) -> Result<(), Status> {
+
+        let credential_id = credential_or_default(credential_id)?;
+
+        let group_id = group_or_legacy(group_id);
+        let group = match groups::find(group_id) {
+            Some(group) => group,
+            None => {
+                metrics::record_outcome("Register", "unknown_group");
+                return Err(Status::invalid_argument(format!("Unknown group {}", group_id)));
+            }
+        };
+        let y1 = y1.decode_element("y1", group)?;
+        let y2 = y2.decode_element("y2", group)?;
+
+        if recovery_keys.len() > MAX_RECOVERY_CODES {
+            return Err(Status::invalid_argument(format!("At most {} recovery codes can be registered", MAX_RECOVERY_CODES)));
+        }
+        let recovery_keys = recovery_keys
+            .iter()
+            .map(|(y1, y2)| Ok((y1.decode_element("recovery y1", group)?, y2.decode_element("recovery y2", group)?)))
+            .collect::<Result<Vec<(BigInt, BigInt)>, Status>>()?;
+
+        let pool = &self.pool;
+
+        // user_is_registered is boolean to determine if the user is registered.
+        // The column register_request:auth_id contains the hash of the usernames.
+        // We can determine if the user is registered by checking if the hash of the username exists in that column.
+        let user_is_registered = timed("select_registered", sqlx::query(
+            "select exists(select 1 from register_request where auth_id=($1))")
+            .bind(default_hash(user).to_str_radix(16))
+            .fetch_one(pool))
+            .await
+            .expect("Check User registered failed")
+            .get::<bool, usize>(0)
+        ;
+
+        if !user_is_registered {
+
+            // The user and the recovery codes are stored together or not at all
+            let mut transaction = pool.begin().await.expect("Transaction start error");
+
+            // Add the user into the database
+            timed("insert_user", sqlx::query(
+                "insert into register_request (auth_id, credential_id, y1, y2, group_id) values ($1, $2, $3, $4, $5)")
+                .bind(default_hash(user).to_str_radix(16))
+                .bind(credential_id)
+                .bind(encode_hex(&y1))
+                .bind(encode_hex(&y2))
+                .bind(group.id)
+                .execute(&mut transaction))
+                .await
+                .expect("user insertion error")
+            ;
+
+            for (code_id, (y1, y2)) in recovery_keys.iter().enumerate() {
+                timed("insert_recovery_code", sqlx::query(
+                    "insert into recovery_code (auth_id, code_id, y1, y2, group_id) values ($1, $2, $3, $4, $5)")
+                    .bind(default_hash(user).to_str_radix(16))
+                    .bind(code_id as i32 + 1)
+                    .bind(encode_hex(y1))
+                    .bind(encode_hex(y2))
+                    .bind(group.id)
+                    .execute(&mut transaction))
+                    .await
+                    .expect("Recovery code insertion error")
+                ;
+            }
+
+            transaction.commit().await.expect("Transaction commit error");
+            metrics::record_outcome("Register", "registered");
+            println!("{}", "Registration successful!".green());
+        }
+        else {
+            metrics::record_outcome("Register", "already_registered");
+            println!("{}", "Already registered. Please login instead".red());
+        }
+
+        Ok(())
+    }
+
+    // Store the commitment (r1, r2) made with the credential credential_id of the user, or with the recovery code
+    // recovery_code_id if any, and create the challenge c signed by the server. None if the user is not registered or has
+    // no such credential or code.
+    // With a dh_share in the commitment, the share A = g^a of the client, the server also answers with its share of a key exchange.
+    // channel_binding is the TLS exporter of the connection of the request, None without TLS. The challenge is then bound
+    // to it and must be answered on the same connection.
+    // A commitment the user already sent within COMMITMENT_REUSE_WINDOW is refused and raises an alert.
+    // Under load, the request must carry the solution of a puzzle (see puzzle.rs).
+    // With challenge tokens, the commitment and the challenge are sealed in the auth_id instead of being stored.
+    pub async fn create_challenge(&self, user: &str, credential_id: &str, recovery_code_id: Option<i32>, commitment: Commitment<'_>, puzzle: Option<Solution<'_>>, channel_binding: Option<&[u8]>) -> Result<Option<Challenge>, Status> {
+        let custody = match self.challenge_tokens {
+            Some(_) => Custody::Token,
+            None => Custody::Database,
+        };
+        let challenge = self.issue_challenge(user, credential_id, recovery_code_id, commitment, puzzle, channel_binding, custody).await?;
+        Ok(challenge.map(|(challenge, _)| challenge))
+    }
+
+    // Create the challenge of a login over an Authenticate stream: the stream keeps the returned state until the answer
+    pub async fn create_stream_challenge(&self, user: &str, credential_id: &str, commitment: Commitment<'_>, puzzle: Option<Solution<'_>>, channel_binding: Option<&[u8]>) -> Result<Option<(Challenge, ChallengeState)>, Status> {
+        self.issue_challenge(user, credential_id, None, commitment, puzzle, channel_binding, Custody::Stream).await
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn issue_challenge(&self, user: &str, credential_id: &str, recovery_code_id: Option<i32>, commitment: Commitment<'_>, puzzle: Option<Solution<'_>>, channel_binding: Option<&[u8]>, custody: Custody) -> Result<Option<(Challenge, ChallengeState)>, Status> {
+
+        // Under load, nothing is read or written before the puzzle is solved
+        self.puzzles.admit(user, puzzle)?;
+
+        let pool = &self.pool;
+        // The transcript holds the request as sent by the client
+        let requested_credential_id = credential_id;
+        let credential_id = credential_or_default(credential_id)?;
+
+        // The group of the credential or of the recovery code if the user is registered with it
+        let group_id = match recovery_code_id {
+            None => timed("select_group", sqlx::query(
+                "select group_id from register_request where auth_id = ($1) and credential_id = ($2)")
+                .bind(default_hash(user).to_str_radix(16))
+                .bind(credential_id)
+                .fetch_optional(pool))
+                .await,
+            Some(recovery_code_id) => timed("select_recovery_group", sqlx::query(
+                "select group_id from recovery_code where auth_id = ($1) and code_id = ($2)")
+                .bind(default_hash(user).to_str_radix(16))
+                .bind(recovery_code_id)
+                .fetch_optional(pool))
+                .await,
+        }
+            .expect("Check User registered failed")
+            .map(|row| row.get::<String, usize>(0))
+        ;
+
+        let group = match group_id {
+            Some(group_id) => registered_group(&group_id)?,
+            None => {
+                metrics::record_outcome("CreateAuthenticationChallenge", "user_not_registered");
+                return Ok(None);
+            }
+        };
+        let r1 = commitment.r1.decode_element("r1", group)?;
+        let r2 = commitment.r2.decode_element("r2", group)?;
+
+        // Set register_request:auth_id to hash(user)
+        let auth_id = default_hash(user).to_str_radix(16);
+        let c = random_big_int(Zero::zero(), challenge_space(group));
+
+        // The shares of the key exchange are signed with the challenge, the server keeps Z and the hash of the transcript
+        let client_dh_share = commitment.dh_share.map(|share| share.decode_element("dh_share", group)).transpose()?;
+        let key_exchange = client_dh_share.as_ref().map(|share| key_exchange::respond(share, group)).transpose()?;
+
+        let state = ChallengeState {
+            user: user.to_string(),
+            credential_id: requested_credential_id.to_string(),
+            recovery_code_id: recovery_code_id.unwrap_or(0) as u32,
+            group,
+            r1: r1.clone(),
+            r2: r2.clone(),
+            c: c.clone(),
+            key_exchange: client_dh_share.clone().zip(key_exchange.clone()).map(|(client_share, (server_share, secret))| (client_share, server_share, secret)),
+            channel_binding: channel_binding.map(|channel_binding| channel_binding.to_vec()),
+            expires_at: self.clock.now() + match custody {
+                Custody::Stream => AUTHENTICATE_STREAM_DEADLINE.as_secs(),
+                _ => CHALLENGE_TOKEN_LIFETIME.as_secs(),
+            },
+        };
+
+        // With challenge tokens, the client gets the sealed commitment and challenge as the auth_id, otherwise the id of
+        // this challenge, so that two logins of the user in flight do not overwrite each other
+        let challenge_id = match (custody, &self.challenge_tokens) {
+            (Custody::Token, Some(challenge_tokens)) => challenge_tokens.seal(&state),
+            _ => random_string(CHALLENGE_ID_LENGTH),
+        };
+
+        let transcript = ChallengeTranscript {
+            user,
+            credential_id: requested_credential_id,
+            recovery_code_id: recovery_code_id.unwrap_or(0) as u32,
+            group,
+            r1: &r1,
+            r2: &r2,
+            auth_id: &challenge_id,
+            c: &c,
+            dh_shares: client_dh_share.as_ref().zip(key_exchange.as_ref().map(|(share, _)| share)),
+            channel_binding,
+        }.encode();
+        let signature = self.identity.sign(&transcript);
+
+        // The commitment and the challenge are stored together or not at all
+        let mut transaction = pool.begin().await.expect("Transaction start error");
+
+        // Remember the commitment, unless it was already seen: answering it would let the client give away x
+        let now = self.clock.now() as i64;
+        timed("delete_seen_commitments", sqlx::query(
+            "delete from seen_commitment where auth_id = ($1) and seen_at < ($2)")
+            .bind(&auth_id)
+            .bind(now - COMMITMENT_REUSE_WINDOW.as_secs() as i64)
+            .execute(&mut transaction))
+            .await
+            .expect("Seen commitments deletion error");
+        let first_seen = timed("insert_seen_commitment", sqlx::query(
+            "insert into seen_commitment (auth_id, fingerprint, seen_at) values ($1, $2, $3)
+            on conflict (auth_id, fingerprint) do nothing")
+            .bind(&auth_id)
+            .bind(commitment_fingerprint(&r1, &r2, group))
+            .bind(now)
+            .execute(&mut transaction))
+            .await
+            .expect("Seen commitment insertion error")
+            .rows_affected() == 1;
+        if !first_seen {
+            audit::record(&mut transaction, &auth_id, credential_id, audit::COMMITMENT_REUSED).await;
+            transaction.commit().await.expect("Transaction commit error");
+            metrics::COMMITMENT_REUSE.inc();
+            metrics::record_outcome("CreateAuthenticationChallenge", "commitment_reused");
+            println!("{}", format!("Commitment reused by {}: the random number generator of the client is broken", auth_id).red());
+            return Err(Status::invalid_argument("The commitment was already used, pick a new random k"));
+        }
+
+        // A token or a stream holds the commitment and the challenge, nothing else is stored
+        let dh_share = key_exchange.as_ref().map(|(share, _)| share.clone());
+        if custody != Custody::Database {
+            transaction.commit().await.expect("Transaction commit error");
+            metrics::record_outcome("CreateAuthenticationChallenge", "issued");
+            return Ok(Some((Challenge { auth_id: challenge_id, c, group, signature, dh_share }, state)));
+        }
+
+        // The expired challenges of the user are not answered anymore
+        for (operation, query) in [
+            ("delete_expired_challenges", "delete from auth_challenge where challenge_id in
+                (select challenge_id from auth_commitment where auth_id = $1 and expires_at <= $2)"),
+            ("delete_expired_commitments", "delete from auth_commitment where auth_id = $1 and expires_at <= $2"),
+        ] {
+            timed(operation, sqlx::query(query)
+                .bind(&auth_id)
+                .bind(now)
+                .execute(&mut transaction))
+                .await
+                .expect("Expired challenges deletion error");
+        }
+
+        // Add the commitment into the database
+        timed("insert_commitment", sqlx::query(
+            "insert into auth_commitment (challenge_id, auth_id, r1, r2, credential_id, recovery_code_id, dh_secret, dh_transcript_hash, channel_binding, expires_at)
+            values ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)")
+            .bind(&challenge_id)
+            .bind(&auth_id)
+            .bind(encode_hex(&r1))
+            .bind(encode_hex(&r2))
+            .bind(credential_id)
+            .bind(recovery_code_id)
+            .bind(key_exchange.as_ref().map(|(_, secret)| encode_hex(secret)))
+            .bind(key_exchange.as_ref().map(|_| key_exchange::transcript_hash(&transcript)))
+            .bind(channel_binding)
+            .bind(now + CHALLENGE_LIFETIME.as_secs() as i64)
+            .execute(&mut transaction))
+            .await
+            .expect("Commitment insertion error");
+
+        // Add the challenge into the database
+        timed("insert_challenge", sqlx::query(
+            "insert into auth_challenge (challenge_id, auth_id, c) values ($1, $2, $3)")
+            .bind(&challenge_id)
+            .bind(&auth_id)
+            .bind(encode_hex(&c))
+            .execute(&mut transaction))
+            .await
+            .expect("Challenge insertion error");
+
+        transaction.commit().await.expect("Transaction commit error");
+
+        metrics::record_outcome("CreateAuthenticationChallenge", "issued");
+        refresh_outstanding_challenges(pool).await;
+
+        Ok(Some((Challenge { auth_id: challenge_id, c, group, signature, dh_share }, state)))
+    }
+
+    // Check the answer s to the challenge identified by auth_id. Opens a new session, unless the user enrolled a TOTP
+    // second factor: then the login waits for a code sent with verify_second_factor.
+    // channel_binding is the TLS exporter of the connection of the request, None without TLS.
+    pub async fn verify_answer(&self, auth_id: &str, s: WireNumber<'_>, channel_binding: Option<&[u8]>) -> Result<Authentication, Status> {
+
+        // Reading and deleting the commitment and the challenge happen in one transaction, so that a verification
+        // interrupted halfway (e.g. at shutdown) leaves them untouched
+        let mut transaction = self.pool.begin().await.expect("Transaction start error");
+        let proof = self.take_pending_proof(&mut transaction, auth_id, channel_binding).await?.credential_proof()?;
+        transaction.commit().await.expect("Transaction commit error");
+        refresh_outstanding_challenges(&self.pool).await;
+
+        self.log_in(proof, s).await
+    }
+
+    // Check the answer s to the challenge of an Authenticate stream, issued as auth_id with state
+    pub async fn verify_stream_answer(&self, auth_id: &str, state: ChallengeState, s: WireNumber<'_>, channel_binding: Option<&[u8]>) -> Result<Authentication, Status> {
+        let mut transaction = self.pool.begin().await.expect("Transaction start error");
+        let proof = take_proof_of_state(&mut transaction, auth_id, state, channel_binding, self.clock.now()).await?.credential_proof()?;
+        transaction.commit().await.expect("Transaction commit error");
+
+        self.log_in(proof, s).await
+    }
+
+    // Check the answer s of a login and open the session, or wait for the second factor
+    async fn log_in(&self, proof: PendingProof, s: WireNumber<'_>) -> Result<Authentication, Status> {
+        let auth_id = proof.auth_id.as_str();
+        let s = s.decode_exponent("s", proof.group)?;
+
+        // Verify if the calculated parts have the expected values
+        match proof.check(&proof.c, &s) {
+            true => {
+                // The keys of the key exchange are derived over the whole transcript, answer included
+                let keys = proof.key_exchange.as_ref().map(|pending| key_exchange::derive(pending, &s, proof.group));
+                let (session_key, key_confirmation) = match keys {
+                    Some(keys) => (Some(keys.session_key), Some(keys.key_confirmation)),
+                    None => (None, None),
+                };
+
+                let totp_confirmed = timed("select_totp", sqlx::query(
+                    "select exists(select 1 from totp where auth_id = ($1) and confirmed)")
+                    .bind(auth_id)
+                    .fetch_one(&self.pool))
+                    .await
+                    .expect("Error retrieving TOTP")
+                    .get::<bool, usize>(0);
+
+                if totp_confirmed {
+                    let token = random_string(SESSION_ID_LENGTH);
+                    timed("insert_second_factor", sqlx::query(
+                        "insert into second_factor (token, auth_id, credential_id, expires_at, session_key) values ($1, $2, $3, $4, $5)")
+                        .bind(&token)
+                        .bind(auth_id)
+                        .bind(&proof.credential_id)
+                        .bind((self.clock.now() + SECOND_FACTOR_TIMEOUT.as_secs()) as i64)
+                        .bind(&session_key)
+                        .execute(&self.pool))
+                        .await
+                        .expect("Second factor insertion error");
+
+                    metrics::record_outcome("VerifyAuthentication", "second_factor_required");
+                    println!("{}", "Authentication successful, waiting for the second factor".green());
+                    return Ok(Authentication::SecondFactorRequired { token, key_confirmation });
+                }
+
+                let session = open_session(&mut self.pool.acquire().await.expect("Connection error"), auth_id, &proof.credential_id, session_key).await;
+                refresh_active_sessions(&self.pool).await;
+
+                metrics::record_outcome("VerifyAuthentication", "success");
+                println!("{}", "Authentication successful!".green());
+                Ok(Authentication::Authenticated { session, key_confirmation })
+            }
+            false => {
+                metrics::record_outcome("VerifyAuthentication", "failure");
+                println!("{}", "Authentication FAILED!".red());
+                Ok(Authentication::WrongCredentials)
+            }
+        }
+    }
+
+    // The ring of the key of the credential of the session, in ascending order. The keys registered in its group are
+    // ordered by their MD5 and split into max(1, count / RING_SIZE) consecutive rings whose sizes differ by at most one:
+    // RING_SIZE to 2 * RING_SIZE - 1 keys each, or all the keys when there are fewer than 2 * RING_SIZE. Every
+    // member of a ring gets the same ring, and a user only ever sees the keys of one ring: the keys are not salted, the
+    // whole directory would be a dictionary attack on every password at once.
+    pub async fn ring(&self, session_id: &str) -> Result<Ring, Status> {
+        let (auth_id, credential_id) = self.session_user(session_id).await?;
+
+        let credential = timed("select_ring_credential", sqlx::query(
+            "select y1, group_id from register_request where auth_id = ($1) and credential_id = ($2)")
+            .bind(&auth_id)
+            .bind(&credential_id)
+            .fetch_optional(&self.pool))
+            .await
+            .map_err(|error| Status::internal(format!("Error retrieving the credential: {}", error)))?
+            .map(|row| (row.get::<String, usize>(0), row.get::<String, usize>(1)))
+            .ok_or_else(|| Status::not_found("The credential of the session was revoked"))?;
+        let (y1, group) = (credential.0, registered_group(&credential.1)?);
+
+        let mut members = timed("select_ring", sqlx::query(
+            "with keys as (select distinct y1 from register_request where group_id = ($1)),
+            rings as (select y1, ntile(greatest(1, (select count(*) from keys) / ($2))::int) over (order by md5(y1)) as ring from keys)
+            select y1 from rings where ring = (select ring from rings where y1 = ($3))")
+            .bind(group.id)
+            .bind(RING_SIZE as i64)
+            .bind(&y1)
+            .fetch_all(&self.pool))
+            .await
+            .map_err(|error| Status::internal(format!("Error retrieving the ring: {}", error)))?
+            .iter()
+            .map(|row| BigInt::parse_bytes(row.get::<String, usize>(0).as_bytes(), 16)
+                .ok_or_else(|| Status::internal("A stored y1 is not hexadecimal")))
+            .collect::<Result<Vec<BigInt>, Status>>()?;
+        members.sort();
+
+        metrics::record_outcome("GetRing", "issued");
+        Ok(Ring { group, members })
+    }
+
+    // Create the challenge of an anonymous login for the commitments r_i made for the keys of ring. The ring holds
+    // RING_MIN_SIZE to RING_MAX_SIZE distinct keys registered in the group, in ascending order. The returned state is kept
+    // by the AuthenticateAnonymously stream until the answer.
+    // Under load, the request must carry the solution of a puzzle for an empty user (see puzzle.rs).
+    pub async fn create_ring_challenge(&self, group_id: &str, ring: &[WireNumber<'_>], commitments: &[WireNumber<'_>], puzzle: Option<Solution<'_>>, channel_binding: Option<&[u8]>) -> Result<(RingChallenge, RingState), Status> {
+
+        // The caller is not authenticated: the sizes are checked before anything else, and the costly subgroup checks
+        // of the commitments come last
+        if ring.len() < RING_MIN_SIZE || ring.len() > RING_MAX_SIZE {
+            metrics::record_outcome("AuthenticateAnonymously", "ring_size");
+            return Err(Status::invalid_argument(format!("A ring has {} to {} keys", RING_MIN_SIZE, RING_MAX_SIZE)));
+        }
+        if commitments.len() != ring.len() {
+            return Err(Status::invalid_argument("A ring needs one commitment per key"));
+        }
+
+        // Under load, nothing is read before the puzzle is solved
+        self.puzzles.admit("", puzzle)?;
+
+        let group = groups::find(group_id).ok_or_else(|| Status::invalid_argument(format!("Unknown group {}", group_id)))?;
+        // The keys must be registered keys, which are in the subgroup
+        let ring = ring.iter().map(|y1| y1.decode_nonzero("ring key", group)).collect::<Result<Vec<BigInt>, Status>>()?;
+        // One order for a given set of keys, so that the order tells nothing about the client
+        if ring.windows(2).any(|keys| keys[0] >= keys[1]) {
+            return Err(Status::invalid_argument("The keys of the ring are not in ascending order without duplicates"));
+        }
+
+        let registered = timed("count_ring_members", sqlx::query(
+            "select count(distinct y1) from register_request where group_id = ($1) and y1 = any($2)")
+            .bind(group.id)
+            .bind(ring.iter().map(encode_hex).collect::<Vec<String>>())
+            .fetch_one(&self.pool))
+            .await
+            .expect("Error counting ring members")
+            .get::<i64, usize>(0);
+        if registered as usize != ring.len() {
+            metrics::record_outcome("AuthenticateAnonymously", "unknown_ring_member");
+            return Err(Status::failed_precondition(format!("A key of the ring is not registered in {}", group.id)));
+        }
+        let commitments = commitments.iter().map(|r| r.decode_element("commitment", group)).collect::<Result<Vec<BigInt>, Status>>()?;
+
+        let c = random_big_int(Zero::zero(), challenge_space(group));
+        let transcript = RingTranscript { group, ring: &ring, commitments: &commitments, c: &c, channel_binding }.encode();
+        let signature = self.identity.sign(&transcript);
+
+        // Over TLS, the answer is computed for the challenge bound to the connection
+        let bound = match channel_binding {
+            Some(channel_binding) => channel_bound_challenge(&c, channel_binding, group),
+            None => c.clone(),
+        };
+
+        metrics::record_outcome("AuthenticateAnonymously", "issued");
+        Ok((RingChallenge { c, group, signature }, RingState { group, ring, commitments, c: bound }))
+    }
+
+    // Check the answer of an anonymous login: the challenges c_i and the answers s_i for the keys of the ring. Opens a
+    // session tied to no user, None if the answer is wrong.
+    pub async fn verify_ring_answer(&self, state: RingState, challenges: &[WireNumber<'_>], responses: &[WireNumber<'_>]) -> Result<Option<Session>, Status> {
+        let group = state.group;
+        if challenges.len() != state.ring.len() || responses.len() != state.ring.len() {
+            return Err(Status::invalid_argument("A ring answer has one challenge and one answer per key"));
+        }
+        let answers = challenges
+            .iter()
+            .zip(responses)
+            .map(|(c, s)| Ok((c.decode_exponent("c_i", group)?, s.decode_exponent("s_i", group)?)))
+            .collect::<Result<Vec<(BigInt, BigInt)>, Status>>()?;
+
+        let proof = AnyOf(state.ring.iter().map(|y1| Schnorr::new(group, y1)).collect());
+        if !proof.verify(&state.commitments, &state.c, &answers) {
+            metrics::record_outcome("AuthenticateAnonymously", "failure");
+            println!("{}", "Anonymous authentication FAILED!".red());
+            return Ok(None);
+        }
+
+        let session = open_session(&mut self.pool.acquire().await.expect("Connection error"), ANONYMOUS_AUTH_ID, ANONYMOUS_AUTH_ID, None).await;
+        refresh_active_sessions(&self.pool).await;
+        metrics::record_outcome("AuthenticateAnonymously", "success");
+        println!("{}", format!("Anonymous authentication successful with a ring of {} keys!", state.ring.len()).green());
+        Ok(Some(session))
+    }
+
+    // Replace y1 and y2 of the credential proven by the user by new_y1 and new_y2. The user proves the knowledge of the old x
+    // by answering the challenge identified by auth_id, with the challenge bound to the new public key (see bound_challenge).
+    // The public key is replaced and every session of the credential deleted in one transaction. Returns false if the answer is wrong.
+    pub async fn change_password(&self, auth_id: &str, s: WireNumber<'_>, new_y1: WireNumber<'_>, new_y2: WireNumber<'_>, channel_binding: Option<&[u8]>) -> Result<bool, Status> {
+
+        let mut transaction = self.pool.begin().await.expect("Transaction start error");
+        let proof = self.take_pending_proof(&mut transaction, auth_id, channel_binding).await?.credential_proof()?;
+        let auth_id = proof.auth_id.as_str();
+        let group = proof.group;
+
+        let new_y1 = new_y1.decode_element("new_y1", group)?;
+        let new_y2 = new_y2.decode_element("new_y2", group)?;
+        let s = s.decode_exponent("s", group)?;
+
+        let c = bound_challenge(CHANGE_PASSWORD_DOMAIN, &proof.c, &[&new_y1, &new_y2], group);
+        if !proof.check(&c, &s) {
+            // The challenge is consumed even though the password is not changed
+            transaction.commit().await.expect("Transaction commit error");
+            refresh_outstanding_challenges(&self.pool).await;
+
+            metrics::record_outcome("ChangePassword", "failure");
+            println!("{}", "Password change FAILED!".red());
+            return Ok(false);
+        }
+
+        timed("update_user", sqlx::query(
+            "update register_request set y1 = $3, y2 = $4 where auth_id = $1 and credential_id = $2")
+            .bind(auth_id)
+            .bind(&proof.credential_id)
+            .bind(encode_hex(&new_y1))
+            .bind(encode_hex(&new_y2))
+            .execute(&mut transaction))
+            .await
+            .expect("User update error");
+
+        // The sessions and the logins waiting for the second factor opened with the old password are no longer valid
+        for (operation, query) in [
+            ("delete_sessions", "delete from session where auth_id = $1 and credential_id = $2"),
+            ("delete_second_factors", "delete from second_factor where auth_id = $1 and credential_id = $2"),
+        ] {
+            timed(operation, sqlx::query(query)
+                .bind(auth_id)
+                .bind(&proof.credential_id)
+                .execute(&mut transaction))
+                .await
+                .expect("Error deleting sessions");
+        }
+
+        audit::record(&mut transaction, auth_id, &proof.credential_id, audit::PASSWORD_CHANGED).await;
+
+        transaction.commit().await.expect("Transaction commit error");
+        refresh_outstanding_challenges(&self.pool).await;
+        refresh_active_sessions(&self.pool).await;
+
+        metrics::record_outcome("ChangePassword", "changed");
+        println!("{}", "Password changed!".green());
+        Ok(true)
+    }
+
+    // Delete the account of the user with all its credentials. The user proves the knowledge of x of any credential by answering the challenge identified by auth_id,
+    // with the challenge bound to the deletion (see bound_challenge), so that a login answer cannot be used to delete the account.
+    // The public key, the pending commitment and challenge and the sessions are deleted in one transaction. Returns false if the answer is wrong.
+    pub async fn unregister(&self, auth_id: &str, s: WireNumber<'_>, channel_binding: Option<&[u8]>) -> Result<bool, Status> {
+
+        let mut transaction = self.pool.begin().await.expect("Transaction start error");
+        let proof = self.take_pending_proof(&mut transaction, auth_id, channel_binding).await?.credential_proof()?;
+        let auth_id = proof.auth_id.as_str();
+        let s = s.decode_exponent("s", proof.group)?;
+
+        let c = bound_challenge(UNREGISTER_DOMAIN, &proof.c, &[], proof.group);
+        if !proof.check(&c, &s) {
+            // The challenge is consumed even though the account is not deleted
+            transaction.commit().await.expect("Transaction commit error");
+            refresh_outstanding_challenges(&self.pool).await;
+
+            metrics::record_outcome("Unregister", "failure");
+            println!("{}", "Unregistration FAILED!".red());
+            return Ok(false);
+        }
+
+        for (operation, query) in [
+            ("delete_user", "delete from register_request where auth_id = $1"),
+            ("delete_recovery_codes", "delete from recovery_code where auth_id = $1"),
+            ("delete_commitment", "delete from auth_commitment where auth_id = $1"),
+            ("delete_challenge", "delete from auth_challenge where auth_id = $1"),
+            ("delete_sessions", "delete from session where auth_id = $1"),
+            ("delete_second_factors", "delete from second_factor where auth_id = $1"),
+            ("delete_totp", "delete from totp where auth_id = $1"),
+            // Without its row, an outstanding challenge token of the user can no longer be answered
+            ("delete_seen_commitments", "delete from seen_commitment where auth_id = $1"),
+        ] {
+            timed(operation, sqlx::query(query)
+                .bind(auth_id)
+                .execute(&mut transaction))
+                .await
+                .expect("Account deletion error");
+        }
+
+        audit::record(&mut transaction, auth_id, &proof.credential_id, audit::UNREGISTERED).await;
+
+        transaction.commit().await.expect("Transaction commit error");
+        refresh_outstanding_challenges(&self.pool).await;
+        refresh_active_sessions(&self.pool).await;
+
+        metrics::record_outcome("Unregister", "unregistered");
+        println!("{}", "Account deleted!".green());
+        Ok(true)
+    }
+
+    // Check the TOTP code of a login waiting for the second factor. Returns the new session, or None if the code is wrong.
+    // The login is dropped after MAX_SECOND_FACTOR_ATTEMPTS wrong codes or SECOND_FACTOR_TIMEOUT.
+    pub async fn verify_second_factor(&self, token: &str, code: &str) -> Result<Option<Session>, Status> {
+
+        let now = self.clock.now() as i64;
+        let mut transaction = self.pool.begin().await.expect("Transaction start error");
+
+        let pending = timed("select_second_factor", sqlx::query(
+            "select auth_id, credential_id, expires_at, session_key from second_factor where token = ($1) for update")
+            .bind(token)
+            .fetch_optional(&mut transaction))
+            .await
+            .expect("Error retrieving second factor");
+        let (auth_id, credential_id, session_key) = match pending {
+            Some(row) if row.get::<i64, usize>(2) >= now => (row.get::<String, usize>(0), row.get::<String, usize>(1), row.get::<Option<Vec<u8>>, usize>(3)),
+            _ => {
+                metrics::record_outcome("VerifySecondFactor", "unknown_token");
+                return Err(Status::unauthenticated("Unknown or expired second factor token, please login again"));
+            }
+        };
+
+        let enrolled = timed("select_totp", sqlx::query(
+            "select secret, last_step from totp where auth_id = ($1) and confirmed for update")
+            .bind(&auth_id)
+            .fetch_one(&mut transaction))
+            .await
+            .expect("Error retrieving TOTP");
+
+        let step = match totp::verify(&enrolled.get::<String, usize>(0), code, now as u64, enrolled.get::<i64, usize>(1)) {
+            Some(step) => step,
+            None => {
+                // Count the wrong code, and drop the login once there were too many
+                timed("update_second_factor", sqlx::query(
+                    "update second_factor set attempts = attempts + 1 where token = $1")
+                    .bind(token)
+                    .execute(&mut transaction))
+                    .await
+                    .expect("Second factor update error");
+                timed("delete_second_factor", sqlx::query(
+                    "delete from second_factor where token = $1 and attempts >= $2")
+                    .bind(token)
+                    .bind(MAX_SECOND_FACTOR_ATTEMPTS)
+                    .execute(&mut transaction))
+                    .await
+                    .expect("Error deleting second factor");
+                transaction.commit().await.expect("Transaction commit error");
+
+                metrics::record_outcome("VerifySecondFactor", "failure");
+                println!("{}", "Second factor FAILED!".red());
+                return Ok(None);
+            }
+        };
+
+        timed("update_totp", sqlx::query(
+            "update totp set last_step = $2 where auth_id = $1")
+            .bind(&auth_id)
+            .bind(step)
+            .execute(&mut transaction))
+            .await
+            .expect("TOTP update error");
+        timed("delete_second_factor", sqlx::query(
+            "delete from second_factor where token = $1")
+            .bind(token)
+            .execute(&mut transaction))
+            .await
+            .expect("Error deleting second factor");
+
+        let session = open_session(&mut transaction, &auth_id, &credential_id, session_key).await;
+        transaction.commit().await.expect("Transaction commit error");
+        refresh_active_sessions(&self.pool).await;
+
+        metrics::record_outcome("VerifySecondFactor", "success");
+        println!("{}", "Authentication successful!".green());
+        Ok(Some(session))
+    }
+
+    // Start the TOTP enrollment of the user of the session. Returns the base32 secret to load in an authenticator app.
+    // The second factor is only required once a code has been confirmed with confirm_totp.
+    pub async fn enroll_totp(&self, session_id: &str) -> Result<String, Status> {
+
+        let (auth_id, _) = self.session_user(session_id).await?;
+        let secret = totp::generate_secret();
+
+        // A confirmed second factor cannot be replaced, or a stolen session would be enough to remove it
+        let stored = timed("upsert_totp", sqlx::query(
+            "insert into totp (auth_id, secret) values ($1, $2)
+            on conflict (auth_id) do update set secret = $2, last_step = -1 where not totp.confirmed")
+            .bind(&auth_id)
+            .bind(&secret)
+            .execute(&self.pool))
+            .await
+            .expect("TOTP insertion error")
+            .rows_affected();
+
+        if stored == 0 {
+            metrics::record_outcome("EnrollTotp", "already_enrolled");
+            return Err(Status::already_exists("A second factor is already enrolled"));
+        }
+
+        metrics::record_outcome("EnrollTotp", "started");
+        Ok(secret)
+    }
+
+    // Confirm the TOTP enrollment of the user of the session with a first code. Returns false if the code is wrong.
+    pub async fn confirm_totp(&self, session_id: &str, code: &str) -> Result<bool, Status> {
+
+        let (auth_id, credential_id) = self.session_user(session_id).await?;
+        let mut transaction = self.pool.begin().await.expect("Transaction start error");
+
+        let enrolled = timed("select_totp", sqlx::query(
+            "select secret, last_step from totp where auth_id = ($1) and not confirmed for update")
+            .bind(&auth_id)
+            .fetch_optional(&mut transaction))
+            .await
+            .expect("Error retrieving TOTP")
+            .ok_or_else(|| Status::failed_precondition("No second factor enrollment is pending"))?;
+
+        let step = match totp::verify(&enrolled.get::<String, usize>(0), code, self.clock.now(), enrolled.get::<i64, usize>(1)) {
+            Some(step) => step,
+            None => {
+                metrics::record_outcome("ConfirmTotp", "failure");
+                return Ok(false);
+            }
+        };
+
+        timed("update_totp", sqlx::query(
+            "update totp set confirmed = true, last_step = $2 where auth_id = $1")
+            .bind(&auth_id)
+            .bind(step)
+            .execute(&mut transaction))
+            .await
+            .expect("TOTP update error");
+
+        audit::record(&mut transaction, &auth_id, &credential_id, audit::TOTP_ENROLLED).await;
+        transaction.commit().await.expect("Transaction commit error");
+
+        metrics::record_outcome("ConfirmTotp", "confirmed");
+        println!("{}", "Second factor enrolled!".green());
+        Ok(true)
+    }
+
+    // Recover the account of a user who lost the password. The user proves the knowledge of the x of a recovery code by
+    // answering the challenge identified by auth_id, with the challenge bound to the public key new_y1, new_y2 of the new
+    // password (see bound_challenge). In one transaction the recovery code is consumed, the credential credential_id gets
+    // the new public key (it is created if it does not exist) and every session of the user is deleted. No session is
+    // opened: the user logs in with the new password. Returns the number of remaining recovery codes, or None if the answer is wrong.
+    pub async fn recover_account(&self, auth_id: &str, s: WireNumber<'_>, credential_id: &str, new_y1: WireNumber<'_>, new_y2: WireNumber<'_>, channel_binding: Option<&[u8]>) -> Result<Option<i64>, Status> {
+
+        let credential_id = credential_or_default(credential_id)?;
+
+        let mut transaction = self.pool.begin().await.expect("Transaction start error");
+        let proof = self.take_pending_proof(&mut transaction, auth_id, channel_binding).await?;
+        let auth_id = proof.auth_id.as_str();
+        let recovery_code_id = proof.recovery_code_id
+            .ok_or_else(|| Status::failed_precondition("The challenge was not issued for a recovery code"))?;
+        let group = proof.group;
+
+        let new_y1 = new_y1.decode_element("new_y1", group)?;
+        let new_y2 = new_y2.decode_element("new_y2", group)?;
+        let s = s.decode_exponent("s", group)?;
+
+        let c = bound_challenge(RECOVERY_DOMAIN, &proof.c, &[&new_y1, &new_y2], group);
+        if !proof.check(&c, &s) {
+            // The challenge is consumed but the recovery code is kept
+            transaction.commit().await.expect("Transaction commit error");
+            refresh_outstanding_challenges(&self.pool).await;
+
+            metrics::record_outcome("RecoverAccount", "failure");
+            println!("{}", "Recovery FAILED!".red());
+            return Ok(None);
+        }
+
+        // A recovery code is used only once
+        timed("delete_recovery_code", sqlx::query(
+            "delete from recovery_code where auth_id = $1 and code_id = $2")
+            .bind(auth_id)
+            .bind(recovery_code_id)
+            .execute(&mut transaction))
+            .await
+            .expect("Error deleting recovery code");
+
+        timed("upsert_credential", sqlx::query(
+            "insert into register_request (auth_id, credential_id, y1, y2, group_id) values ($1, $2, $3, $4, $5)
+            on conflict (auth_id, credential_id) do update set y1 = $3, y2 = $4, group_id = $5")
+            .bind(auth_id)
+            .bind(credential_id)
+            .bind(encode_hex(&new_y1))
+            .bind(encode_hex(&new_y2))
+            .bind(group.id)
+            .execute(&mut transaction))
+            .await
+            .expect("Credential update error");
+
+        // Whoever knew the lost password no longer has a session, nor a login waiting for the second factor
+        for (operation, query) in [
+            ("delete_sessions", "delete from session where auth_id = $1"),
+            ("delete_second_factors", "delete from second_factor where auth_id = $1"),
+        ] {
+            timed(operation, sqlx::query(query)
+                .bind(auth_id)
+                .execute(&mut transaction))
+                .await
+                .expect("Error deleting sessions");
+        }
+
+        let remaining = timed("count_recovery_codes", sqlx::query(
+            "select count(*) from recovery_code where auth_id = $1")
+            .bind(auth_id)
+            .fetch_one(&mut transaction))
+            .await
+            .expect("Error counting recovery codes")
+            .get::<i64, usize>(0);
+
+        audit::record(&mut transaction, auth_id, credential_id, audit::RECOVERED).await;
+
+        transaction.commit().await.expect("Transaction commit error");
+        refresh_outstanding_challenges(&self.pool).await;
+        refresh_active_sessions(&self.pool).await;
+
+        metrics::record_outcome("RecoverAccount", "recovered");
+        println!("{}", "Account recovered!".green());
+        Ok(Some(remaining))
+    }
+
+    // Enroll a new credential credential_id for the user of the session, with its own public key y1, y2 in the group group_id.
+    // An empty group id means the legacy group, as in register_user.
+    pub async fn add_credential(&self, session_id: &str, credential_id: &str, group_id: &str, y1: WireNumber<'_>, y2: WireNumber<'_>) -> Result<(), Status> {
+
+        let (auth_id, _) = self.session_user(session_id).await?;
+        let credential_id = credential_or_default(credential_id)?;
+        let group_id = group_or_legacy(group_id);
+        let group = groups::find(group_id).ok_or_else(|| Status::invalid_argument(format!("Unknown group {}", group_id)))?;
+        let y1 = y1.decode_element("y1", group)?;
+        let y2 = y2.decode_element("y2", group)?;
+
+        let mut transaction = self.pool.begin().await.expect("Transaction start error");
+
+        let inserted = timed("insert_credential", sqlx::query(
+            "insert into register_request (auth_id, credential_id, y1, y2, group_id) values ($1, $2, $3, $4, $5)
+            on conflict (auth_id, credential_id) do nothing")
+            .bind(&auth_id)
+            .bind(credential_id)
+            .bind(encode_hex(&y1))
+            .bind(encode_hex(&y2))
+            .bind(group.id)
+            .execute(&mut transaction))
+            .await
+            .expect("Credential insertion error")
+            .rows_affected();
+
+        if inserted == 0 {
+            metrics::record_outcome("AddCredential", "already_exists");
+            return Err(Status::already_exists(format!("The credential {} already exists", credential_id)));
+        }
+
+        audit::record(&mut transaction, &auth_id, credential_id, audit::CREDENTIAL_ADDED).await;
+        transaction.commit().await.expect("Transaction commit error");
+
+        metrics::record_outcome("AddCredential", "added");
+        println!("{}", format!("Credential {} added!", credential_id).green());
+        Ok(())
+    }
+
+    // The credentials of the user of the session
+    pub async fn list_credentials(&self, session_id: &str) -> Result<Vec<Credential>, Status> {
+
+        let (auth_id, _) = self.session_user(session_id).await?;
+
+        let credentials = timed("select_credentials", sqlx::query(
+            "select credential_id, group_id from register_request where auth_id = ($1) order by credential_id")
+            .bind(&auth_id)
+            .fetch_all(&self.pool))
+            .await
+            .expect("Error retrieving credentials")
+            .iter()
+            .map(|row| Credential {
+                credential_id: row.get::<String, usize>(0),
+                group_id: row.get::<String, usize>(1),
+            })
+            .collect();
+
+        Ok(credentials)
+    }
+
+    // Revoke the credential credential_id of the user of the session. The credential, its sessions and its pending
+    // commitment are deleted in one transaction. The last credential cannot be revoked: the account is deleted with unregister.
+    pub async fn revoke_credential(&self, session_id: &str, credential_id: &str) -> Result<(), Status> {
+
+        let (auth_id, _) = self.session_user(session_id).await?;
+        let credential_id = credential_or_default(credential_id)?;
+
+        let mut transaction = self.pool.begin().await.expect("Transaction start error");
+
+        // Lock the credentials of the user, so that two revocations cannot remove the last two credentials together
+        let credential_ids = timed("select_credentials", sqlx::query(
+            "select credential_id from register_request where auth_id = ($1) for update")
+            .bind(&auth_id)
+            .fetch_all(&mut transaction))
+            .await
+            .expect("Error retrieving credentials")
+            .iter()
+            .map(|row| row.get::<String, usize>(0))
+            .collect::<Vec<String>>();
+
+        if !credential_ids.iter().any(|id| id == credential_id) {
+            metrics::record_outcome("RevokeCredential", "not_found");
+            return Err(Status::not_found(format!("Unknown credential {}", credential_id)));
+        }
+        if credential_ids.len() == 1 {
+            metrics::record_outcome("RevokeCredential", "last_credential");
+            return Err(Status::failed_precondition("The last credential cannot be revoked, delete the account instead"));
+        }
+
+        for (operation, query) in [
+            ("delete_challenge", "delete from auth_challenge where challenge_id in
+                (select challenge_id from auth_commitment where auth_id = $1 and credential_id = $2)"),
+            ("delete_commitment", "delete from auth_commitment where auth_id = $1 and credential_id = $2"),
+            ("delete_sessions", "delete from session where auth_id = $1 and credential_id = $2"),
+            ("delete_second_factors", "delete from second_factor where auth_id = $1 and credential_id = $2"),
+            ("delete_credential", "delete from register_request where auth_id = $1 and credential_id = $2"),
+        ] {
+            timed(operation, sqlx::query(query)
+                .bind(&auth_id)
+                .bind(credential_id)
+                .execute(&mut transaction))
+                .await
+                .expect("Credential deletion error");
+        }
+
+        audit::record(&mut transaction, &auth_id, credential_id, audit::CREDENTIAL_REVOKED).await;
+
+        transaction.commit().await.expect("Transaction commit error");
+        refresh_outstanding_challenges(&self.pool).await;
+        refresh_active_sessions(&self.pool).await;
+
+        metrics::record_outcome("RevokeCredential", "revoked");
+        println!("{}", format!("Credential {} revoked!", credential_id).green());
+        Ok(())
+    }
+
+    // The group a client should use.
+    // A registered user gets the group of the credential, or of the recovery codes if recovery is set. Without a user, the
+    // requested group, or the default group for new registrations.
+    // The answer must not tell who is registered: with a user, the requested group is ignored, and a user without such a
+    // credential or recovery codes gets the group of a decoy. The decoy is the first registered auth_id from a keyed hash
+    // of the request, so the answer is the same on every request, and the groups of unknown users are spread like the
+    // groups of registered ones.
+    pub async fn public_parameters(&self, group_id: &str, user: &str, credential_id: &str, recovery: bool) -> Result<&'static Group, Status> {
+
+        let credential_id = credential_or_default(credential_id)?;
+        if user.is_empty() {
+            let group_id = match group_id {
+                "" => DEFAULT_GROUP_ID,
+                group_id => group_id,
+            };
+            return groups::find(group_id)
+                .ok_or_else(|| Status::not_found(format!("Unknown group {}", group_id)));
+        }
+
+        let request = format!("{}\0{}\0{}", user, credential_id, recovery);
+        let decoy = u64::from_be_bytes(self.identity.keyed_hash(DECOY_DOMAIN, request.as_bytes())[..8].try_into().unwrap());
+        // The decoys wrap around to the first auth_id
+        let query = if recovery {
+            "select coalesce(
+                (select group_id from recovery_code where auth_id = ($1) limit 1),
+                (select group_id from recovery_code where auth_id >= ($3) order by auth_id limit 1),
+                (select group_id from recovery_code order by auth_id limit 1))"
+        } else {
+            "select coalesce(
+                (select group_id from register_request where auth_id = ($1) and credential_id = ($2)),
+                (select group_id from register_request where auth_id >= ($3) order by auth_id limit 1),
+                (select group_id from register_request order by auth_id limit 1))"
+        };
+        let user_group_id = timed("select_group", sqlx::query(query)
+            .bind(default_hash(user).to_str_radix(16))
+            .bind(credential_id)
+            .bind(format!("{:x}", decoy))
+            .fetch_one(&self.pool))
+            .await
+            .expect("Error retrieving group")
+            .get::<Option<String>, usize>(0);
+
+        // Nobody is registered yet
+        let group_id = user_group_id.as_deref().unwrap_or(DEFAULT_GROUP_ID);
+        registered_group(group_id)
+    }
+
+    // The versions, groups, KDFs and proof modes the server supports, so that clients can pick what they speak
+    pub fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            versions: PROTOCOL_VERSIONS.to_vec(),
+            groups: groups::groups().iter().map(|group| group.id).collect(),
+            default_group_id: DEFAULT_GROUP_ID,
+            kdfs: vec![KDF],
+            proof_modes: PROOF_MODES.to_vec(),
+            key_exchanges: vec![KEY_EXCHANGE],
+        }
+    }
+
+    // The user (auth_id) and the credential of a session. Anonymous sessions have neither and are refused.
+    async fn session_user(&self, session_id: &str) -> Result<(String, String), Status> {
+        let (auth_id, credential_id) = timed("select_session", sqlx::query(
+            "select auth_id, credential_id from session where session_id = ($1)")
+            .bind(session_id)
+            .fetch_optional(&self.pool))
+            .await
+            .expect("Error retrieving session")
+            .map(|row| (row.get::<String, usize>(0), row.get::<String, usize>(1)))
+            .ok_or_else(|| Status::unauthenticated("Unknown session"))?;
+        if auth_id == ANONYMOUS_AUTH_ID {
+            return Err(Status::permission_denied("An anonymous session belongs to no user"));
+        }
+        Ok((auth_id, credential_id))
+    }
+}
+
+// The values needed to check an answer: the credential or the recovery code, its group and public key, the commitment
+// and the challenge
+struct PendingProof {
+    // The user in the database, even when the challenge was identified by a challenge token
+    auth_id: String,
+    credential_id: String,
+    recovery_code_id: Option<i32>,
+    group: &'static Group,
+    y1: BigInt,
+    y2: BigInt,
+    r1: BigInt,
+    r2: BigInt,
+    c: BigInt,
+    // Set when the client asked for a key exchange with the commitment
+    key_exchange: Option<PendingKeyExchange>,
+}
+
+impl PendingProof {
+
+    // Only a recovery accepts the answer of a challenge issued for a recovery code
+    fn credential_proof(self) -> Result<Self, Status> {
+        match self.recovery_code_id {
+            None => Ok(self),
+            Some(_) => Err(Status::failed_precondition("The challenge was issued for a recovery code")),
+        }
+    }
+
+    // Check the Chaum–Pedersen proof: g^s * y1^c = r1 and h^s * y2^c = r2 for the challenge c
+    fn check(&self, c: &BigInt, s: &BigInt) -> bool {
+        let verification_start = Instant::now();
+        let verified = ChaumPedersen::new(self.group, &self.y1, &self.y2)
+            .verify(&(self.r1.clone(), self.r2.clone()), c, s);
+        metrics::VERIFICATION_DURATION.observe(verification_start.elapsed().as_secs_f64());
+
+        println!("r1 = {}", &self.r1);
+        println!("r2 = {}", &self.r2);
+        println!("verified = {}", verified);
+
+        verified
+    }
+}
+
+impl AuthService {
+
+    // The commitment and the challenge identified by auth_id, along with the public key of the credential the commitment
+    // was made with: opened from auth_id if it is a challenge token, read from the database otherwise. Either way the
+    // challenge cannot be answered twice, and the row of the credential stays locked until the transaction ends.
+    // The answer must come on the connection of the commitment, whose TLS exporter channel_binding is (None without TLS).
+    async fn take_pending_proof(&self, transaction: &mut Transaction<'_, Postgres>, auth_id: &str, channel_binding: Option<&[u8]>) -> Result<PendingProof, Status> {
+        let now = self.clock.now();
+        match self.challenge_tokens.as_ref().and_then(|challenge_tokens| challenge_tokens.open(auth_id, now)) {
+            Some(state) => take_proof_of_state(transaction, auth_id, state?, channel_binding, now).await,
+            None => take_stored_proof(transaction, auth_id, channel_binding, now).await,
+        }
+    }
+}
+
+// Read and delete the commitment and the challenge stored with the id challenge_id
+async fn take_stored_proof(transaction: &mut Transaction<'_, Postgres>, challenge_id: &str, channel_binding: Option<&[u8]>, now: u64) -> Result<PendingProof, Status> {
+
+    // Retrieving the required parameters (user, credential or recovery code, r1, r2 and c) based on the challenge id for verification
+    let commitment = timed("select_commitment", sqlx::query(
+        "select auth_id, credential_id, recovery_code_id, expires_at, r1, r2, dh_secret, dh_transcript_hash, channel_binding
+        from auth_commitment where challenge_id = ($1)")
+        .bind(challenge_id).fetch_optional(&mut *transaction)).await
+        .map_err(|error| Status::internal(format!("Error retrieving the commitment: {}", error)))?
+    ;
+    // An unknown id, or the id of a challenge that was already answered
+    let commitment = match commitment {
+        Some(commitment) => commitment,
+        None => {
+            metrics::record_outcome("VerifyAuthentication", "unknown_challenge");
+            return Err(Status::unauthenticated("Unknown or already answered challenge"));
+        }
+    };
+    let auth_id = commitment.get::<String, usize>(0);
+    let credential_id = commitment.get::<String, usize>(1);
+    let recovery_code_id = commitment.get::<Option<i32>, usize>(2);
+    let r1 = commitment.get::<String, usize>(4);
+    let r2 = commitment.get::<String, usize>(5);
+    let dh_secret = commitment.get::<Option<String>, usize>(6);
+    let dh_transcript_hash = commitment.get::<Option<Vec<u8>>, usize>(7);
+    if commitment.get::<i64, usize>(3) <= now as i64 {
+        metrics::record_outcome("VerifyAuthentication", "challenge_expired");
+        return Err(Status::deadline_exceeded("The challenge has expired"));
+    }
+
+    // We can delete the commitment and the challenge after retrieving them because they are not going to be used anymore
+    timed("delete_commitment", sqlx::query(
+        "delete from auth_commitment where challenge_id = ($1)")
+        .bind(challenge_id).execute(&mut *transaction)).await
+        .map_err(|error| Status::internal(format!("Error deleting the commitment: {}", error)))?
+    ;
+    let c = timed("select_c", sqlx::query(
+        "select c from auth_challenge where challenge_id = ($1)")
+        .bind(challenge_id).fetch_optional(&mut *transaction)).await
+        .map_err(|error| Status::internal(format!("Error retrieving the challenge: {}", error)))?
+        .ok_or_else(|| Status::not_found("The challenge of the commitment no longer exists"))?
+        .get::<String, usize>(0)
+    ;
+    timed("delete_challenge", sqlx::query(
+        "delete from auth_challenge where challenge_id = ($1)")
+        .bind(challenge_id).execute(&mut *transaction)).await
+        .map_err(|error| Status::internal(format!("Error deleting the challenge: {}", error)))?
+    ;
+
+    // A relaying attacker answers on its own connection, not on the one the challenge was issued on
+    let commitment_channel_binding = commitment.get::<Option<Vec<u8>>, usize>(8);
+    if commitment_channel_binding.as_deref() != channel_binding {
+        metrics::record_outcome("VerifyAuthentication", "channel_binding_mismatch");
+        return Err(Status::unauthenticated("The answer does not come on the connection of the challenge"));
+    }
+
+    let (group_id, y1, y2) = select_public_key(transaction, &auth_id, &credential_id, recovery_code_id).await?;
+
+    // The verification happens in the group the user registered with
+    let group = registered_group(&group_id)?;
+
+    // Convert parameters back to BigInt. The stored ones were checked when they were received.
+    let c = WireNumber::Hex(&c).decode("c", group)?;
+    Ok(PendingProof {
+        auth_id,
+        credential_id,
+        recovery_code_id,
+        group,
+        y1: WireNumber::Hex(&y1).decode("y1", group)?,
+        y2: WireNumber::Hex(&y2).decode("y2", group)?,
+        r1: WireNumber::Hex(&r1).decode("r1", group)?,
+        r2: WireNumber::Hex(&r2).decode("r2", group)?,
+        c: match channel_binding {
+            Some(channel_binding) => channel_bound_challenge(&c, channel_binding, group),
+            None => c,
+        },
+        key_exchange: match (dh_secret, dh_transcript_hash) {
+            (Some(secret), Some(transcript_hash)) => Some(PendingKeyExchange {
+                secret: WireNumber::Hex(&secret).decode("dh_secret", group)?,
+                transcript_hash,
+            }),
+            _ => None,
+        },
+    })
+}
+
+// The commitment and the challenge of state, issued as auth_id: a challenge token, or the id sent over an Authenticate
+// stream. The answer is recorded with the fingerprint of the commitment, so that a token is only answered once.
+async fn take_proof_of_state(transaction: &mut Transaction<'_, Postgres>, challenge_id: &str, state: ChallengeState, channel_binding: Option<&[u8]>, now: u64) -> Result<PendingProof, Status> {
+
+    if state.channel_binding.as_deref() != channel_binding {
+        metrics::record_outcome("VerifyAuthentication", "channel_binding_mismatch");
+        return Err(Status::unauthenticated("The answer does not come on the connection of the challenge"));
+    }
+
+    let auth_id = default_hash(&state.user).to_str_radix(16);
+    let first_answer = timed("update_seen_commitment", sqlx::query(
+        "update seen_commitment set answered_at = $3 where auth_id = $1 and fingerprint = $2 and answered_at is null")
+        .bind(&auth_id)
+        .bind(commitment_fingerprint(&state.r1, &state.r2, state.group))
+        .bind(now as i64)
+        .execute(&mut *transaction))
+        .await
+        .expect("Seen commitment update error")
+        .rows_affected() == 1;
+    if !first_answer {
+        metrics::record_outcome("VerifyAuthentication", "challenge_replayed");
+        return Err(Status::unauthenticated("The challenge was already answered"));
+    }
+
+    let credential_id = credential_or_default(&state.credential_id)?.to_string();
+    let recovery_code_id = match state.recovery_code_id {
+        0 => None,
+        recovery_code_id => Some(recovery_code_id as i32),
+    };
+    let (group_id, y1, y2) = select_public_key(transaction, &auth_id, &credential_id, recovery_code_id).await?;
+    let group = registered_group(&group_id)?;
+    if group.id != state.group.id {
+        return Err(Status::failed_precondition("The credential changed group since the challenge was issued"));
+    }
+
+    // The key exchange is derived over the transcript signed with the challenge, challenge id included
+    let key_exchange = state.key_exchange.as_ref().map(|(client_share, server_share, secret)| {
+        let transcript = ChallengeTranscript {
+            user: &state.user,
+            credential_id: &state.credential_id,
+            recovery_code_id: state.recovery_code_id,
+            group,
+            r1: &state.r1,
+            r2: &state.r2,
+            auth_id: challenge_id,
+            c: &state.c,
+            dh_shares: Some((client_share, server_share)),
+            channel_binding,
+        }.encode();
+        PendingKeyExchange { secret: secret.clone(), transcript_hash: key_exchange::transcript_hash(&transcript) }
+    });
+
+    Ok(PendingProof {
+        y1: WireNumber::Hex(&y1).decode("y1", group)?,
+        y2: WireNumber::Hex(&y2).decode("y2", group)?,
+        c: match channel_binding {
+            Some(channel_binding) => channel_bound_challenge(&state.c, channel_binding, group),
+            None => state.c,
+        },
+        auth_id,
+        credential_id,
+        recovery_code_id,
+        group,
+        r1: state.r1,
+        r2: state.r2,
+        key_exchange,
+    })
+}
+
+// The group and the public key (y1, y2) of the credential, or of the recovery code if recovery_code_id is set, locked
+// until the transaction ends
+async fn select_public_key(transaction: &mut Transaction<'_, Postgres>, auth_id: &str, credential_id: &str, recovery_code_id: Option<i32>) -> Result<(String, String, String), Status> {
+    let key = match recovery_code_id {
+        None => timed("select_user", sqlx::query(
+            "select group_id, y1, y2 from register_request where auth_id = ($1) and credential_id = ($2) for update")
+            .bind(auth_id).bind(credential_id).fetch_optional(&mut *transaction)).await
+            .map_err(|error| Status::internal(format!("Error retrieving the credential: {}", error)))?,
+        Some(recovery_code_id) => timed("select_recovery_code", sqlx::query(
+            "select group_id, y1, y2 from recovery_code where auth_id = ($1) and code_id = ($2) for update")
+            .bind(auth_id).bind(recovery_code_id).fetch_optional(&mut *transaction)).await
+            .map_err(|error| Status::internal(format!("Error retrieving the recovery code: {}", error)))?,
+    };
+    key.map(|key| (key.get::<String, usize>(0), key.get::<String, usize>(1), key.get::<String, usize>(2)))
+        .ok_or_else(|| Status::failed_precondition("The credential of the challenge no longer exists"))
+}
+
+// The challenge answered for an operation other than a login: SHA-256 of the domain separator of the operation,
+// the challenge c issued by the server and the values of the operation (e.g. the new public key), each encoded as
+// fixed-length big-endian bytes, reduced mod q.
+// The domain separator keeps an answer for one operation from being used for another, and binding the values
+// prevents replacing them in transit.
+pub fn bound_challenge(domain: &[u8], c: &BigInt, values: &[&BigInt], group: &Group) -> BigInt {
+    let mut hasher = Sha256::new();
+    hasher.update(domain);
+    hasher.update(encode_bytes(c, group));
+    for value in values {
+        hasher.update(encode_bytes(value, group));
+    }
+    BigInt::from_bytes_be(Sign::Plus, &hasher.finalize()) % &group.q
+}
+
+// SHA-256(COMMITMENT_DOMAIN || group id || r1 || r2), the group id prefixed with its length as a 4-byte big-endian integer
+// and r1, r2 encoded as fixed-length big-endian bytes
+fn commitment_fingerprint(r1: &BigInt, r2: &BigInt, group: &Group) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.update(COMMITMENT_DOMAIN);
+    hasher.update((group.id.len() as u32).to_be_bytes());
+    hasher.update(group.id.as_bytes());
+    hasher.update(encode_bytes(r1, group));
+    hasher.update(encode_bytes(r2, group));
+    hasher.finalize().to_vec()
+}
+
+// The number of challenges: c is drawn uniformly from [0, 2^CHALLENGE_BITS), or from [0, q)
+pub fn challenge_space(group: &Group) -> BigInt {
+    match CHALLENGE_BITS {
+        0 => group.q.clone(),
+        bits => BigInt::one() << bits,
+    }
+}
+
+// Refuse a challenge length whose soundness error is too large, or which does not fit in the q of every group
+pub fn check_challenge_bits() -> Result<(), String> {
+    let bits = match CHALLENGE_BITS {
+        0 => return Ok(()),
+        bits => bits,
+    };
+    if bits < MIN_CHALLENGE_BITS {
+        return Err(format!("{}-bit challenges are too short, at least {} bits are required", bits, MIN_CHALLENGE_BITS));
+    }
+    match groups::groups().iter().find(|group| bits >= group.q.bits()) {
+        Some(group) => Err(format!("{}-bit challenges do not fit in the q of {}", bits, group.id)),
+        None => Ok(()),
+    }
+}
+
+// The challenge of a commitment received over TLS: SHA-256(CHANNEL_BINDING_DOMAIN || c || channel binding) mod q, with c
+// encoded as fixed-length big-endian bytes. An answer computed for another connection does not verify. It replaces c
+// everywhere, bound_challenge included.
+pub fn channel_bound_challenge(c: &BigInt, channel_binding: &[u8], group: &Group) -> BigInt {
+    let mut hasher = Sha256::new();
+    hasher.update(CHANNEL_BINDING_DOMAIN);
+    hasher.update(encode_bytes(c, group));
+    hasher.update(channel_binding);
+    BigInt::from_bytes_be(Sign::Plus, &hasher.finalize()) % &group.q
+}
+
+// Open a session for the credential credential_id of the user auth_id, with the key derived by the key exchange if any
+async fn open_session(connection: &mut PgConnection, auth_id: &str, credential_id: &str, session_key: Option<Vec<u8>>) -> Session {
+    let session_id = random_string(SESSION_ID_LENGTH);
+    timed("insert_session", sqlx::query(
+        "insert into session (session_id, auth_id, credential_id, session_key) values ($1, $2, $3, $4)")
+        .bind(&session_id)
+        .bind(auth_id)
+        .bind(credential_id)
+        .bind(session_key)
+        .execute(connection))
+        .await
+        .expect("Session insertion error");
+    Session { session_id, credential_id: credential_id.to_string() }
+}
+
+// The credential named in a request, DEFAULT_CREDENTIAL_ID if none
+fn credential_or_default(credential_id: &str) -> Result<&str, Status> {
+    match credential_id {
+        "" => Ok(DEFAULT_CREDENTIAL_ID),
+        credential_id if credential_id.chars().count() > MAX_CREDENTIAL_ID_LENGTH => Err(Status::invalid_argument(
+            format!("The credential name is longer than {} characters", MAX_CREDENTIAL_ID_LENGTH))),
+        credential_id => Ok(credential_id),
+    }
+}
+
+// The group a key is registered in, by register_user and add_credential alike. Clients which send no group id compute
+// their keys in the legacy group.
+fn group_or_legacy(group_id: &str) -> &str {
+    match group_id {
+        "" => groups::LEGACY_GROUP_ID,
+        group_id => group_id,
+    }
+}
+
+// Look up the group stored with a user. Only fails if the group was removed from the registry after the user registered.
+fn registered_group(group_id: &str) -> Result<&'static Group, Status> {
+    groups::find(group_id)
+        .ok_or_else(|| Status::internal(format!("The group {} of the user is not in the registry", group_id)))
+}
+
+// Set the outstanding challenges gauge to the number of challenges waiting for an answer
+async fn refresh_outstanding_challenges(pool: &PgPool) {
+    let outstanding = timed("count_challenges", sqlx::query(
+        "select count(*) from auth_challenge")
+        .fetch_one(pool))
+        .await
+        .expect("Error counting challenges")
+        .get::<i64, usize>(0)
+    ;
+    metrics::OUTSTANDING_CHALLENGES.set(outstanding);
+}
+
+// Set the active sessions gauge to the number of stored sessions
+async fn refresh_active_sessions(pool: &PgPool) {
+    let active = timed("count_sessions", sqlx::query(
+        "select count(*) from session")
+        .fetch_one(pool))
+        .await
+        .expect("Error counting sessions")
+        .get::<i64, usize>(0)
+    ;
+    metrics::ACTIVE_SESSIONS.set(active);
+}
+
+// The second factor against a FixedClock, and the groups keys are registered in. The tests marked ignore need the database of DATABASE_URL: cargo test -- --ignored
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::health;
+    use crate::parameters::{DATABASE_URL, TOTP_DIGITS, TOTP_SKEW_STEPS, TOTP_STEP};
+    use crate::totp::FixedClock;
+    use crate::zkp_utils::mod_exp;
+    use sqlx::postgres::PgPoolOptions;
+    use std::env;
+    use std::fs;
+
+    // The secret of the RFC 6238 test vectors, base32 encoded
+    const SECRET: &str = "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ";
+    const TIME: u64 = 1111111109;
+
+    fn code(step: u64) -> String {
+        format!("{:0width$}", totp::hotp(b"12345678901234567890", step, TOTP_DIGITS), width = TOTP_DIGITS as usize)
+    }
+
+    // A service stopped at TIME
+    async fn service() -> AuthService {
+        let pool = PgPoolOptions::new().connect(DATABASE_URL).await.expect("The tests need the database of DATABASE_URL");
+        health::migrate(&pool).await.expect("Database migration error");
+
+        let name = random_string(16);
+        let directory = env::temp_dir();
+        let identity = ServerKey::load_or_generate(&directory.join(&name), &directory.join(format!("{}.pub", name))).expect("Server key error");
+        fs::remove_file(directory.join(&name)).ok();
+        fs::remove_file(directory.join(format!("{}.pub", name))).ok();
+
+        AuthService::with_clock(pool, Arc::new(identity), Arc::new(FixedClock(TIME)))
+    }
+
+    // A user with a confirmed TOTP whose login waits for the second factor
+    async fn pending_login() -> (AuthService, String, String) {
+        let service = service().await;
+
+        let auth_id = random_string(16);
+        let token = random_string(SESSION_ID_LENGTH);
+        sqlx::query("insert into totp (auth_id, secret, confirmed) values ($1, $2, true)")
+            .bind(&auth_id).bind(SECRET).execute(&service.pool).await.expect("TOTP insertion error");
+        sqlx::query("insert into second_factor (token, auth_id, credential_id, expires_at) values ($1, $2, $3, $4)")
+            .bind(&token).bind(&auth_id).bind(DEFAULT_CREDENTIAL_ID).bind((TIME + SECOND_FACTOR_TIMEOUT.as_secs()) as i64)
+            .execute(&service.pool).await.expect("Second factor insertion error");
+
+        (service, auth_id, token)
+    }
+
+    async fn forget(service: &AuthService, auth_id: &str) {
+        for table in ["totp", "second_factor", "session", "register_request", "recovery_code", "seen_commitment"] {
+            sqlx::query(&format!("delete from {} where auth_id = $1", table))
+                .bind(auth_id).execute(&service.pool).await.expect("Cleanup error");
+        }
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn accepts_a_code_at_the_edge_of_the_skew_window() {
+        let (service, auth_id, token) = pending_login().await;
+        let step = TIME / TOTP_STEP + TOTP_SKEW_STEPS;
+
+        let session = service.verify_second_factor(&token, &code(step)).await.expect("Second factor error");
+        assert_eq!(session.map(|session| session.credential_id), Some(DEFAULT_CREDENTIAL_ID.to_string()));
+
+        // The login is over, and the step is used up
+        assert_eq!(service.verify_second_factor(&token, &code(step)).await.unwrap_err().code(), tonic::Code::Unauthenticated);
+        let last_step = sqlx::query("select last_step from totp where auth_id = $1")
+            .bind(&auth_id).fetch_one(&service.pool).await.unwrap().get::<i64, usize>(0);
+        assert_eq!(last_step, step as i64);
+
+        forget(&service, &auth_id).await;
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn refuses_a_code_past_the_skew_window() {
+        let (service, auth_id, token) = pending_login().await;
+
+        let late = code(TIME / TOTP_STEP + TOTP_SKEW_STEPS + 1);
+        assert!(service.verify_second_factor(&token, &late).await.expect("Second factor error").is_none());
+        let early = code(TIME / TOTP_STEP - TOTP_SKEW_STEPS - 1);
+        assert!(service.verify_second_factor(&token, &early).await.expect("Second factor error").is_none());
+
+        forget(&service, &auth_id).await;
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn drops_the_login_after_too_many_wrong_codes() {
+        let (service, auth_id, token) = pending_login().await;
+        let wrong = code(TIME / TOTP_STEP + 10);
+
+        for _ in 0..MAX_SECOND_FACTOR_ATTEMPTS {
+            assert!(service.verify_second_factor(&token, &wrong).await.expect("Second factor error").is_none());
+        }
+        // Even the right code comes too late
+        let right = code(TIME / TOTP_STEP);
+        assert_eq!(service.verify_second_factor(&token, &right).await.unwrap_err().code(), tonic::Code::Unauthenticated);
+
+        forget(&service, &auth_id).await;
+    }
+
+    #[test]
+    fn an_empty_group_id_means_the_legacy_group() {
+        assert_eq!(group_or_legacy(""), groups::LEGACY_GROUP_ID);
+        assert_eq!(group_or_legacy("ffdhe3072"), "ffdhe3072");
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn register_and_add_credential_put_an_empty_group_id_in_the_same_group() {
+        let service = service().await;
+        let group = groups::find(groups::LEGACY_GROUP_ID).unwrap();
+        let y1 = encode_hex(&mod_exp(&group.g, &BigInt::from(3), &group.p));
+        let y2 = encode_hex(&mod_exp(&group.h, &BigInt::from(3), &group.p));
+
+        let user = random_string(16);
+        let auth_id = default_hash(&user).to_str_radix(16);
+        service.register_user(&user, "", "", WireNumber::Hex(&y1), WireNumber::Hex(&y2), &[]).await.expect("Registration error");
+        let session_id = random_string(SESSION_ID_LENGTH);
+        sqlx::query("insert into session (session_id, auth_id, credential_id) values ($1, $2, $3)")
+            .bind(&session_id).bind(&auth_id).bind(DEFAULT_CREDENTIAL_ID)
+            .execute(&service.pool).await.expect("Session insertion error");
+        service.add_credential(&session_id, "laptop", "", WireNumber::Hex(&y1), WireNumber::Hex(&y2)).await.expect("Credential error");
+
+        let group_ids = sqlx::query("select group_id from register_request where auth_id = $1 order by credential_id")
+            .bind(&auth_id).fetch_all(&service.pool).await.expect("Group retrieval error")
+            .iter().map(|row| row.get::<String, usize>(0)).collect::<Vec<String>>();
+        assert_eq!(group_ids, vec![groups::LEGACY_GROUP_ID, groups::LEGACY_GROUP_ID]);
+
+        forget(&service, &auth_id).await;
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn unregister_forgets_the_seen_commitments() {
+        let service = service().await;
+        let group = groups::find(groups::LEGACY_GROUP_ID).unwrap();
+        let (x, k) = (BigInt::from(3), BigInt::from(5));
+        let y1 = encode_hex(&mod_exp(&group.g, &x, &group.p));
+        let y2 = encode_hex(&mod_exp(&group.h, &x, &group.p));
+        let r1 = encode_hex(&mod_exp(&group.g, &k, &group.p));
+        let r2 = encode_hex(&mod_exp(&group.h, &k, &group.p));
+
+        let user = random_string(16);
+        let auth_id = default_hash(&user).to_str_radix(16);
+        service.register_user(&user, "", "", WireNumber::Hex(&y1), WireNumber::Hex(&y2), &[]).await.expect("Registration error");
+        let commitment = Commitment { r1: WireNumber::Hex(&r1), r2: WireNumber::Hex(&r2), dh_share: None };
+        let challenge = service.create_challenge(&user, "", None, commitment, None, None).await
+            .expect("Challenge error").expect("The user is registered");
+
+        let c = bound_challenge(UNREGISTER_DOMAIN, &challenge.c, &[], group);
+        let s = ((&k - c * &x) % &group.q + &group.q) % &group.q;
+        assert!(service.unregister(&challenge.auth_id, WireNumber::Hex(&encode_hex(&s)), None).await.expect("Unregistration error"));
+
+        let seen = sqlx::query("select count(*) from seen_commitment where auth_id = $1")
+            .bind(&auth_id).fetch_one(&service.pool).await.unwrap().get::<i64, usize>(0);
+        assert_eq!(seen, 0);
+
+        forget(&service, &auth_id).await;
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn public_parameters_do_not_tell_who_is_registered() {
+        let service = service().await;
+        let group = groups::find("ffdhe2048").unwrap();
+        let y1 = encode_hex(&mod_exp(&group.g, &BigInt::from(3), &group.p));
+        let y2 = encode_hex(&mod_exp(&group.h, &BigInt::from(3), &group.p));
+        let user = random_string(16);
+        service.register_user(&user, "", group.id, WireNumber::Hex(&y1), WireNumber::Hex(&y2), &[]).await.expect("Registration error");
+        let registered = sqlx::query("select distinct group_id from register_request")
+            .fetch_all(&service.pool).await.expect("Group retrieval error")
+            .iter().map(|row| row.get::<String, usize>(0)).collect::<Vec<String>>();
+
+        // The requested group is ignored once a user is named, registered or not
+        let unknown = random_string(16);
+        let decoy = service.public_parameters("", &unknown, "", false).await.expect("Unknown user refused").id;
+        for group_id in ["", "ffdhe8192", "no-such-group"] {
+            assert_eq!(service.public_parameters(group_id, &user, "", false).await.expect("Registered user refused").id, group.id);
+            assert_eq!(service.public_parameters(group_id, &unknown, "", false).await.expect("Unknown user refused").id, decoy);
+        }
+        assert!(registered.iter().any(|group_id| group_id == decoy));
+        assert!(service.public_parameters("", &unknown, "", true).await.is_ok());
+
+        // Without a user, the requested group or the default one
+        assert_eq!(service.public_parameters("", "", "", false).await.unwrap().id, DEFAULT_GROUP_ID);
+        assert_eq!(service.public_parameters("ffdhe8192", "", "", false).await.unwrap().id, "ffdhe8192");
+        assert_eq!(service.public_parameters("no-such-group", "", "", false).await.unwrap_err().code(), tonic::Code::NotFound);
+
+        forget(&service, &default_hash(&user).to_str_radix(16)).await;
+    }
+}
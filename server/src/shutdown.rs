@@ -0,0 +1,36 @@
+use tokio::sync::oneshot;
+
+use crate::parameters::SHUTDOWN_GRACE_PERIOD;
+
+// Resolve when the process receives SIGINT (Ctrl+C) or SIGTERM
+pub async fn signal() {
+    let interrupt = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("SIGINT handler installation failed");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("SIGTERM handler installation failed")
+            .recv()
+            .await;
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = interrupt => {},
+        _ = terminate => {},
+    }
+}
+
+// Resolve SHUTDOWN_GRACE_PERIOD after the draining has started. Never resolves if the server stops on its own.
+pub async fn deadline(draining: oneshot::Receiver<()>) {
+    match draining.await {
+        Ok(()) => tokio::time::sleep(SHUTDOWN_GRACE_PERIOD).await,
+        Err(_) => std::future::pending().await,
+    }
+}
@@ -0,0 +1,145 @@
+use num_bigint::{BigInt, Sign};
+use num_traits::Zero;
+
+use crate::groups::Group;
+use crate::zkp_utils::mod_exp;
+
+// Sigma protocols: three-move proofs of knowledge (commitment, challenge, response) with special soundness and
+// honest-verifier zero knowledge. The instances below all live in a prime-order subgroup of Z_p^* and answer with
+// s = k - c * x (mod q), like the login. The server only checks the proofs: the moves of the prover (commit, respond
+// and simulate) are in the client (client/src/sigma.rs). Composing them with And and AnyOf gives a single verifier for
+// policies such as "knows the password OR knows a recovery key".
+pub trait SigmaProtocol {
+    type Commitment;
+    type Response;
+
+    // Challenges are integers in [0, challenge_space())
+    fn challenge_space(&self) -> &BigInt;
+
+    fn verify(&self, commitment: &Self::Commitment, c: &BigInt, response: &Self::Response) -> bool;
+}
+
+// Knowledge of x such that y = g^x: commitment r = g^k, checked with g^s * y^c = r
+#[derive(Debug, Clone)]
+pub struct Schnorr<'a> {
+    pub p: &'a BigInt,
+    pub q: &'a BigInt,
+    pub g: &'a BigInt,
+    pub y: &'a BigInt,
+}
+
+impl<'a> Schnorr<'a> {
+    pub fn new(group: &'a Group, y: &'a BigInt) -> Self {
+        Schnorr { p: &group.p, q: &group.q, g: &group.g, y }
+    }
+}
+
+impl SigmaProtocol for Schnorr<'_> {
+    type Commitment = BigInt;
+    type Response = BigInt;
+
+    fn challenge_space(&self) -> &BigInt {
+        self.q
+    }
+
+    fn verify(&self, r: &BigInt, c: &BigInt, s: &BigInt) -> bool {
+        mod_exp(self.g, s, self.p) * mod_exp(self.y, c, self.p) % self.p == *r
+    }
+}
+
+// Knowledge of x such that y1 = g^x and y2 = h^x, the proof of the login: commitment (r1, r2) = (g^k, h^k), checked
+// with g^s * y1^c = r1 and h^s * y2^c = r2
+#[derive(Debug, Clone)]
+pub struct ChaumPedersen<'a> {
+    pub p: &'a BigInt,
+    pub q: &'a BigInt,
+    pub g: &'a BigInt,
+    pub h: &'a BigInt,
+    pub y1: &'a BigInt,
+    pub y2: &'a BigInt,
+}
+
+impl<'a> ChaumPedersen<'a> {
+    pub fn new(group: &'a Group, y1: &'a BigInt, y2: &'a BigInt) -> Self {
+        ChaumPedersen { p: &group.p, q: &group.q, g: &group.g, h: &group.h, y1, y2 }
+    }
+
+    // The two Schnorr proofs sharing the exponent
+    fn halves(&self) -> And<Schnorr<'_>, Schnorr<'_>> {
+        And(
+            Schnorr { p: self.p, q: self.q, g: self.g, y: self.y1 },
+            Schnorr { p: self.p, q: self.q, g: self.h, y: self.y2 },
+        )
+    }
+}
+
+impl SigmaProtocol for ChaumPedersen<'_> {
+    type Commitment = (BigInt, BigInt);
+    type Response = BigInt;
+
+    fn challenge_space(&self) -> &BigInt {
+        self.q
+    }
+
+    // Both halves answer c with the same s
+    fn verify(&self, commitment: &(BigInt, BigInt), c: &BigInt, s: &BigInt) -> bool {
+        self.halves().verify(commitment, c, &(s.clone(), s.clone()))
+    }
+}
+
+// Knowledge of both witnesses: both proofs answer the same challenge. Both must have the same challenge space.
+#[derive(Debug, Clone)]
+pub struct And<A, B>(pub A, pub B);
+
+impl<A: SigmaProtocol, B: SigmaProtocol> SigmaProtocol for And<A, B> {
+    type Commitment = (A::Commitment, B::Commitment);
+    type Response = (A::Response, B::Response);
+
+    fn challenge_space(&self) -> &BigInt {
+        self.0.challenge_space()
+    }
+
+    fn verify(&self, (left_commitment, right_commitment): &Self::Commitment, c: &BigInt, (left_response, right_response): &Self::Response) -> bool {
+        self.0.challenge_space() == self.1.challenge_space()
+            && self.0.verify(left_commitment, c, left_response)
+            && self.1.verify(right_commitment, c, right_response)
+    }
+}
+
+// Knowledge of the witness of one of any number of proofs, without telling which (Cramer–Damgård–Schoenmakers). The
+// prover simulates every other proof with a challenge of its choice and answers its own with the rest of c: the
+// challenges add up to c modulo the challenge space. There must be at least one proof, and all must have the same
+// challenge space.
+#[derive(Debug, Clone)]
+pub struct AnyOf<P>(pub Vec<P>);
+
+impl<P: SigmaProtocol> AnyOf<P> {
+
+    // c minus the sum of challenges, in [0, challenge space)
+    fn remaining<'a>(&self, c: &BigInt, challenges: impl Iterator<Item = &'a BigInt>) -> BigInt {
+        let space = self.challenge_space();
+        let sum = challenges.fold(BigInt::zero(), |sum, challenge| sum + challenge);
+        ((c - sum) % space + space) % space
+    }
+}
+
+impl<P: SigmaProtocol> SigmaProtocol for AnyOf<P> {
+    type Commitment = Vec<P::Commitment>;
+    // The challenge and the response of each proof
+    type Response = Vec<(BigInt, P::Response)>;
+
+    fn challenge_space(&self) -> &BigInt {
+        self.0[0].challenge_space()
+    }
+
+    fn verify(&self, commitments: &Self::Commitment, c: &BigInt, responses: &Self::Response) -> bool {
+        !self.0.is_empty()
+            && commitments.len() == self.0.len()
+            && responses.len() == self.0.len()
+            && self.0.iter().all(|proof| proof.challenge_space() == self.challenge_space())
+            && responses.iter().all(|(challenge, _)| challenge.sign() != Sign::Minus && challenge < self.challenge_space())
+            && self.remaining(c, responses.iter().map(|(challenge, _)| challenge)).is_zero()
+            && self.0.iter().zip(commitments).zip(responses)
+                .all(|((proof, commitment), (challenge, response))| proof.verify(commitment, challenge, response))
+    }
+}
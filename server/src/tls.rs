@@ -0,0 +1,180 @@
+use colored::Colorize;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio_rustls::rustls::{self, Certificate, PrivateKey, ServerConfig};
+use tokio_rustls::TlsAcceptor;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::transport::server::Connected;
+use tonic::Request;
+
+use crate::parameters::{TLS_SERVER_NAME, TLS_HANDSHAKE_TIMEOUT, ACCEPT_ERROR_BACKOFF};
+use crate::zkp_utils::write_private;
+
+// TLS of the gRPC listener, and the channel binding of each connection.
+// The channel binding is the tls-exporter value of RFC 9266: 32 bytes exported (RFC 5705) with the label
+// EXPORTER-Channel-Binding and no context. Only TLS 1.3 is accepted, where every connection has its own exporter.
+// It is attached to every request of the connection, so that a challenge can be bound to the connection it was issued on.
+pub const CHANNEL_BINDING_LABEL: &[u8] = b"EXPORTER-Channel-Binding";
+pub const CHANNEL_BINDING_LENGTH: usize = 32;
+
+// What the service knows about the connection of a request
+#[derive(Debug, Clone)]
+pub struct ConnectionInfo {
+    // None without TLS
+    pub channel_binding: Option<Vec<u8>>,
+}
+
+// The channel binding of the connection a request came on, None without TLS
+pub fn channel_binding<T>(request: &Request<T>) -> Option<&[u8]> {
+    request
+        .extensions()
+        .get::<ConnectionInfo>()
+        .and_then(|info| info.channel_binding.as_deref())
+}
+
+// An accepted connection, with or without TLS
+pub enum Connection {
+    Plain(TcpStream),
+    Tls(Box<tokio_rustls::server::TlsStream<TcpStream>>, Vec<u8>),
+}
+
+impl Connected for Connection {
+    type ConnectInfo = ConnectionInfo;
+
+    fn connect_info(&self) -> ConnectionInfo {
+        match self {
+            Connection::Plain(_) => ConnectionInfo { channel_binding: None },
+            Connection::Tls(_, channel_binding) => ConnectionInfo { channel_binding: Some(channel_binding.clone()) },
+        }
+    }
+}
+
+impl AsyncRead for Connection {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Connection::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+            Connection::Tls(stream, _) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Connection {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Connection::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
+            Connection::Tls(stream, _) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Connection::Plain(stream) => Pin::new(stream).poll_flush(cx),
+            Connection::Tls(stream, _) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Connection::Plain(stream) => Pin::new(stream).poll_shutdown(cx),
+            Connection::Tls(stream, _) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+// Load the certificate and its private key, or generate a self-signed certificate for TLS_SERVER_NAME if there is
+// none yet. Clients pin the certificate.
+pub fn load_or_generate(certificate_path: &Path, key_path: &Path) -> io::Result<TlsAcceptor> {
+    if !certificate_path.exists() && !key_path.exists() {
+        let certificate = rcgen::generate_simple_self_signed(vec![TLS_SERVER_NAME.to_string()])
+            .map_err(|error| io::Error::other(error.to_string()))?;
+        let pem = certificate.serialize_pem()
+            .map_err(|error| io::Error::other(error.to_string()))?;
+        fs::write(certificate_path, pem)?;
+        write_private(key_path, &certificate.serialize_private_key_pem())?;
+    }
+
+    let certificates = rustls_pemfile::certs(&mut io::BufReader::new(fs::File::open(certificate_path)?))?
+        .into_iter()
+        .map(Certificate)
+        .collect();
+    let key = rustls_pemfile::pkcs8_private_keys(&mut io::BufReader::new(fs::File::open(key_path)?))?
+        .into_iter()
+        .next()
+        .map(PrivateKey)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "No PKCS#8 private key found"))?;
+
+    let mut config = ServerConfig::builder()
+        .with_safe_default_cipher_suites()
+        .with_safe_default_kx_groups()
+        .with_protocol_versions(&[&rustls::version::TLS13])
+        .map_err(|error| io::Error::other(error.to_string()))?
+        .with_no_client_auth()
+        .with_single_cert(certificates, key)
+        .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error.to_string()))?;
+    config.alpn_protocols = vec![b"h2".to_vec()];
+
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+// The connections accepted on listener, after the TLS handshake if acceptor is set. Handshakes run in their own task
+// so that a slow client does not hold the others back, and failed handshakes, or handshakes not done within
+// TLS_HANDSHAKE_TIMEOUT, are dropped. The stream never yields an
+// error, and ends when the server stops listening.
+pub fn incoming(listener: TcpListener, acceptor: Option<TlsAcceptor>) -> ReceiverStream<io::Result<Connection>> {
+    let (sender, receiver) = mpsc::channel(64);
+
+    tokio::spawn(async move {
+        loop {
+            let (stream, address) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                // An error ends the stream and the server with it: a failed accept (e.g. EMFILE, ECONNABORTED) is
+                // logged and retried after a pause instead
+                Err(error) => {
+                    println!("{}", format!("Accepting a connection failed: {}", error).red());
+                    tokio::time::sleep(ACCEPT_ERROR_BACKOFF).await;
+                    continue;
+                }
+            };
+
+            let Some(acceptor) = acceptor.clone() else {
+                if sender.send(Ok(Connection::Plain(stream))).await.is_err() {
+                    break;
+                }
+                continue;
+            };
+
+            let sender = sender.clone();
+            tokio::spawn(async move {
+                // A client which stops in the middle of the handshake would otherwise hold its task and socket forever
+                let connection = match tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                    Ok(Ok(stream)) => stream,
+                    Ok(Err(error)) => {
+                        println!("{}", format!("TLS handshake with {} failed: {}", address, error).red());
+                        return;
+                    }
+                    Err(_) => {
+                        println!("{}", format!("TLS handshake with {} timed out", address).red());
+                        return;
+                    }
+                };
+                let channel_binding = match connection.get_ref().1.export_keying_material(vec![0; CHANNEL_BINDING_LENGTH], CHANNEL_BINDING_LABEL, None) {
+                    Ok(channel_binding) => channel_binding,
+                    Err(error) => {
+                        println!("{}", format!("TLS exporter of {} failed: {}", address, error).red());
+                        return;
+                    }
+                };
+                let _ = sender.send(Ok(Connection::Tls(Box::new(connection), channel_binding))).await;
+            });
+        }
+    });
+
+    ReceiverStream::new(receiver)
+}
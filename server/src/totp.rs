@@ -0,0 +1,170 @@
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use sha1::Sha1;
+use std::fmt::Debug;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::parameters::{TOTP_DIGITS, TOTP_STEP, TOTP_SKEW_STEPS, TOTP_SECRET_LENGTH};
+
+// Time-based one-time passwords (RFC 6238) with HMAC-SHA1, the algorithm every authenticator app supports.
+// Known answers from RFC 6238 appendix B, with the secret "12345678901234567890" and 8 digits:
+// 59 s -> 94287082, 1111111109 s -> 07081804, 1234567890 s -> 89005924, 2000000000 s -> 69279037
+
+// Source of the current time, so that the second factor can be checked against a fixed clock
+pub trait Clock: Debug + Send + Sync {
+    // Seconds since the Unix epoch
+    fn now(&self) -> u64;
+}
+
+#[derive(Debug)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("The system clock is before the Unix epoch")
+            .as_secs()
+    }
+}
+
+// A clock stopped at the given number of seconds since the Unix epoch
+#[cfg(test)]
+#[derive(Debug)]
+pub struct FixedClock(pub u64);
+
+#[cfg(test)]
+impl Clock for FixedClock {
+    fn now(&self) -> u64 {
+        self.0
+    }
+}
+
+// A new random secret, base32 encoded like authenticator apps expect it
+pub fn generate_secret() -> String {
+    let mut secret = vec![0; TOTP_SECRET_LENGTH];
+    rand::thread_rng().fill_bytes(&mut secret);
+    base32::encode(base32::Alphabet::RFC4648 { padding: false }, &secret)
+}
+
+// HOTP (RFC 4226): HMAC-SHA1 of the counter, dynamically truncated to digits decimal digits
+pub fn hotp(secret: &[u8], counter: u64, digits: u32) -> u32 {
+    let mut mac = Hmac::<Sha1>::new_from_slice(secret).expect("HMAC accepts keys of any length");
+    mac.update(&counter.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+
+    let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+    let truncated = u32::from_be_bytes([hash[offset], hash[offset + 1], hash[offset + 2], hash[offset + 3]]) & 0x7fff_ffff;
+    truncated % 10u32.pow(digits)
+}
+
+// The code of the time step containing time
+#[cfg(test)]
+pub fn totp(secret: &[u8], time: u64) -> u32 {
+    hotp(secret, time / TOTP_STEP, TOTP_DIGITS)
+}
+
+// The time step matched by code at time, allowing TOTP_SKEW_STEPS steps of clock drift either way.
+// Only the steps after last_step are accepted, so that a code cannot be used twice.
+pub fn verify(secret: &str, code: &str, time: u64, last_step: i64) -> Option<i64> {
+    let secret = base32::decode(base32::Alphabet::RFC4648 { padding: false }, secret)?;
+    if code.len() != TOTP_DIGITS as usize || !code.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    let code: u32 = code.parse().ok()?;
+
+    let step = time / TOTP_STEP;
+    (step.saturating_sub(TOTP_SKEW_STEPS)..=step + TOTP_SKEW_STEPS)
+        .filter(|candidate| *candidate as i64 > last_step)
+        .find(|candidate| hotp(&secret, *candidate, TOTP_DIGITS) == code)
+        .map(|candidate| candidate as i64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The secret of the RFC 6238 test vectors, and its base32 encoding as stored in the database
+    const SECRET: &[u8] = b"12345678901234567890";
+    const SECRET_BASE32: &str = "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ";
+
+    fn code(step: u64) -> String {
+        format!("{:0width$}", hotp(SECRET, step, TOTP_DIGITS), width = TOTP_DIGITS as usize)
+    }
+
+    #[test]
+    fn rfc_6238_vectors() {
+        let vectors = [
+            (59, 94287082),
+            (1111111109, 7081804),
+            (1111111111, 14050471),
+            (1234567890, 89005924),
+            (2000000000, 69279037),
+            (20000000000, 65353130),
+        ];
+        for (time, expected) in vectors {
+            assert_eq!(hotp(SECRET, time / TOTP_STEP, 8), expected, "time {}", time);
+            assert_eq!(totp(SECRET, time), expected % 10u32.pow(TOTP_DIGITS), "time {}", time);
+        }
+    }
+
+    #[test]
+    fn secret_is_base32() {
+        assert_eq!(base32::encode(base32::Alphabet::RFC4648 { padding: false }, SECRET), SECRET_BASE32);
+        let secret = generate_secret();
+        assert_eq!(base32::decode(base32::Alphabet::RFC4648 { padding: false }, &secret).unwrap().len(), TOTP_SECRET_LENGTH);
+    }
+
+    #[test]
+    fn accepts_the_skew_window() {
+        let time = 1111111109;
+        let step = time / TOTP_STEP;
+        for candidate in step - TOTP_SKEW_STEPS..=step + TOTP_SKEW_STEPS {
+            assert_eq!(verify(SECRET_BASE32, &code(candidate), time, -1), Some(candidate as i64));
+        }
+        assert_eq!(verify(SECRET_BASE32, &code(step - TOTP_SKEW_STEPS - 1), time, -1), None);
+        assert_eq!(verify(SECRET_BASE32, &code(step + TOTP_SKEW_STEPS + 1), time, -1), None);
+    }
+
+    #[test]
+    fn skew_window_follows_the_step_boundaries() {
+        // The last and the first second of a step
+        let step = 1111111109 / TOTP_STEP;
+        let first = step * TOTP_STEP;
+        let last = first + TOTP_STEP - 1;
+        let late = code(step + TOTP_SKEW_STEPS + 1);
+        assert_eq!(verify(SECRET_BASE32, &late, last, -1), None);
+        assert_eq!(verify(SECRET_BASE32, &late, last + 1, -1), Some((step + TOTP_SKEW_STEPS + 1) as i64));
+        let early = code(step - TOTP_SKEW_STEPS);
+        assert_eq!(verify(SECRET_BASE32, &early, last, -1), Some((step - TOTP_SKEW_STEPS) as i64));
+        assert_eq!(verify(SECRET_BASE32, &early, last + 1, -1), None);
+    }
+
+    #[test]
+    fn skew_window_stops_at_the_epoch() {
+        assert_eq!(verify(SECRET_BASE32, &code(0), 0, -1), Some(0));
+        assert_eq!(verify(SECRET_BASE32, &code(TOTP_SKEW_STEPS), 0, -1), Some(TOTP_SKEW_STEPS as i64));
+    }
+
+    #[test]
+    fn refuses_steps_already_used() {
+        let time = 1234567890;
+        let step = (time / TOTP_STEP) as i64;
+        assert_eq!(verify(SECRET_BASE32, &code(step as u64), time, step - 1), Some(step));
+        assert_eq!(verify(SECRET_BASE32, &code(step as u64), time, step), None);
+        assert_eq!(verify(SECRET_BASE32, &code(step as u64 - 1), time, step), None);
+        assert_eq!(verify(SECRET_BASE32, &code(step as u64 + 1), time, step), Some(step + 1));
+    }
+
+    #[test]
+    fn refuses_malformed_codes() {
+        let time = 59;
+        let valid = code(time / TOTP_STEP);
+        assert!(verify(SECRET_BASE32, &valid, time, -1).is_some());
+        assert_eq!(verify(SECRET_BASE32, &valid[1..], time, -1), None);
+        assert_eq!(verify(SECRET_BASE32, &format!("0{}", valid), time, -1), None);
+        assert_eq!(verify(SECRET_BASE32, &format!("+{}", &valid[1..]), time, -1), None);
+        assert_eq!(verify(SECRET_BASE32, "", time, -1), None);
+        assert_eq!(verify("not base32!", &valid, time, -1), None);
+    }
+}
@@ -0,0 +1,222 @@
+use num_bigint::BigInt;
+use num_traits::{One, Zero};
+
+use crate::groups::Group;
+use crate::service::challenge_space;
+use crate::sigma::{ChaumPedersen, SigmaProtocol};
+use crate::zkp_utils::{mod_exp, random_big_int};
+
+// Test support checking the two properties the login relies on, against the real verifier
+// (ChaumPedersen::verify, which PendingProof::check calls):
+// - honest-verifier zero knowledge: simulate produces accepting transcripts (r1, r2, c, s) for any (y1, y2) without x,
+//   distributed like the transcripts of an honest login
+// - special soundness: extract recovers x from two accepting transcripts sharing the commitment (r1, r2) with
+//   different challenges, so a prover able to answer two challenges knows x
+// The tests below pick random x and c, then check that accepts holds for simulate and for rewind, that
+// extract on the output of rewind gives back x, and that forged transcripts are refused.
+
+// A transcript of the Chaum–Pedersen proof of the login
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Transcript {
+    pub r1: BigInt,
+    pub r2: BigInt,
+    pub c: BigInt,
+    pub s: BigInt,
+}
+
+// Whether the verifier of the login accepts the transcript for the public key (y1, y2)
+pub fn accepts(group: &Group, y1: &BigInt, y2: &BigInt, transcript: &Transcript) -> bool {
+    ChaumPedersen::new(group, y1, y2).verify(&(transcript.r1.clone(), transcript.r2.clone()), &transcript.c, &transcript.s)
+}
+
+// A random challenge in the challenge space of the group
+pub fn random_challenge(group: &Group) -> BigInt {
+    random_big_int(Zero::zero(), challenge_space(group))
+}
+
+// An accepting transcript for (y1, y2) and the challenge c, computed without x: s random, r1 = g^s * y1^c, r2 = h^s * y2^c
+pub fn simulate(group: &Group, y1: &BigInt, y2: &BigInt, c: &BigInt) -> Transcript {
+    let s = random_big_int(Zero::zero(), group.q.clone());
+    let r1 = mod_exp(&group.g, &s, &group.p) * mod_exp(y1, c, &group.p) % &group.p;
+    let r2 = mod_exp(&group.h, &s, &group.p) * mod_exp(y2, c, &group.p) % &group.p;
+    Transcript { r1, r2, c: c.clone(), s }
+}
+
+// Two honest transcripts with the same commitment (g^k, h^k), answering the challenges c1 and c2 with s = k - c * x
+// (mod q): what a rewound prover gives
+pub fn rewind(group: &Group, x: &BigInt, c1: &BigInt, c2: &BigInt) -> (Transcript, Transcript) {
+    let q = &group.q;
+    let k = random_big_int(BigInt::from(2), q - 1);
+    let (r1, r2) = (mod_exp(&group.g, &k, &group.p), mod_exp(&group.h, &k, &group.p));
+    let answer = |c: &BigInt| ((&k - c * x) % q + q) % q;
+
+    let first = Transcript { r1: r1.clone(), r2: r2.clone(), c: c1.clone(), s: answer(c1) };
+    let second = Transcript { r1, r2, c: c2.clone(), s: answer(c2) };
+    (first, second)
+}
+
+// x from two transcripts with the same commitment and different challenges: s1 = k - c1 * x and s2 = k - c2 * x give
+// x = (s2 - s1) / (c1 - c2) mod q. None if the commitments differ or the challenges are equal modulo q.
+pub fn extract(group: &Group, first: &Transcript, second: &Transcript) -> Option<BigInt> {
+    let q = &group.q;
+    if first.r1 != second.r1 || first.r2 != second.r2 {
+        return None;
+    }
+
+    let challenges = ((&first.c - &second.c) % q + q) % q;
+    if challenges.is_zero() {
+        return None;
+    }
+
+    // q is prime: the inverse is challenges^(q - 2)
+    let inverse = mod_exp(&challenges, &(q - 2), q);
+    let answers = ((&second.s - &first.s) % q + q) % q;
+    Some(answers * inverse % q)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::groups;
+    use crate::parameters::DEFAULT_GROUP_ID;
+
+    // Rounds of each property per group
+    const ROUNDS: usize = 4;
+
+    // The default group and a group of RFC 7919. The larger groups only differ in size, and take minutes in a debug build.
+    fn groups() -> Vec<&'static Group> {
+        vec![groups::find(DEFAULT_GROUP_ID).unwrap(), groups::find("ffdhe2048").unwrap()]
+    }
+
+    fn key(group: &Group, x: &BigInt) -> (BigInt, BigInt) {
+        (mod_exp(&group.g, x, &group.p), mod_exp(&group.h, x, &group.p))
+    }
+
+    // The verifier on the challenge c with a random x: honest and simulated transcripts are accepted, a wrong answer is
+    // not, and x is extracted from two transcripts sharing the commitment
+    fn check_edge_challenge(c: BigInt) {
+        let group = groups::find(DEFAULT_GROUP_ID).unwrap();
+        let x = random_challenge(group);
+        let (y1, y2) = key(group, &x);
+
+        let other = (&c + 1) % &group.q;
+        let (first, second) = rewind(group, &x, &c, &other);
+        assert!(accepts(group, &y1, &y2, &first), "an honest answer to the challenge {} is refused", c);
+        assert!(accepts(group, &y1, &y2, &second), "an honest answer to the challenge {} is refused", other);
+        assert!(accepts(group, &y1, &y2, &simulate(group, &y1, &y2, &c)), "a simulated answer to the challenge {} is refused", c);
+        let wrong = Transcript { s: (&first.s + 1) % &group.q, ..first.clone() };
+        assert!(!accepts(group, &y1, &y2, &wrong), "a wrong answer to the challenge {} is accepted", c);
+        assert_eq!(extract(group, &first, &second), Some(x), "x cannot be extracted with the challenge {}", c);
+    }
+
+    #[test]
+    fn edge_challenge_zero() {
+        check_edge_challenge(Zero::zero());
+    }
+
+    #[test]
+    fn edge_challenge_one() {
+        check_edge_challenge(One::one());
+    }
+
+    #[test]
+    fn edge_challenge_largest() {
+        check_edge_challenge(challenge_space(groups::find(DEFAULT_GROUP_ID).unwrap()) - 1);
+    }
+
+    // -1 modulo q
+    #[test]
+    fn edge_challenge_q_minus_one() {
+        check_edge_challenge(&groups::find(DEFAULT_GROUP_ID).unwrap().q - 1);
+    }
+
+    #[test]
+    fn challenges_stay_in_the_space() {
+        for group in groups() {
+            let space = challenge_space(group);
+            assert!(space > One::one() && space <= group.q, "{}", group.id);
+            for _ in 0..64 {
+                let c = random_challenge(group);
+                assert!(c >= Zero::zero() && c < space, "{}", group.id);
+            }
+        }
+    }
+
+    #[test]
+    fn simulated_transcripts_verify() {
+        for group in groups() {
+            for _ in 0..ROUNDS {
+                let (y1, y2) = key(group, &random_challenge(group));
+                let c = random_challenge(group);
+                let transcript = simulate(group, &y1, &y2, &c);
+                assert_eq!(transcript.c, c);
+                assert!(accepts(group, &y1, &y2, &transcript), "{}", group.id);
+            }
+        }
+    }
+
+    #[test]
+    fn honest_transcripts_verify() {
+        for group in groups() {
+            for _ in 0..ROUNDS {
+                let x = random_challenge(group);
+                let (y1, y2) = key(group, &x);
+                let (first, second) = rewind(group, &x, &random_challenge(group), &random_challenge(group));
+                assert!(accepts(group, &y1, &y2, &first), "{}", group.id);
+                assert!(accepts(group, &y1, &y2, &second), "{}", group.id);
+            }
+        }
+    }
+
+    #[test]
+    fn extraction_recovers_x() {
+        for group in groups() {
+            for _ in 0..ROUNDS {
+                let x = random_challenge(group);
+                let c1 = random_challenge(group);
+                let c2 = (&c1 + 1 + random_challenge(group) % (&group.q - 1)) % &group.q;
+                let (first, second) = rewind(group, &x, &c1, &c2);
+                assert_eq!(extract(group, &first, &second), Some(x), "{}", group.id);
+            }
+        }
+    }
+
+    #[test]
+    fn extraction_needs_two_challenges_on_one_commitment() {
+        for group in groups() {
+            let x = random_challenge(group);
+            let c = random_challenge(group);
+            let (first, second) = rewind(group, &x, &c, &c);
+            assert_eq!(extract(group, &first, &second), None, "{}", group.id);
+            let (other, _) = rewind(group, &x, &(&c + 1), &c);
+            assert_eq!(extract(group, &first, &other), None, "{}", group.id);
+        }
+    }
+
+    #[test]
+    fn forged_transcripts_are_refused() {
+        for group in groups() {
+            for _ in 0..ROUNDS {
+                let x = random_challenge(group);
+                let (y1, y2) = key(group, &x);
+                let c = random_challenge(group);
+                let (honest, _) = rewind(group, &x, &c, &c);
+
+                // Another answer, another challenge, or one half of the commitment replaced
+                let forged = [
+                    Transcript { s: (&honest.s + 1) % &group.q, ..honest.clone() },
+                    Transcript { c: (&honest.c + 1) % &group.q, ..honest.clone() },
+                    Transcript { r1: &honest.r1 * &group.g % &group.p, ..honest.clone() },
+                    Transcript { r2: &honest.r2 * &group.g % &group.p, ..honest.clone() },
+                ];
+                for transcript in &forged {
+                    assert!(!accepts(group, &y1, &y2, transcript), "{}", group.id);
+                }
+
+                // A simulation for one key does not verify for another
+                let (other1, other2) = key(group, &(&x + 1));
+                assert!(!accepts(group, &other1, &other2, &simulate(group, &y1, &y2, &c)), "{}", group.id);
+            }
+        }
+    }
+}
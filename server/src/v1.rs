@@ -0,0 +1,307 @@
+use tonic::{Request, Response, Status};
+
+use crate::encoding::{decode_hex_bytes, encode_hex, WireNumber};
+use crate::parameters::{KDF, CHALLENGE_BITS, TOTP_DIGITS, TOTP_STEP};
+use crate::puzzle::Solution;
+use crate::service::{AuthService, Authentication, Commitment, USER_NOT_REGISTERED, WRONG_CREDENTIALS, SECOND_FACTOR_REQUIRED};
+use crate::tls;
+use crate::zkp_auth::v1::auth_server::Auth;
+use crate::zkp_auth::v1::{
+    RegisterRequest,
+    RegisterResponse,
+    AuthenticationChallengeRequest,
+    AuthenticationChallengeResponse,
+    ServerSignature,
+    AuthenticationAnswerRequest,
+    AuthenticationAnswerResponse,
+    VerifySecondFactorRequest,
+    VerifySecondFactorResponse,
+    EnrollTotpRequest,
+    EnrollTotpResponse,
+    ConfirmTotpRequest,
+    ConfirmTotpResponse,
+    PublicParametersRequest,
+    PublicParametersResponse,
+    ChangePasswordRequest,
+    ChangePasswordResponse,
+    UnregisterRequest,
+    UnregisterResponse,
+    RecoverAccountRequest,
+    RecoverAccountResponse,
+    AddCredentialRequest,
+    AddCredentialResponse,
+    ListCredentialsRequest,
+    ListCredentialsResponse,
+    Credential,
+    RevokeCredentialRequest,
+    RevokeCredentialResponse,
+    CapabilitiesRequest,
+    CapabilitiesResponse,
+    ProtocolVersion
+};
+
+// zkp_auth.v1.Auth: the numbers are hexadecimal strings
+#[tonic::async_trait]
+impl Auth for AuthService {
+
+    // Implementing the Register method that allows registering users by providing username, y1 and y2
+    async fn register(&self, request:Request<RegisterRequest>) -> Result<Response<RegisterResponse>, Status> {
+
+        let request = request.get_ref();
+        self.register_user(
+            &request.user,
+            &request.credential_id,
+            &request.group_id,
+            WireNumber::Hex(&request.y1),
+            WireNumber::Hex(&request.y2),
+            &request.recovery_keys
+                .iter()
+                .map(|key| (WireNumber::Hex(&key.y1), WireNumber::Hex(&key.y2)))
+                .collect::<Vec<_>>(),
+        ).await?;
+
+        Ok(Response::new(RegisterResponse{}))
+    }
+
+    // Implementing the CreateAuthenticationChallenge challenge which creates the challenge c based on username, r1, and r2
+    async fn create_authentication_challenge(&self, request:Request<AuthenticationChallengeRequest>) -> Result<Response<AuthenticationChallengeResponse>, Status> {
+
+        let channel_binding = tls::channel_binding(&request);
+        let request = request.get_ref();
+        let puzzle_seed = decode_hex_bytes(&request.puzzle_seed, "puzzle_seed")?;
+        let challenge = self.create_challenge(
+            &request.user,
+            &request.credential_id,
+            match request.recovery_code_id {
+                0 => None,
+                recovery_code_id => Some(i32::try_from(recovery_code_id)
+                    .map_err(|_| Status::invalid_argument("Unknown recovery code"))?),
+            },
+            Commitment {
+                r1: WireNumber::Hex(&request.r1),
+                r2: WireNumber::Hex(&request.r2),
+                dh_share: match request.dh_share.is_empty() {
+                    true => None,
+                    false => Some(WireNumber::Hex(&request.dh_share)),
+                },
+            },
+            match puzzle_seed.is_empty() {
+                true => None,
+                false => Some(Solution { seed: &puzzle_seed, nonce: request.puzzle_nonce }),
+            },
+            channel_binding,
+        ).await?;
+
+        // Send back the random challenge c
+        // If user is not registered, set auth_id to UserNotRegistered
+        Ok(Response::new(match challenge {
+            Some(challenge) => AuthenticationChallengeResponse{
+                auth_id: challenge.auth_id,
+                c: encode_hex(&challenge.c),
+                server_signature: Some(ServerSignature{
+                    e: encode_hex(&challenge.signature.e),
+                    s: encode_hex(&challenge.signature.s),
+                }),
+                dh_share: challenge.dh_share.as_ref().map_or_else(String::new, encode_hex),
+            },
+            None => AuthenticationChallengeResponse{
+                auth_id: USER_NOT_REGISTERED.to_string(),
+                c: String::new(),
+                server_signature: None,
+                dh_share: String::new(),
+            },
+        }))
+    }
+
+    // Implementing the VerifyAuthentication method based on the received s
+    async fn verify_authentication(&self, request:Request<AuthenticationAnswerRequest>) -> Result<Response<AuthenticationAnswerResponse>, Status> {
+
+        let channel_binding = tls::channel_binding(&request);
+        let request = request.get_ref();
+        let authentication = self.verify_answer(&request.auth_id, WireNumber::Hex(&request.s), channel_binding).await?;
+
+        Ok(Response::new(match authentication {
+            Authentication::Authenticated { session, key_confirmation } => AuthenticationAnswerResponse{
+                session_id: session.session_id,
+                credential_id: session.credential_id,
+                second_factor_token: String::new(),
+                key_confirmation: key_confirmation.map_or_else(String::new, |key_confirmation| key_confirmation.iter().map(|byte| format!("{:02x}", byte)).collect()),
+            },
+            Authentication::SecondFactorRequired { token, key_confirmation } => AuthenticationAnswerResponse{
+                session_id: SECOND_FACTOR_REQUIRED.to_string(),
+                credential_id: String::new(),
+                second_factor_token: token,
+                key_confirmation: key_confirmation.map_or_else(String::new, |key_confirmation| key_confirmation.iter().map(|byte| format!("{:02x}", byte)).collect()),
+            },
+            Authentication::WrongCredentials => AuthenticationAnswerResponse{
+                session_id: WRONG_CREDENTIALS.to_string(),
+                credential_id: String::new(),
+                second_factor_token: String::new(),
+                key_confirmation: String::new(),
+            },
+        }))
+    }
+
+    // Implementing the VerifySecondFactor method which turns a login waiting for the second factor into a session
+    async fn verify_second_factor(&self, request:Request<VerifySecondFactorRequest>) -> Result<Response<VerifySecondFactorResponse>, Status> {
+
+        let request = request.get_ref();
+        let session = self.verify_second_factor(&request.second_factor_token, &request.code).await?;
+
+        Ok(Response::new(match session {
+            Some(session) => VerifySecondFactorResponse{
+                session_id: session.session_id,
+                credential_id: session.credential_id,
+            },
+            None => VerifySecondFactorResponse{
+                session_id: WRONG_CREDENTIALS.to_string(),
+                credential_id: String::new(),
+            },
+        }))
+    }
+
+    // Implementing the EnrollTotp method which starts the TOTP enrollment of the user of the session
+    async fn enroll_totp(&self, request:Request<EnrollTotpRequest>) -> Result<Response<EnrollTotpResponse>, Status> {
+
+        let secret = self.enroll_totp(&request.get_ref().session_id).await?;
+
+        Ok(Response::new(EnrollTotpResponse{
+            secret,
+            digits: TOTP_DIGITS,
+            period: TOTP_STEP as u32,
+        }))
+    }
+
+    // Implementing the ConfirmTotp method which confirms the TOTP enrollment with a first code
+    async fn confirm_totp(&self, request:Request<ConfirmTotpRequest>) -> Result<Response<ConfirmTotpResponse>, Status> {
+
+        let request = request.get_ref();
+        let confirmed = self.confirm_totp(&request.session_id, &request.code).await?;
+
+        Ok(Response::new(ConfirmTotpResponse{ confirmed }))
+    }
+
+    // Implementing the GetPublicParameters method which lets the clients fetch the group and the KDF instead of hardcoding them
+    async fn get_public_parameters(&self, request:Request<PublicParametersRequest>) -> Result<Response<PublicParametersResponse>, Status> {
+
+        let request = request.get_ref();
+        let group = self.public_parameters(&request.group_id, &request.user, &request.credential_id, request.recovery).await?;
+
+        Ok(Response::new(PublicParametersResponse{
+            group_id: group.id.to_string(),
+            p: encode_hex(&group.p),
+            q: encode_hex(&group.q),
+            g: encode_hex(&group.g),
+            h: encode_hex(&group.h),
+            kdf: KDF.to_string(),
+            challenge_bits: CHALLENGE_BITS as u32,
+        }))
+    }
+
+    // Implementing the ChangePassword method which replaces y1 and y2 after checking the answer computed with the old x
+    async fn change_password(&self, request:Request<ChangePasswordRequest>) -> Result<Response<ChangePasswordResponse>, Status> {
+
+        let channel_binding = tls::channel_binding(&request);
+        let request = request.get_ref();
+        let changed = self.change_password(
+            &request.auth_id,
+            WireNumber::Hex(&request.s),
+            WireNumber::Hex(&request.new_y1),
+            WireNumber::Hex(&request.new_y2),
+            channel_binding,
+        ).await?;
+
+        Ok(Response::new(ChangePasswordResponse{ changed }))
+    }
+
+    // Implementing the Unregister method which deletes the account after checking the answer bound to the deletion
+    async fn unregister(&self, request:Request<UnregisterRequest>) -> Result<Response<UnregisterResponse>, Status> {
+
+        let channel_binding = tls::channel_binding(&request);
+        let request = request.get_ref();
+        let unregistered = self.unregister(&request.auth_id, WireNumber::Hex(&request.s), channel_binding).await?;
+
+        Ok(Response::new(UnregisterResponse{ unregistered }))
+    }
+
+    // Implementing the RecoverAccount method which sets a new password after checking the answer computed with a recovery code
+    async fn recover_account(&self, request:Request<RecoverAccountRequest>) -> Result<Response<RecoverAccountResponse>, Status> {
+
+        let channel_binding = tls::channel_binding(&request);
+        let request = request.get_ref();
+        let remaining = self.recover_account(
+            &request.auth_id,
+            WireNumber::Hex(&request.s),
+            &request.credential_id,
+            WireNumber::Hex(&request.new_y1),
+            WireNumber::Hex(&request.new_y2),
+            channel_binding,
+        ).await?;
+
+        Ok(Response::new(RecoverAccountResponse{
+            recovered: remaining.is_some(),
+            remaining_recovery_codes: remaining.unwrap_or(0) as u32,
+        }))
+    }
+
+    // Implementing the AddCredential method which enrolls another credential (device) for the user of the session
+    async fn add_credential(&self, request:Request<AddCredentialRequest>) -> Result<Response<AddCredentialResponse>, Status> {
+
+        let request = request.get_ref();
+        self.add_credential(
+            &request.session_id,
+            &request.credential_id,
+            &request.group_id,
+            WireNumber::Hex(&request.y1),
+            WireNumber::Hex(&request.y2),
+        ).await?;
+
+        Ok(Response::new(AddCredentialResponse{}))
+    }
+
+    // Implementing the ListCredentials method which lists the credentials of the user of the session
+    async fn list_credentials(&self, request:Request<ListCredentialsRequest>) -> Result<Response<ListCredentialsResponse>, Status> {
+
+        let credentials = self.list_credentials(&request.get_ref().session_id).await?;
+
+        Ok(Response::new(ListCredentialsResponse{
+            credentials: credentials
+                .into_iter()
+                .map(|credential| Credential {
+                    credential_id: credential.credential_id,
+                    group_id: credential.group_id,
+                })
+                .collect(),
+        }))
+    }
+
+    // Implementing the RevokeCredential method which revokes a credential of the user of the session
+    async fn revoke_credential(&self, request:Request<RevokeCredentialRequest>) -> Result<Response<RevokeCredentialResponse>, Status> {
+
+        let request = request.get_ref();
+        self.revoke_credential(&request.session_id, &request.credential_id).await?;
+
+        Ok(Response::new(RevokeCredentialResponse{}))
+    }
+
+    // Implementing the GetCapabilities method, the handshake telling the clients what the server supports
+    async fn get_capabilities(&self, _request:Request<CapabilitiesRequest>) -> Result<Response<CapabilitiesResponse>, Status> {
+
+        let capabilities = self.capabilities();
+
+        Ok(Response::new(CapabilitiesResponse{
+            versions: capabilities.versions
+                .into_iter()
+                .map(|(service, encoding)| ProtocolVersion {
+                    service: service.to_string(),
+                    encoding: encoding.to_string(),
+                })
+                .collect(),
+            groups: capabilities.groups.into_iter().map(String::from).collect(),
+            default_group_id: capabilities.default_group_id.to_string(),
+            kdfs: capabilities.kdfs.into_iter().map(String::from).collect(),
+            proof_modes: capabilities.proof_modes.into_iter().map(String::from).collect(),
+            key_exchanges: capabilities.key_exchanges.into_iter().map(String::from).collect(),
+        }))
+    }
+}
@@ -0,0 +1,500 @@
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::{Request, Response, Status, Streaming};
+
+use crate::encoding::{encode_bytes, WireNumber};
+use crate::metrics;
+use crate::parameters::{KDF, CHALLENGE_BITS, TOTP_DIGITS, TOTP_STEP, AUTHENTICATE_STREAM_DEADLINE};
+use crate::puzzle::Solution;
+use crate::service::{AuthService, Authentication, Challenge, Commitment, USER_NOT_REGISTERED, WRONG_CREDENTIALS, SECOND_FACTOR_REQUIRED};
+use crate::service::RingChallenge as IssuedRingChallenge;
+use crate::tls;
+use crate::zkp_auth::v2::auth_server::Auth;
+use crate::zkp_auth::v2::{
+    RegisterRequest,
+    RegisterResponse,
+    AuthenticationChallengeRequest,
+    AuthenticationChallengeResponse,
+    ServerSignature,
+    AuthenticationAnswerRequest,
+    AuthenticationAnswerResponse,
+    AuthenticateRequest,
+    AuthenticateResponse,
+    authenticate_request::Step as ClientStep,
+    authenticate_response::Step as ServerStep,
+    RingRequest,
+    RingResponse,
+    RingChallenge,
+    RingResult,
+    AuthenticateAnonymouslyRequest,
+    AuthenticateAnonymouslyResponse,
+    authenticate_anonymously_request::Step as RingClientStep,
+    authenticate_anonymously_response::Step as RingServerStep,
+    VerifySecondFactorRequest,
+    VerifySecondFactorResponse,
+    EnrollTotpRequest,
+    EnrollTotpResponse,
+    ConfirmTotpRequest,
+    ConfirmTotpResponse,
+    PublicParametersRequest,
+    PublicParametersResponse,
+    ChangePasswordRequest,
+    ChangePasswordResponse,
+    UnregisterRequest,
+    UnregisterResponse,
+    RecoverAccountRequest,
+    RecoverAccountResponse,
+    AddCredentialRequest,
+    AddCredentialResponse,
+    ListCredentialsRequest,
+    ListCredentialsResponse,
+    Credential,
+    RevokeCredentialRequest,
+    RevokeCredentialResponse,
+    CapabilitiesRequest,
+    CapabilitiesResponse,
+    ProtocolVersion
+};
+
+// The response to a commitment. If user is not registered, auth_id is UserNotRegistered.
+fn challenge_response(challenge: Option<&Challenge>) -> AuthenticationChallengeResponse {
+    match challenge {
+        Some(challenge) => AuthenticationChallengeResponse{
+            auth_id: challenge.auth_id.clone(),
+            c: encode_bytes(&challenge.c, challenge.group),
+            server_signature: Some(ServerSignature{
+                e: encode_bytes(&challenge.signature.e, challenge.signature.group),
+                s: encode_bytes(&challenge.signature.s, challenge.signature.group),
+            }),
+            dh_share: challenge.dh_share.as_ref().map_or_else(Vec::new, |share| encode_bytes(share, challenge.group)),
+        },
+        None => AuthenticationChallengeResponse{
+            auth_id: USER_NOT_REGISTERED.to_string(),
+            c: Vec::new(),
+            server_signature: None,
+            dh_share: Vec::new(),
+        },
+    }
+}
+
+fn answer_response(authentication: Authentication) -> AuthenticationAnswerResponse {
+    match authentication {
+        Authentication::Authenticated { session, key_confirmation } => AuthenticationAnswerResponse{
+            session_id: session.session_id,
+            credential_id: session.credential_id,
+            second_factor_token: String::new(),
+            key_confirmation: key_confirmation.unwrap_or_default(),
+        },
+        Authentication::SecondFactorRequired { token, key_confirmation } => AuthenticationAnswerResponse{
+            session_id: SECOND_FACTOR_REQUIRED.to_string(),
+            credential_id: String::new(),
+            second_factor_token: token,
+            key_confirmation: key_confirmation.unwrap_or_default(),
+        },
+        Authentication::WrongCredentials => AuthenticationAnswerResponse{
+            session_id: WRONG_CREDENTIALS.to_string(),
+            credential_id: String::new(),
+            second_factor_token: String::new(),
+            key_confirmation: Vec::new(),
+        },
+    }
+}
+
+// The next message of the client on an Authenticate stream
+async fn next_step(steps: &mut Streaming<AuthenticateRequest>) -> Result<ClientStep, Status> {
+    steps.message().await?
+        .and_then(|request| request.step)
+        .ok_or_else(|| Status::cancelled("The client closed the stream before the end of the login"))
+}
+
+// The login of an Authenticate stream: the commitment, the challenge, the answer and the result. The challenge stays in
+// memory, in state, and nothing is left to clean up when the stream ends early.
+async fn authenticate_stream(service: &AuthService, steps: &mut Streaming<AuthenticateRequest>, sender: &mpsc::Sender<Result<AuthenticateResponse, Status>>, channel_binding: Option<&[u8]>) -> Result<(), Status> {
+
+    let request = match next_step(steps).await? {
+        ClientStep::Commitment(request) => request,
+        ClientStep::Answer(_) => return Err(Status::failed_precondition("The login starts with a commitment")),
+    };
+    if request.recovery_code_id != 0 {
+        return Err(Status::invalid_argument("Recovery codes are answered with RecoverAccount"));
+    }
+
+    let issued = service.create_stream_challenge(
+        &request.user,
+        &request.credential_id,
+        Commitment {
+            r1: WireNumber::Bytes(&request.r1),
+            r2: WireNumber::Bytes(&request.r2),
+            dh_share: match request.dh_share.is_empty() {
+                true => None,
+                false => Some(WireNumber::Bytes(&request.dh_share)),
+            },
+        },
+        match request.puzzle_seed.is_empty() {
+            true => None,
+            false => Some(Solution { seed: &request.puzzle_seed, nonce: request.puzzle_nonce }),
+        },
+        channel_binding,
+    ).await?;
+
+    let send = |step| async { sender.send(Ok(AuthenticateResponse { step: Some(step) })).await.map_err(|_| Status::cancelled("The client closed the stream")) };
+    send(ServerStep::Challenge(challenge_response(issued.as_ref().map(|(challenge, _)| challenge)))).await?;
+    let (challenge, state) = match issued {
+        Some(issued) => issued,
+        None => return Ok(()),
+    };
+
+    let answer = match next_step(steps).await? {
+        ClientStep::Answer(answer) => answer,
+        ClientStep::Commitment(_) => return Err(Status::failed_precondition("The commitment is followed by the answer")),
+    };
+    let authentication = service.verify_stream_answer(&challenge.auth_id, state, WireNumber::Bytes(&answer.s), channel_binding).await?;
+    metrics::record_outcome("Authenticate", "finished");
+
+    send(ServerStep::Result(answer_response(authentication))).await
+}
+
+// The next message of the client on an AuthenticateAnonymously stream
+async fn next_ring_step(steps: &mut Streaming<AuthenticateAnonymouslyRequest>) -> Result<RingClientStep, Status> {
+    steps.message().await?
+        .and_then(|request| request.step)
+        .ok_or_else(|| Status::cancelled("The client closed the stream before the end of the login"))
+}
+
+// The anonymous login of an AuthenticateAnonymously stream: the ring and the commitments, the challenge, the answer and
+// the result. Like the Authenticate stream, the challenge stays in memory.
+async fn authenticate_anonymously_stream(service: &AuthService, steps: &mut Streaming<AuthenticateAnonymouslyRequest>, sender: &mpsc::Sender<Result<AuthenticateAnonymouslyResponse, Status>>, channel_binding: Option<&[u8]>) -> Result<(), Status> {
+
+    let request = match next_ring_step(steps).await? {
+        RingClientStep::Commitment(request) => request,
+        RingClientStep::Answer(_) => return Err(Status::failed_precondition("The login starts with a commitment")),
+    };
+    let (challenge, state) = service.create_ring_challenge(
+        &request.group_id,
+        &request.ring.iter().map(|y1| WireNumber::Bytes(y1)).collect::<Vec<_>>(),
+        &request.commitments.iter().map(|r| WireNumber::Bytes(r)).collect::<Vec<_>>(),
+        match request.puzzle_seed.is_empty() {
+            true => None,
+            false => Some(Solution { seed: &request.puzzle_seed, nonce: request.puzzle_nonce }),
+        },
+        channel_binding,
+    ).await?;
+
+    let send = |step| async { sender.send(Ok(AuthenticateAnonymouslyResponse { step: Some(step) })).await.map_err(|_| Status::cancelled("The client closed the stream")) };
+    let IssuedRingChallenge { c, group, signature } = challenge;
+    send(RingServerStep::Challenge(RingChallenge {
+        c: encode_bytes(&c, group),
+        server_signature: Some(ServerSignature{
+            e: encode_bytes(&signature.e, signature.group),
+            s: encode_bytes(&signature.s, signature.group),
+        }),
+    })).await?;
+
+    let answer = match next_ring_step(steps).await? {
+        RingClientStep::Answer(answer) => answer,
+        RingClientStep::Commitment(_) => return Err(Status::failed_precondition("The commitment is followed by the answer")),
+    };
+    let session = service.verify_ring_answer(
+        state,
+        &answer.challenges.iter().map(|c| WireNumber::Bytes(c)).collect::<Vec<_>>(),
+        &answer.responses.iter().map(|s| WireNumber::Bytes(s)).collect::<Vec<_>>(),
+    ).await?;
+
+    send(RingServerStep::Result(RingResult {
+        session_id: session.map_or_else(|| WRONG_CREDENTIALS.to_string(), |session| session.session_id),
+    })).await
+}
+
+// zkp_auth.v2.Auth: the numbers are fixed-length big-endian bytes
+#[tonic::async_trait]
+impl Auth for AuthService {
+
+    // Implementing the Register method that allows registering users by providing username, y1 and y2
+    async fn register(&self, request:Request<RegisterRequest>) -> Result<Response<RegisterResponse>, Status> {
+
+        let request = request.get_ref();
+        self.register_user(
+            &request.user,
+            &request.credential_id,
+            &request.group_id,
+            WireNumber::Bytes(&request.y1),
+            WireNumber::Bytes(&request.y2),
+            &request.recovery_keys
+                .iter()
+                .map(|key| (WireNumber::Bytes(&key.y1), WireNumber::Bytes(&key.y2)))
+                .collect::<Vec<_>>(),
+        ).await?;
+
+        Ok(Response::new(RegisterResponse{}))
+    }
+
+    // Implementing the CreateAuthenticationChallenge challenge which creates the challenge c based on username, r1, and r2
+    async fn create_authentication_challenge(&self, request:Request<AuthenticationChallengeRequest>) -> Result<Response<AuthenticationChallengeResponse>, Status> {
+
+        let channel_binding = tls::channel_binding(&request);
+        let request = request.get_ref();
+        let challenge = self.create_challenge(
+            &request.user,
+            &request.credential_id,
+            match request.recovery_code_id {
+                0 => None,
+                recovery_code_id => Some(i32::try_from(recovery_code_id)
+                    .map_err(|_| Status::invalid_argument("Unknown recovery code"))?),
+            },
+            Commitment {
+                r1: WireNumber::Bytes(&request.r1),
+                r2: WireNumber::Bytes(&request.r2),
+                dh_share: match request.dh_share.is_empty() {
+                    true => None,
+                    false => Some(WireNumber::Bytes(&request.dh_share)),
+                },
+            },
+            match request.puzzle_seed.is_empty() {
+                true => None,
+                false => Some(Solution { seed: &request.puzzle_seed, nonce: request.puzzle_nonce }),
+            },
+            channel_binding,
+        ).await?;
+
+        // Send back the random challenge c
+        Ok(Response::new(challenge_response(challenge.as_ref())))
+    }
+
+    // Implementing the VerifyAuthentication method based on the received s
+    async fn verify_authentication(&self, request:Request<AuthenticationAnswerRequest>) -> Result<Response<AuthenticationAnswerResponse>, Status> {
+
+        let channel_binding = tls::channel_binding(&request);
+        let request = request.get_ref();
+        let authentication = self.verify_answer(&request.auth_id, WireNumber::Bytes(&request.s), channel_binding).await?;
+
+        Ok(Response::new(answer_response(authentication)))
+    }
+
+    type AuthenticateStream = ReceiverStream<Result<AuthenticateResponse, Status>>;
+
+    // Implementing the Authenticate method: the login over one stream, the challenge held in memory until the answer
+    async fn authenticate(&self, request:Request<Streaming<AuthenticateRequest>>) -> Result<Response<Self::AuthenticateStream>, Status> {
+
+        let channel_binding = tls::channel_binding(&request).map(<[u8]>::to_vec);
+        let mut steps = request.into_inner();
+        let service = self.clone();
+        let (sender, receiver) = mpsc::channel(2);
+
+        tokio::spawn(async move {
+            let login = authenticate_stream(&service, &mut steps, &sender, channel_binding.as_deref());
+            let status = match tokio::time::timeout(AUTHENTICATE_STREAM_DEADLINE, login).await {
+                Ok(Ok(())) => return,
+                Ok(Err(status)) => status,
+                Err(_) => {
+                    metrics::record_outcome("Authenticate", "deadline_exceeded");
+                    Status::deadline_exceeded(format!("The login did not finish within {} seconds", AUTHENTICATE_STREAM_DEADLINE.as_secs()))
+                }
+            };
+            // The client may be gone already
+            let _ = sender.send(Err(status)).await;
+        });
+
+        Ok(Response::new(ReceiverStream::new(receiver)))
+    }
+
+    // Implementing the GetRing method which sends the fixed ring of the key of the session for anonymous logins
+    async fn get_ring(&self, request:Request<RingRequest>) -> Result<Response<RingResponse>, Status> {
+
+        let ring = self.ring(&request.get_ref().session_id).await?;
+
+        Ok(Response::new(RingResponse{
+            group_id: ring.group.id.to_string(),
+            ring: ring.members.iter().map(|y1| encode_bytes(y1, ring.group)).collect(),
+        }))
+    }
+
+    type AuthenticateAnonymouslyStream = ReceiverStream<Result<AuthenticateAnonymouslyResponse, Status>>;
+
+    // Implementing the AuthenticateAnonymously method: the proof for one of the keys of a ring over one stream
+    async fn authenticate_anonymously(&self, request:Request<Streaming<AuthenticateAnonymouslyRequest>>) -> Result<Response<Self::AuthenticateAnonymouslyStream>, Status> {
+
+        let channel_binding = tls::channel_binding(&request).map(<[u8]>::to_vec);
+        let mut steps = request.into_inner();
+        let service = self.clone();
+        let (sender, receiver) = mpsc::channel(2);
+
+        tokio::spawn(async move {
+            let login = authenticate_anonymously_stream(&service, &mut steps, &sender, channel_binding.as_deref());
+            let status = match tokio::time::timeout(AUTHENTICATE_STREAM_DEADLINE, login).await {
+                Ok(Ok(())) => return,
+                Ok(Err(status)) => status,
+                Err(_) => {
+                    metrics::record_outcome("AuthenticateAnonymously", "deadline_exceeded");
+                    Status::deadline_exceeded(format!("The login did not finish within {} seconds", AUTHENTICATE_STREAM_DEADLINE.as_secs()))
+                }
+            };
+            // The client may be gone already
+            let _ = sender.send(Err(status)).await;
+        });
+
+        Ok(Response::new(ReceiverStream::new(receiver)))
+    }
+
+    // Implementing the VerifySecondFactor method which turns a login waiting for the second factor into a session
+    async fn verify_second_factor(&self, request:Request<VerifySecondFactorRequest>) -> Result<Response<VerifySecondFactorResponse>, Status> {
+
+        let request = request.get_ref();
+        let session = self.verify_second_factor(&request.second_factor_token, &request.code).await?;
+
+        Ok(Response::new(match session {
+            Some(session) => VerifySecondFactorResponse{
+                session_id: session.session_id,
+                credential_id: session.credential_id,
+            },
+            None => VerifySecondFactorResponse{
+                session_id: WRONG_CREDENTIALS.to_string(),
+                credential_id: String::new(),
+            },
+        }))
+    }
+
+    // Implementing the EnrollTotp method which starts the TOTP enrollment of the user of the session
+    async fn enroll_totp(&self, request:Request<EnrollTotpRequest>) -> Result<Response<EnrollTotpResponse>, Status> {
+
+        let secret = self.enroll_totp(&request.get_ref().session_id).await?;
+
+        Ok(Response::new(EnrollTotpResponse{
+            secret,
+            digits: TOTP_DIGITS,
+            period: TOTP_STEP as u32,
+        }))
+    }
+
+    // Implementing the ConfirmTotp method which confirms the TOTP enrollment with a first code
+    async fn confirm_totp(&self, request:Request<ConfirmTotpRequest>) -> Result<Response<ConfirmTotpResponse>, Status> {
+
+        let request = request.get_ref();
+        let confirmed = self.confirm_totp(&request.session_id, &request.code).await?;
+
+        Ok(Response::new(ConfirmTotpResponse{ confirmed }))
+    }
+
+    // Implementing the GetPublicParameters method which lets the clients fetch the group and the KDF instead of hardcoding them
+    async fn get_public_parameters(&self, request:Request<PublicParametersRequest>) -> Result<Response<PublicParametersResponse>, Status> {
+
+        let request = request.get_ref();
+        let group = self.public_parameters(&request.group_id, &request.user, &request.credential_id, request.recovery).await?;
+
+        Ok(Response::new(PublicParametersResponse{
+            group_id: group.id.to_string(),
+            p: encode_bytes(&group.p, group),
+            q: encode_bytes(&group.q, group),
+            g: encode_bytes(&group.g, group),
+            h: encode_bytes(&group.h, group),
+            kdf: KDF.to_string(),
+            challenge_bits: CHALLENGE_BITS as u32,
+        }))
+    }
+
+    // Implementing the ChangePassword method which replaces y1 and y2 after checking the answer computed with the old x
+    async fn change_password(&self, request:Request<ChangePasswordRequest>) -> Result<Response<ChangePasswordResponse>, Status> {
+
+        let channel_binding = tls::channel_binding(&request);
+        let request = request.get_ref();
+        let changed = self.change_password(
+            &request.auth_id,
+            WireNumber::Bytes(&request.s),
+            WireNumber::Bytes(&request.new_y1),
+            WireNumber::Bytes(&request.new_y2),
+            channel_binding,
+        ).await?;
+
+        Ok(Response::new(ChangePasswordResponse{ changed }))
+    }
+
+    // Implementing the Unregister method which deletes the account after checking the answer bound to the deletion
+    async fn unregister(&self, request:Request<UnregisterRequest>) -> Result<Response<UnregisterResponse>, Status> {
+
+        let channel_binding = tls::channel_binding(&request);
+        let request = request.get_ref();
+        let unregistered = self.unregister(&request.auth_id, WireNumber::Bytes(&request.s), channel_binding).await?;
+
+        Ok(Response::new(UnregisterResponse{ unregistered }))
+    }
+
+    // Implementing the RecoverAccount method which sets a new password after checking the answer computed with a recovery code
+    async fn recover_account(&self, request:Request<RecoverAccountRequest>) -> Result<Response<RecoverAccountResponse>, Status> {
+
+        let channel_binding = tls::channel_binding(&request);
+        let request = request.get_ref();
+        let remaining = self.recover_account(
+            &request.auth_id,
+            WireNumber::Bytes(&request.s),
+            &request.credential_id,
+            WireNumber::Bytes(&request.new_y1),
+            WireNumber::Bytes(&request.new_y2),
+            channel_binding,
+        ).await?;
+
+        Ok(Response::new(RecoverAccountResponse{
+            recovered: remaining.is_some(),
+            remaining_recovery_codes: remaining.unwrap_or(0) as u32,
+        }))
+    }
+
+    // Implementing the AddCredential method which enrolls another credential (device) for the user of the session
+    async fn add_credential(&self, request:Request<AddCredentialRequest>) -> Result<Response<AddCredentialResponse>, Status> {
+
+        let request = request.get_ref();
+        self.add_credential(
+            &request.session_id,
+            &request.credential_id,
+            &request.group_id,
+            WireNumber::Bytes(&request.y1),
+            WireNumber::Bytes(&request.y2),
+        ).await?;
+
+        Ok(Response::new(AddCredentialResponse{}))
+    }
+
+    // Implementing the ListCredentials method which lists the credentials of the user of the session
+    async fn list_credentials(&self, request:Request<ListCredentialsRequest>) -> Result<Response<ListCredentialsResponse>, Status> {
+
+        let credentials = self.list_credentials(&request.get_ref().session_id).await?;
+
+        Ok(Response::new(ListCredentialsResponse{
+            credentials: credentials
+                .into_iter()
+                .map(|credential| Credential {
+                    credential_id: credential.credential_id,
+                    group_id: credential.group_id,
+                })
+                .collect(),
+        }))
+    }
+
+    // Implementing the RevokeCredential method which revokes a credential of the user of the session
+    async fn revoke_credential(&self, request:Request<RevokeCredentialRequest>) -> Result<Response<RevokeCredentialResponse>, Status> {
+
+        let request = request.get_ref();
+        self.revoke_credential(&request.session_id, &request.credential_id).await?;
+
+        Ok(Response::new(RevokeCredentialResponse{}))
+    }
+
+    // Implementing the GetCapabilities method, the handshake telling the clients what the server supports
+    async fn get_capabilities(&self, _request:Request<CapabilitiesRequest>) -> Result<Response<CapabilitiesResponse>, Status> {
+
+        let capabilities = self.capabilities();
+
+        Ok(Response::new(CapabilitiesResponse{
+            versions: capabilities.versions
+                .into_iter()
+                .map(|(service, encoding)| ProtocolVersion {
+                    service: service.to_string(),
+                    encoding: encoding.to_string(),
+                })
+                .collect(),
+            groups: capabilities.groups.into_iter().map(String::from).collect(),
+            default_group_id: capabilities.default_group_id.to_string(),
+            kdfs: capabilities.kdfs.into_iter().map(String::from).collect(),
+            proof_modes: capabilities.proof_modes.into_iter().map(String::from).collect(),
+            key_exchanges: capabilities.key_exchanges.into_iter().map(String::from).collect(),
+        }))
+    }
+}
@@ -4,12 +4,13 @@ use std::hash::{Hash, Hasher};
 use std::collections::hash_map::DefaultHasher;
 use std::io;
 use std::io::Write;
+use std::fs;
+use std::path::Path;
 use rpassword::read_password;
 use num_bigint::{BigInt, ToBigInt, RandBigInt, Sign};
 use num_traits::{Zero, One};
 use rand::{distributions::Alphanumeric, Rng}; 
 use tonic::Request;
-use crate::parameters::public_params;
 use crate::zkp_auth::{
     RegisterRequest, 
     AuthenticationChallengeRequest,
@@ -28,11 +29,11 @@ pub fn mod_exp(g: &BigInt, x: &BigInt, q: &BigInt) -> BigInt {
 
     let mut base = g % q;
     let mut exp = x.clone();
-    while &exp > &zero {
+    while exp > zero {
         if &exp % &two == one {
             result = result * &base % q;
         }        
-        exp = exp >> 1;
+        exp >>= 1;
         base = &base * &base % q
     }
 
@@ -45,12 +46,12 @@ pub fn random_big_int(from: BigInt, to: BigInt) -> BigInt {
 }
 
 // Generate a random String
-pub fn random_string() -> String {
-    return rand::thread_rng()
+pub fn random_string(length: usize) -> String {
+    rand::thread_rng()
         .sample_iter(&Alphanumeric)
-        .take(7)
+        .take(length)
         .map(char::from)
-        .collect();
+        .collect()
 }
 
 // Generate a hash of a String
@@ -61,3 +62,21 @@ pub fn default_hash<T>(obj: T) -> BigInt where T: Hash, {
     hasher.finish().to_bigint().unwrap()
 
 }
+
+// Create a file only the owner can read, for a private key. Fails if the file exists.
+#[cfg(unix)]
+pub fn write_private(path: &Path, content: &str) -> io::Result<()> {
+    use std::os::unix::fs::OpenOptionsExt;
+
+    fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)?
+        .write_all(content.as_bytes())
+}
+
+#[cfg(not(unix))]
+pub fn write_private(path: &Path, content: &str) -> io::Result<()> {
+    fs::write(path, content)
+}